@@ -0,0 +1,261 @@
+//! # Fuzz the connection hub
+//!
+//! Runs a connection hub on the loopback interface and sends it whatever the fuzzer comes up with.
+//! The first byte of the input decides how the rest is sent: as the start of a handshake on a new
+//! stream, on the stream of a user that has been welcomed, or as a datagram of that user. Every
+//! packet is registered, so everything that gets past the hub is decoded aswell.
+//!
+//! The hub handles the input on its network thread. Once the input has been sent, a ping follows
+//! the same way, and the next input is only sent once the ping has been dispatched or the hub has
+//! closed the stream, so a crash is reported for the input that caused it. A panic on the network
+//! thread aborts the process like any other.
+//!
+//! The hub logs everything it rejects, so it is best run with
+//! `cargo fuzz run connection_hub -- -close_fd_mask=1`.
+
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate stupid;
+
+use stupid::packets::*;
+use stupid::packets::frame;
+use stupid::srv::{BindConfig, ConnectionHub, KeepAlive, RateLimit};
+
+use std::cell::RefCell;
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream, UdpSocket};
+use std::sync::Mutex;
+use std::sync::mpsc::{self, Receiver};
+use std::time::{Duration, Instant};
+use std::u32;
+
+/// How long the hub has to handle an input, in milliseconds. If the ping that follows it has not
+/// been dispatched by then, the session is started over.
+const PROBE_TIMEOUT_MS: u64 = 200;
+
+/// How long reading from a stream blocks at most, in milliseconds.
+const READ_TIMEOUT_MS: u64 = 1;
+
+thread_local! {
+	static HARNESS: RefCell<Harness> = RefCell::new(Harness::new());
+}
+
+fuzz_target!(|data: &[u8]| {
+	let (&mode, data) = match data.split_first() {
+		Some(split) => split,
+		None => return
+	};
+
+	HARNESS.with(|harness| {
+		let mut harness = harness.borrow_mut();
+
+		match mode % 3 {
+			0 => harness.handshake(data),
+			1 => harness.send_tcp(data),
+			_ => harness.send_udp(data)
+		}
+	});
+});
+
+/// A user that has completed the handshake.
+struct Session {
+	stream: TcpStream,
+	socket: UdpSocket,
+	token: u64,
+	channel: Channel
+}
+
+impl Session {
+	fn connect(tcp: SocketAddr, name: String) -> Session {
+		let mut stream = TcpStream::connect(tcp).unwrap();
+		Frame::new(&Hello { version: PROTOCOL_VERSION, name: name }).write_to(&mut stream).unwrap();
+
+		let welcome = Frame::read_from(&mut stream).unwrap().decode::<Welcome>().unwrap();
+		stream.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT_MS))).unwrap();
+
+		let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+		socket.connect(SocketAddr::new(tcp.ip(), welcome.udp_port)).unwrap();
+
+		Session {
+			stream: stream,
+			socket: socket,
+			token: welcome.token,
+			channel: Channel::new()
+		}
+	}
+
+	/// Sends the datagram with the session token in front of it.
+	fn send_datagram(&self, datagram: &[u8]) {
+		let mut bytes = frame::token_to_bytes(self.token);
+		bytes.extend(datagram.iter().cloned());
+
+		let _ = self.socket.send(&bytes);
+	}
+
+	/// Reads whatever the hub has sent, and returns false once the stream has been closed.
+	fn is_open(&mut self) -> bool {
+		let mut buffer = [0; 1024];
+
+		match self.stream.read(&mut buffer) {
+			Ok(0) => false,
+			Ok(_) => true,
+			Err(ref err) if err.kind() == ErrorKind::WouldBlock || err.kind() == ErrorKind::TimedOut || err.kind() == ErrorKind::Interrupted => true,
+			Err(_) => false
+		}
+	}
+}
+
+struct Harness {
+	// Kept, so the hub keeps running for as long as the fuzzer does.
+	_hub: ConnectionHub,
+	tcp: SocketAddr,
+	probes: Receiver<u64>,
+	next_probe: u64,
+	session: Option<Session>,
+	// Every session gets a name of its own, since the one before may not have been dropped yet.
+	sessions: u64
+}
+
+impl Harness {
+	fn new() -> Harness {
+		// The system picks a port nobody listens on, which is free again once the socket is closed.
+		// The hub lets it pick the UDP port itself, and tells the session in the welcome.
+		let tcp = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+		// The probe is the only ping a server ever receives.
+		let (probe_sender, probes) = mpsc::channel();
+		let probe_sender = Mutex::new(probe_sender);
+
+		let mut registry = PacketRegistry::new();
+		registry.register::<Ping, _>(move |_, ping| {
+			let _ = probe_sender.lock().unwrap().send(ping.timestamp);
+		}).unwrap();
+
+		registry.register::<Authenticate, _>(|_, _| {}).unwrap();
+		registry.register::<Challenge, _>(|_, _| {}).unwrap();
+		registry.register::<ChangeHealth, _>(|_, _| {}).unwrap();
+		registry.register::<ChatMessage, _>(|_, _| {}).unwrap();
+		registry.register::<ControlEntity, _>(|_, _| {}).unwrap();
+		registry.register::<DespawnEntity, _>(|_, _| {}).unwrap();
+		registry.register::<Disconnect, _>(|_, _| {}).unwrap();
+		registry.register::<Hello, _>(|_, _| {}).unwrap();
+		registry.register::<InputAck, _>(|_, _| {}).unwrap();
+		registry.register::<JoinGroup, _>(|_, _| {}).unwrap();
+		registry.register::<LeaveGroup, _>(|_, _| {}).unwrap();
+		registry.register::<MoveEntity, _>(|_, _| {}).unwrap();
+		registry.register::<PlayerInput, _>(|_, _| {}).unwrap();
+		registry.register::<Pong, _>(|_, _| {}).unwrap();
+		registry.register::<Reject, _>(|_, _| {}).unwrap();
+		registry.register::<SendChat, _>(|_, _| {}).unwrap();
+		registry.register::<SnapshotAck, _>(|_, _| {}).unwrap();
+		registry.register::<SpawnEntity, _>(|_, _| {}).unwrap();
+		registry.register::<TickRate, _>(|_, _| {}).unwrap();
+		registry.register::<Welcome, _>(|_, _| {}).unwrap();
+		registry.register::<WorldSnapshot, _>(|_, _| {}).unwrap();
+
+		// The fuzzer sends far more than any player would, which must not get it disconnected.
+		let rate_limit = RateLimit {
+			packets_per_second: u32::MAX,
+			bytes_per_second: u32::MAX,
+			burst_ms: 1000,
+			max_violations: u32::MAX
+		};
+
+		let hub = ConnectionHub::new(BindConfig::new(tcp).with_udp_port(0), registry, KeepAlive::default(), None, rate_limit, None).unwrap();
+
+		Harness {
+			_hub: hub,
+			tcp: tcp,
+			probes: probes,
+			next_probe: 0,
+			session: None,
+			sessions: 0
+		}
+	}
+
+	/// Starts a handshake with the data, and waits until the hub has closed the stream. A client
+	/// that has said everything it has to say closes its side of the stream, so the hub either
+	/// rejects the handshake or drops the user it has just welcomed.
+	fn handshake(&mut self, data: &[u8]) {
+		let mut stream = TcpStream::connect(self.tcp).unwrap();
+		let _ = stream.write_all(data);
+		let _ = stream.shutdown(Shutdown::Write);
+
+		let _ = stream.set_read_timeout(Some(Duration::from_millis(PROBE_TIMEOUT_MS)));
+		let _ = stream.read_to_end(&mut Vec::new());
+	}
+
+	/// Sends the data on the stream of the session, followed by the probe.
+	fn send_tcp(&mut self, data: &[u8]) {
+		let probe = self.next_probe();
+		let session = self.session();
+
+		let mut bytes = data.to_vec();
+		bytes.extend(Frame::new(&Ping { timestamp: probe }).to_bytes());
+
+		if session.stream.write_all(&bytes).is_err() {
+			self.session = None;
+			return;
+		}
+
+		self.wait_for(probe);
+	}
+
+	/// Sends the data as a datagram of the session, followed by the probe.
+	fn send_udp(&mut self, data: &[u8]) {
+		let probe = self.next_probe();
+		let session = self.session();
+
+		session.send_datagram(data);
+
+		// Pings are unreliable, so they are never queued or sent again, and the time they are sent
+		// at does not matter.
+		let datagram = session.channel.send(&Ping { timestamp: probe }, 0).unwrap();
+		session.send_datagram(&datagram);
+
+		self.wait_for(probe);
+	}
+
+	fn next_probe(&mut self) -> u64 {
+		self.next_probe += 1;
+		self.next_probe
+	}
+
+	/// The current session, which is started if there is none.
+	fn session(&mut self) -> &mut Session {
+		if self.session.is_none() {
+			self.sessions += 1;
+			self.session = Some(Session::connect(self.tcp, format!("fuzzer{}", self.sessions)));
+		}
+
+		self.session.as_mut().unwrap()
+	}
+
+	/// Waits for the probe to be dispatched. If the hub closes the stream of the session instead,
+	/// or does not get to the probe in time, the session is started over with the next input.
+	fn wait_for(&mut self, probe: u64) {
+		let deadline = Instant::now() + Duration::from_millis(PROBE_TIMEOUT_MS);
+
+		while Instant::now() < deadline {
+			// Probes that arrive late are skipped.
+			while let Ok(dispatched) = self.probes.try_recv() {
+				if dispatched == probe {
+					return;
+				}
+			}
+
+			let open = match self.session {
+				Some(ref mut session) => session.is_open(),
+				None => false
+			};
+
+			if !open {
+				break;
+			}
+		}
+
+		self.session = None;
+	}
+}
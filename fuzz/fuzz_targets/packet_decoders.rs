@@ -0,0 +1,85 @@
+//! # Fuzz the packet decoders
+//!
+//! Feeds arbitrary bytes into everything that reads data from the network without a socket: the
+//! frames of streams and datagrams, the UDP channel, the handshake and every packet the registry
+//! knows. Decoding may fail, but it must never panic.
+//!
+//! Run it with `cargo fuzz run packet_decoders`.
+
+#![no_main]
+
+#[macro_use]
+extern crate libfuzzer_sys;
+extern crate stupid;
+
+use stupid::packets::*;
+use stupid::packets::frame;
+use stupid::srv::connection_hub;
+
+fuzz_target!(|data: &[u8]| {
+	let registry = registry();
+
+	// The first byte is taken as the id of the packet, the rest as its data.
+	if let Some((&id, rest)) = data.split_first() {
+		let _ = registry.dispatch(&(), &Frame { id: id, data: rest.to_vec() });
+	}
+
+	// A datagram, with and without the session token in front.
+	if let Ok(frame) = Frame::from_bytes(data) {
+		let _ = connection_hub::greet(&frame);
+		let _ = registry.dispatch(&(), &frame);
+	}
+
+	if frame::read_token(data).is_some() {
+		receive_datagram(&registry, &data[frame::TOKEN_SIZE..]);
+	}
+	receive_datagram(&registry, data);
+
+	// A stream, which may contain any number of frames.
+	let mut buffer = data.to_vec();
+	while let Ok(Some(frame)) = Frame::take_from(&mut buffer) {
+		let _ = connection_hub::greet(&frame);
+		let _ = registry.dispatch(&(), &frame);
+	}
+
+	let _ = Frame::read_from(&mut &data[..]);
+});
+
+/// Reads the datagram with a new channel and dispatches whatever it contains.
+fn receive_datagram(registry: &PacketRegistry<()>, datagram: &[u8]) {
+	if let Ok(received) = Channel::new().receive(datagram) {
+		for frame in received.frames {
+			let _ = registry.dispatch(&(), &frame);
+		}
+	}
+}
+
+/// Creates a registry that knows every packet, and ignores them once they have been decoded.
+fn registry() -> PacketRegistry<()> {
+	let mut registry = PacketRegistry::new();
+
+	registry.register::<Authenticate, _>(|_, _| {}).unwrap();
+	registry.register::<Challenge, _>(|_, _| {}).unwrap();
+	registry.register::<ChangeHealth, _>(|_, _| {}).unwrap();
+	registry.register::<ChatMessage, _>(|_, _| {}).unwrap();
+	registry.register::<ControlEntity, _>(|_, _| {}).unwrap();
+	registry.register::<DespawnEntity, _>(|_, _| {}).unwrap();
+	registry.register::<Disconnect, _>(|_, _| {}).unwrap();
+	registry.register::<Hello, _>(|_, _| {}).unwrap();
+	registry.register::<InputAck, _>(|_, _| {}).unwrap();
+	registry.register::<JoinGroup, _>(|_, _| {}).unwrap();
+	registry.register::<LeaveGroup, _>(|_, _| {}).unwrap();
+	registry.register::<MoveEntity, _>(|_, _| {}).unwrap();
+	registry.register::<Ping, _>(|_, _| {}).unwrap();
+	registry.register::<PlayerInput, _>(|_, _| {}).unwrap();
+	registry.register::<Pong, _>(|_, _| {}).unwrap();
+	registry.register::<Reject, _>(|_, _| {}).unwrap();
+	registry.register::<SendChat, _>(|_, _| {}).unwrap();
+	registry.register::<SnapshotAck, _>(|_, _| {}).unwrap();
+	registry.register::<SpawnEntity, _>(|_, _| {}).unwrap();
+	registry.register::<TickRate, _>(|_, _| {}).unwrap();
+	registry.register::<Welcome, _>(|_, _| {}).unwrap();
+	registry.register::<WorldSnapshot, _>(|_, _| {}).unwrap();
+
+	registry
+}
@@ -0,0 +1,198 @@
+//! # Deriving packets
+//!
+//! Writing from_bytes() and to_bytes() by hand means writing every field twice, and nothing makes
+//! sure both agree on the order. `#[derive(Packet)]` generates both from the fields of the struct,
+//! which are encoded one after another in the order they are declared. Every field must implement
+//! the `Field` trait of the packets, which is the case for the primitive types, `Vector<T>`,
+//! strings, `Vec`s and `Option`s, and for structs that `#[derive(Field)]`.
+//!
+//! The id of the packet, and its delivery if it is not unreliable, are given by an attribute:
+//!
+//! ```ignore
+//! #[derive(Packet)]
+//! #[packet(id = 0, delivery = ReliableOrdered)]
+//! pub struct SpawnEntity { ... }
+//! ```
+//!
+//! Every derived packet claims its id with an implementation of the `PacketId` trait, so two
+//! packets with the same id do not compile.
+
+extern crate proc_macro;
+extern crate proc_macro2;
+extern crate quote;
+extern crate syn;
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{Data, DeriveInput, Fields, Ident, LitInt};
+
+/// # Derive a packet
+///
+/// Implements the `Packet` trait with the id and delivery of the `packet` attribute, encoding
+/// all fields in the order they are declared.
+#[proc_macro_derive(Packet, attributes(packet))]
+pub fn derive_packet(input: TokenStream) -> TokenStream {
+	let input = syn::parse_macro_input!(input as DeriveInput);
+
+	match packet(&input) {
+		Ok(tokens) => tokens.into(),
+		Err(err) => err.to_compile_error().into()
+	}
+}
+
+/// # Derive a field
+///
+/// Implements the `Field` trait for a struct, so it can be nested inside of packets. The fields
+/// are encoded in the order they are declared, just like those of a packet.
+#[proc_macro_derive(Field)]
+pub fn derive_field(input: TokenStream) -> TokenStream {
+	let input = syn::parse_macro_input!(input as DeriveInput);
+
+	match field(&input) {
+		Ok(tokens) => tokens.into(),
+		Err(err) => err.to_compile_error().into()
+	}
+}
+
+/// The settings of the `packet` attribute.
+struct PacketAttribute {
+	id: u8,
+	delivery: Option<Ident>
+}
+
+fn packet(input: &DeriveInput) -> syn::Result<TokenStream2> {
+	let attribute = try!(packet_attribute(input));
+	let (read, members) = try!(encoding(input));
+
+	let name = &input.ident;
+	let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+	let id = attribute.id;
+
+	let delivery = match attribute.delivery {
+		Some(delivery) => quote! {
+			fn delivery() -> ::packets::Delivery {
+				::packets::Delivery::#delivery
+			}
+		},
+		None => quote! {}
+	};
+
+	Ok(quote! {
+		impl #impl_generics ::packets::Packet for #name #type_generics #where_clause {
+			fn id() -> u8 {
+				#id
+			}
+
+			#delivery
+
+			fn from_bytes(bytes: &[u8]) -> ::std::result::Result<Self, ::packets::PacketError> {
+				let mut cursor = ::std::io::Cursor::new(bytes);
+				let packet = {
+					let data = &mut cursor;
+					#read
+				};
+
+				match ::packets::expect_end(&cursor) {
+					::std::result::Result::Ok(()) => ::std::result::Result::Ok(packet),
+					::std::result::Result::Err(err) => ::std::result::Result::Err(err)
+				}
+			}
+
+			fn to_bytes(&self) -> ::std::vec::Vec<u8> {
+				let mut bytes = ::std::vec::Vec::new();
+				{
+					let data = &mut bytes;
+					#(::packets::Field::write_field(&self.#members, data).unwrap();)*
+				}
+
+				bytes
+			}
+		}
+
+		impl ::packets::PacketId<[(); #id as usize]> for ::packets::PacketIds {}
+	})
+}
+
+fn field(input: &DeriveInput) -> syn::Result<TokenStream2> {
+	let (read, members) = try!(encoding(input));
+
+	let name = &input.ident;
+	let (impl_generics, type_generics, where_clause) = input.generics.split_for_impl();
+
+	Ok(quote! {
+		impl #impl_generics ::packets::Field for #name #type_generics #where_clause {
+			fn read_field(data: &mut ::std::io::Cursor<&[u8]>) -> ::std::result::Result<Self, ::packets::PacketError> {
+				::std::result::Result::Ok(#read)
+			}
+
+			fn write_field(&self, data: &mut ::std::vec::Vec<u8>) -> ::packets::byteorder::Result<()> {
+				#(match ::packets::Field::write_field(&self.#members, data) {
+					::std::result::Result::Ok(()) => {},
+					::std::result::Result::Err(err) => return ::std::result::Result::Err(err)
+				})*
+
+				::std::result::Result::Ok(())
+			}
+		}
+	})
+}
+
+/// Reads the id and the delivery from the `packet` attribute.
+fn packet_attribute(input: &DeriveInput) -> syn::Result<PacketAttribute> {
+	let mut id = None;
+	let mut delivery = None;
+
+	for attribute in input.attrs.iter().filter(|attribute| attribute.path().is_ident("packet")) {
+		try!(attribute.parse_nested_meta(|meta| {
+			if meta.path.is_ident("id") {
+				let value: LitInt = try!(try!(meta.value()).parse());
+				id = Some(try!(value.base10_parse::<u8>()));
+				Ok(())
+			}
+			else if meta.path.is_ident("delivery") {
+				delivery = Some(try!(try!(meta.value()).parse::<Ident>()));
+				Ok(())
+			}
+			else {
+				Err(meta.error("Expected `id` or `delivery`."))
+			}
+		}));
+	}
+
+	match id {
+		Some(id) => Ok(PacketAttribute {
+			id: id,
+			delivery: delivery
+		}),
+		None => Err(syn::Error::new_spanned(&input.ident, "A packet needs an id, given by #[packet(id = ...)]."))
+	}
+}
+
+/// Creates the expression that reads the struct from the cursor `data`, and returns it along with
+/// the members of the struct in the order they are declared, which they are written in.
+fn encoding(input: &DeriveInput) -> syn::Result<(TokenStream2, Vec<syn::Member>)> {
+	let fields = match input.data {
+		Data::Struct(ref data) => &data.fields,
+		_ => return Err(syn::Error::new_spanned(&input.ident, "Only structs can be encoded as packets or fields."))
+	};
+
+	let name = &input.ident;
+	let members: Vec<syn::Member> = fields.members().collect();
+
+	// The fields of a struct expression are evaluated in the order they are written, which is
+	// the order they are declared in.
+	let read = match *fields {
+		Fields::Named(_) | Fields::Unnamed(_) => quote! {
+			#name {
+				#(#members: match ::packets::Field::read_field(data) {
+					::std::result::Result::Ok(value) => value,
+					::std::result::Result::Err(err) => return ::std::result::Result::Err(err)
+				}),*
+			}
+		},
+		Fields::Unit => quote! { #name }
+	};
+
+	Ok((read, members))
+}
@@ -4,7 +4,7 @@
 //! multiplayer game, it makes more sense to have a simple entity and have an extra part that is
 //! controlled by the players input.
 
-use entity::Entity;
+use entity::{self, Entity};
 use sdl2::render::Renderer;
 use sys::{Movable, Vector};
 use graphics::{Sprite, Drawable, RenderWindow};
@@ -38,26 +38,14 @@ impl Entity for Character {
 
 	fn damage<T: Entity>(&mut self, raw_damage: u16, _: &T) -> u16 {
 		let actual_damage = raw_damage;
-
-		if actual_damage < self.health {
-			self.health -= actual_damage;
-		}
-		else {
-			self.health = 0;
-		}
+		self.health = entity::damaged(self.health, actual_damage);
 
 		actual_damage
 	}
 
 	fn heal<T: Entity>(&mut self, raw_healing: u16, _: &T) -> u16 {
 		let actual_healing = raw_healing;
-
-		if self.health + actual_healing < self.max_health() {
-			self.health += actual_healing;
-		}
-		else {
-			self.health = self.max_health();
-		}
+		self.health = entity::healed(self.health, actual_healing, self.max_health());
 
 		actual_healing
 	}
@@ -0,0 +1,237 @@
+//! # The chat box
+//!
+//! Shows the recent chat in the bottom left corner of the window, and lets the player write into
+//! it. Return opens the input line and sends what has been written, Escape closes it without
+//! sending anything.
+//!
+//! Plain text is sent to everyone. A few commands send it elsewhere instead:
+//! `/g <group> <text>` writes into a group, `/w <name> <text>` whispers to a user, and
+//! `/join <group>` and `/leave <group>` join and leave groups.
+
+use graphics::{Drawable, Text};
+use graphics::font::{GLYPH_WIDTH, GLYPH_HEIGHT};
+use packets::{ChatChannel, ChatMessage, JoinGroup, LeaveGroup, SendChat, MAX_CHAT_LENGTH};
+use sdl2::event::Event;
+use sdl2::keyboard::Keycode;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Renderer;
+use std::cmp;
+use std::collections::VecDeque;
+use time;
+
+/// The amount of lines that are kept. Older lines are forgotten.
+const MAX_LINES: usize = 50;
+
+/// The amount of lines that are shown at once.
+const VISIBLE_LINES: usize = 8;
+
+/// The time a line is shown after it has been received, unless the player is writing.
+const LINE_VISIBLE_MS: u64 = 10000;
+
+/// The space between the lines and around the input line in pixels.
+const SPACING: u32 = 2;
+
+const GLOBAL_COLOR: Color = Color::RGB(255, 255, 255);
+const GROUP_COLOR: Color = Color::RGB(128, 255, 128);
+const WHISPER_COLOR: Color = Color::RGB(255, 128, 255);
+const SYSTEM_COLOR: Color = Color::RGB(255, 255, 0);
+const INPUT_BACKGROUND: Color = Color::RGB(48, 48, 48);
+
+/// # A chat command
+///
+/// Something the player has written, which has to be sent to the server.
+pub enum ChatCommand {
+	Send(SendChat),
+	Join(JoinGroup),
+	Leave(LeaveGroup)
+}
+
+struct Line {
+	text: String,
+	color: Color,
+
+	// The time the line has been added, in nanoseconds.
+	added: u64
+}
+
+pub struct ChatBox {
+	// The bottom left corner of the box, and its width. Lines that are wider are wrapped.
+	x: i32,
+	bottom: i32,
+	width: u32,
+
+	// The newest line is the last one.
+	lines: VecDeque<Line>,
+
+	// What the player is writing. None while the input line is closed.
+	input: Option<String>
+}
+
+impl ChatBox {
+	pub fn new(bottom_left: (i32, i32), width: u32) -> ChatBox {
+		ChatBox {
+			x: bottom_left.0,
+			bottom: bottom_left.1,
+			width: width,
+			lines: VecDeque::new(),
+			input: None
+		}
+	}
+
+	/// # Is the player writing
+	///
+	/// Returns whether the input line is open. As long as it is, the keys the player presses
+	/// belong to the chat.
+	pub fn is_writing(&self) -> bool {
+		self.input.is_some()
+	}
+
+	/// # Process an event
+	///
+	/// Opens and closes the input line and adds the text the player types to it. Once the player
+	/// sends what they have written, the command for the server is returned.
+	pub fn process_event(&mut self, event: &Event) -> Option<ChatCommand> {
+		match event {
+			&Event::KeyDown{keycode: Some(Keycode::Return), ..} | &Event::KeyDown{keycode: Some(Keycode::KpEnter), ..} => {
+				match self.input.take() {
+					Some(input) => self.parse(input.trim()),
+					None => {
+						self.input = Some(String::new());
+						None
+					}
+				}
+			},
+			&Event::KeyDown{keycode: Some(Keycode::Escape), ..} => {
+				self.input = None;
+				None
+			},
+			&Event::KeyDown{keycode: Some(Keycode::Backspace), ..} => {
+				if let Some(ref mut input) = self.input {
+					input.pop();
+				}
+				None
+			},
+			&Event::TextInput{ref text, ..} => {
+				if let Some(ref mut input) = self.input {
+					for c in text.chars().filter(|c| !c.is_control()) {
+						if input.chars().count() < MAX_CHAT_LENGTH {
+							input.push(c);
+						}
+					}
+				}
+				None
+			},
+			_ => None
+		}
+	}
+
+	/// Turns what the player has written into the command for the server. In case it is not a
+	/// valid command, the player is told how to use it instead.
+	fn parse(&mut self, input: &str) -> Option<ChatCommand> {
+		if input.is_empty() {
+			return None;
+		}
+
+		if !input.starts_with('/') {
+			return Some(ChatCommand::Send(SendChat {
+				channel: ChatChannel::Global,
+				text: String::from(input)
+			}));
+		}
+
+		let mut words = input.splitn(3, ' ');
+		let command = words.next().unwrap_or("");
+		let target = words.next().map(|target| String::from(target));
+		let text = words.next().map(|text| String::from(text.trim()));
+
+		match (command, target, text) {
+			("/g", Some(group), Some(text)) => Some(ChatCommand::Send(SendChat {
+				channel: ChatChannel::Group(group),
+				text: text
+			})),
+			("/w", Some(name), Some(text)) => Some(ChatCommand::Send(SendChat {
+				channel: ChatChannel::Whisper(name),
+				text: text
+			})),
+			("/join", Some(group), None) => Some(ChatCommand::Join(JoinGroup { group: group })),
+			("/leave", Some(group), None) => Some(ChatCommand::Leave(LeaveGroup { group: group })),
+			("/g", _, _) => self.hint("Usage: /g <group> <text>"),
+			("/w", _, _) => self.hint("Usage: /w <name> <text>"),
+			("/join", _, _) => self.hint("Usage: /join <group>"),
+			("/leave", _, _) => self.hint("Usage: /leave <group>"),
+			(command, _, _) => self.hint(&format!("Unknown command '{}'.", command))
+		}
+	}
+
+	fn hint(&mut self, text: &str) -> Option<ChatCommand> {
+		self.add_line(text, SYSTEM_COLOR);
+		None
+	}
+
+	/// # Receive a chat message
+	///
+	/// Adds the message the server relayed to the chat, coloured by the channel it has been sent
+	/// to.
+	pub fn receive(&mut self, message: &ChatMessage) {
+		let (line, color) = match message.channel {
+			ChatChannel::Global => (format!("{}: {}", message.sender, message.text), GLOBAL_COLOR),
+			ChatChannel::Group(ref group) => (format!("[{}] {}: {}", group, message.sender, message.text), GROUP_COLOR),
+			ChatChannel::Whisper(ref recipient) => (format!("{} -> {}: {}", message.sender, recipient, message.text), WHISPER_COLOR),
+			ChatChannel::System => (message.text.clone(), SYSTEM_COLOR)
+		};
+
+		self.add_line(&line, color);
+	}
+
+	/// Adds the text as one or more lines, wrapped to fit into the box.
+	fn add_line(&mut self, text: &str, color: Color) {
+		let columns = (self.width / GLYPH_WIDTH) as usize;
+		let chars: Vec<char> = text.chars().collect();
+		let now = time::precise_time_ns();
+
+		for part in chars.chunks(cmp::max(columns, 1)) {
+			if self.lines.len() >= MAX_LINES {
+				self.lines.pop_front();
+			}
+
+			self.lines.push_back(Line {
+				text: part.iter().cloned().collect(),
+				color: color,
+				added: now
+			});
+		}
+	}
+}
+
+impl Drawable for ChatBox {
+	fn draw(&self, renderer: &mut Renderer) {
+		let line_height = (GLYPH_HEIGHT + SPACING) as i32;
+		let mut y = self.bottom - line_height;
+
+		if let Some(ref input) = self.input {
+			renderer.set_draw_color(INPUT_BACKGROUND);
+			renderer.fill_rect(Rect::new_unwrap(self.x, y, self.width, GLYPH_HEIGHT + SPACING));
+
+			// Only the end of the input fits into the box, which is the part being written.
+			let columns = (self.width / GLYPH_WIDTH) as usize;
+			let line = format!("> {}_", input);
+			let skip = line.chars().count().saturating_sub(columns);
+			let line: String = line.chars().skip(skip).collect();
+
+			Text::new(&line, (self.x, y + SPACING as i32 / 2), GLOBAL_COLOR).draw(renderer);
+			y -= line_height;
+		}
+
+		// While the player is writing, the recent lines are shown regardless of their age.
+		let now = time::precise_time_ns();
+		for line in self.lines.iter().rev().take(VISIBLE_LINES) {
+			if self.input.is_none() && now - line.added > LINE_VISIBLE_MS * 1000000 {
+				break;
+			}
+
+			Text::new(&line.text, (self.x, y), line.color).draw(renderer);
+			y -= line_height;
+		}
+	}
+}
@@ -8,29 +8,127 @@
 #![feature(associated_consts)]
 
 extern crate sdl2;
+extern crate stupid;
 extern crate time;
 
 mod character;
+mod chat_box;
+use chat_box::{ChatBox, ChatCommand};
 mod entity;
+mod entity_table;
+use entity_table::{EntityTable, DEFAULT_INTERPOLATION_DELAY_MS, DEFAULT_MAX_EXTRAPOLATION_MS};
 mod graphics;
 mod player;
 use player::Player;
+mod remote_entity;
+mod server_clock;
+mod snapshot_buffer;
 mod static_object;
-mod sys;
+
+// The packets and the connection to the server are shared with the server, and so is the internal
+// library.
+use stupid::{net, packets, srv, sys};
+use net::ServerConnection;
+use srv::NetworkConditions;
 
 use sdl2::event::Event;
+use packets::{ChatMessage, Disconnect, DisconnectReason, InputAck, Packet, SharedKey};
+use std::env;
+
+/// The title of the window. While the server is telling the player something, it is appended.
+const TITLE: &'static str = "Stupid is awesome";
 
 fn main() {
+    // The server to connect to and the name of the player are given on the command line. Without
+    // a server, the game runs offline. The key of servers that demand one is taken from the
+    // environment, so it does not show up in the list of processes.
+    let name = env::args().nth(2).unwrap_or(String::from("Stupid"));
+    let key = match env::var("STUPID_KEY") {
+        Ok(key) => match SharedKey::new(key.as_bytes()) {
+            Ok(key) => Some(key),
+            Err(err) => {
+                println!("Invalid key. {}", err);
+                return;
+            }
+        },
+        Err(_) => None
+    };
+
+    // How far behind the server remote entities are shown and how long their movement is continued
+    // without news may be tuned to the connection the same way.
+    let interpolation_delay = match milliseconds("STUPID_INTERPOLATION_DELAY_MS") {
+        Ok(delay) => delay.unwrap_or(DEFAULT_INTERPOLATION_DELAY_MS),
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+    let max_extrapolation = match milliseconds("STUPID_MAX_EXTRAPOLATION_MS") {
+        Ok(extrapolation) => extrapolation.unwrap_or(DEFAULT_MAX_EXTRAPOLATION_MS),
+        Err(err) => {
+            println!("{}", err);
+            return;
+        }
+    };
+
+    // Like on the server, bad network conditions can be simulated for the datagrams.
+    let conditions = match env::var("STUPID_NETWORK") {
+        Ok(conditions) => match conditions.parse::<NetworkConditions>() {
+            Ok(conditions) => Some(conditions),
+            Err(err) => {
+                println!("Invalid network conditions. {}", err);
+                return;
+            }
+        },
+        Err(_) => None
+    };
+
+    let mut connection = match env::args().nth(1) {
+        Some(addr) => match ServerConnection::connect(addr.as_str(), &name, key.as_ref(), conditions) {
+            Ok(connection) => Some(connection),
+            Err(err) => {
+                println!("{}", err);
+                None
+            }
+        },
+        None => None
+    };
+
     let sdl_context = sdl2::init().unwrap();
-    let mut window = graphics::RenderWindow::new(&sdl_context, "Stupid is awesome", 800, 600);
+    let mut window = graphics::RenderWindow::new(&sdl_context, TITLE, 800, 600);
 
     let mut running = true;
 
     let mut player = Player::new(&mut window);
+    let mut entities = EntityTable::new();
+    entities.set_interpolation_delay(interpolation_delay);
+    entities.set_max_extrapolation(max_extrapolation);
+    let mut chat = ChatBox::new((8, 592), 480);
 
     while running {
         for event in window.poll_events() {
-            player.process_event(&event);
+            // While the player is writing, the keys they press must not move the character. Keys
+            // that are released still count, so a key held down when the chat opened does not
+            // keep the character moving.
+            match event {
+                Event::KeyDown {..} if chat.is_writing() => {},
+                _ => player.process_event(&event)
+            }
+
+            // Without a server, there is nobody to read what the player writes.
+            if let Some(command) = chat.process_event(&event) {
+                if let Some(ref mut connection) = connection {
+                    let sent = match command {
+                        ChatCommand::Send(send_chat) => connection.send_tcp(&send_chat),
+                        ChatCommand::Join(join_group) => connection.send_tcp(&join_group),
+                        ChatCommand::Leave(leave_group) => connection.send_tcp(&leave_group)
+                    };
+
+                    if let Err(err) = sent {
+                        println!("Unable to send to the chat. {}", err);
+                    }
+                }
+            }
 
             match event {
                 Event::Quit {..} => running = false,
@@ -38,10 +136,76 @@ fn main() {
             }
         }
 
-        player.update(&window);
+        if let Some(ref mut connection) = connection {
+            for frame in connection.poll_frames() {
+                match entities.apply(&frame, &mut window) {
+                    Ok(true) => {},
+                    Ok(false) if frame.id == InputAck::id() => match frame.decode::<InputAck>() {
+                        Ok(ack) => player.reconcile(&ack),
+                        Err(err) => println!("Invalid input acknowledgement received from the server. {}", err)
+                    },
+                    Ok(false) if frame.id == ChatMessage::id() => match frame.decode::<ChatMessage>() {
+                        Ok(message) => chat.receive(&message),
+                        Err(err) => println!("Invalid chat message received from the server. {}", err)
+                    },
+                    Ok(false) if frame.id == Disconnect::id() => match frame.decode::<Disconnect>() {
+                        Ok(disconnect) => show_disconnect(&mut window, &disconnect),
+                        Err(err) => println!("Invalid disconnect received from the server. {}", err)
+                    },
+                    Ok(false) => println!("Packet {} with {} bytes received from the server.", frame.id, frame.data.len()),
+                    Err(err) => println!("Invalid packet received from the server. {}", err)
+                }
+            }
+
+            if let Some(ack) = entities.snapshot_ack() {
+                let _ = connection.send_udp(&ack);
+            }
+        }
+
+        entities.update(time::precise_time_ns());
+
+        match connection {
+            Some(ref mut connection) => {
+                // Until the server says which entity the player controls, there is nothing to move.
+                if entities.controlled().is_some() {
+                    for input in player.predict(window.frame_duration()) {
+                        if let Err(err) = connection.send_udp(&input) {
+                            println!("Unable to send input to the server. {}", err);
+                        }
+                    }
+                }
+            },
+            None => player.update(&window)
+        }
 
         window.clear();
+        entities.draw(&mut window);
         window.draw(player.character());
+        window.draw(&chat);
         window.present();
     }
 }
+
+/// Reads the milliseconds in the environment variable. Returns None if it is not set.
+fn milliseconds(variable: &str) -> Result<Option<u64>, String> {
+    match env::var(variable) {
+        Ok(value) => match value.parse::<u64>() {
+            Ok(ms) => Ok(Some(ms)),
+            Err(err) => Err(format!("Invalid {} '{}'. {}", variable, value, err))
+        },
+        Err(_) => Ok(None)
+    }
+}
+
+/// Tells the player why the server has disconnected them.
+fn show_disconnect(window: &mut graphics::RenderWindow, disconnect: &Disconnect) {
+    let reason = match disconnect.reason {
+        DisconnectReason::ServerShutdown => "Server shutting down",
+        DisconnectReason::Kicked => "Kicked from the server",
+        DisconnectReason::Flooding => "Sent too much to the server",
+        DisconnectReason::Unreachable => "Lost the connection to the server"
+    };
+
+    println!("{}. {}", reason, disconnect.message);
+    window.set_title(&format!("{} - {}", TITLE, reason));
+}
@@ -2,6 +2,8 @@
 //!
 //! The entity trait is implemented for everything living, especially stuff that moves about.
 
+use std::cmp;
+
 pub trait Entity {
 	/// # The current health
 	///
@@ -29,3 +31,17 @@ pub trait Entity {
 	/// health, this function should return the theoretical amount, not the actual amount.
 	fn heal<T: Entity>(&mut self, raw_healing: u16, source: &T) -> u16;
 }
+
+/// # Damage a health
+///
+/// Returns the health that is left after the damage has been done. It never drops below zero.
+pub fn damaged(health: u16, damage: u16) -> u16 {
+	health.saturating_sub(damage)
+}
+
+/// # Heal a health
+///
+/// Returns the health after the healing has been done. It never exceeds the maximum health.
+pub fn healed(health: u16, healing: u16, max_health: u16) -> u16 {
+	cmp::min(health.saturating_add(healing), max_health)
+}
@@ -0,0 +1,250 @@
+//! # The entities known to the client
+//!
+//! The entity table holds every remote entity by its id, and applies the entity packets the
+//! server sends to them. Entities are shown as they were a short delay ago, so there is always a
+//! snapshot to move them towards, even if the next one is late. The snapshots are timed by the
+//! tick of the server they are from, not by when they arrived, so jitter does not show.
+//!
+//! The world snapshots of the server only contain what changed since a snapshot the client has
+//! acknowledged, so the recent snapshots are kept to apply the following ones to. Entities are
+//! spawned and despawned as they appear in and disappear from the snapshots.
+
+use std::collections::{HashMap, VecDeque};
+
+use graphics::RenderWindow;
+use packets::{Frame, Packet, PacketError, SpawnEntity, MoveEntity, ChangeHealth, DespawnEntity, ControlEntity, SnapshotAck, TickRate, WorldSnapshot, WorldState};
+use remote_entity::RemoteEntity;
+use server_clock::ServerClock;
+use time;
+
+/// The delay in milliseconds remote entities are shown with, unless another one is set.
+pub const DEFAULT_INTERPOLATION_DELAY_MS: u64 = 100;
+
+/// The time in milliseconds the movement of an entity is continued after its newest snapshot.
+/// After that, it stops until the next snapshot arrives.
+pub const DEFAULT_MAX_EXTRAPOLATION_MS: u64 = 250;
+
+/// The amount of world snapshots that are kept for later snapshots to be relative to. It must be
+/// larger than the amount the server waits for acknowledgements.
+const MAX_WORLD_SNAPSHOTS: usize = 64;
+
+pub struct EntityTable {
+	entities: HashMap<u32, RemoteEntity>,
+
+	// The entity the server lets this client control, once it has been told.
+	controlled: Option<u32>,
+
+	// Translates the ticks of the server into the time of the client.
+	clock: ServerClock,
+
+	// Both in nanoseconds.
+	interpolation_delay: u64,
+	max_extrapolation: u64,
+
+	// The recent world states by their tick, oldest first.
+	world_snapshots: VecDeque<(u32, WorldState)>,
+
+	// The snapshot that has been applied, but not acknowledged yet.
+	unacknowledged: Option<u32>
+}
+
+impl EntityTable {
+	pub fn new() -> EntityTable {
+		EntityTable {
+			entities: HashMap::new(),
+			controlled: None,
+			clock: ServerClock::new(),
+			interpolation_delay: DEFAULT_INTERPOLATION_DELAY_MS * 1000000,
+			max_extrapolation: DEFAULT_MAX_EXTRAPOLATION_MS * 1000000,
+			world_snapshots: VecDeque::new(),
+			unacknowledged: None
+		}
+	}
+
+	/// # Set the interpolation delay
+	///
+	/// Sets how many milliseconds remote entities are shown behind the server. The delay should be
+	/// larger than the time between two snapshots plus the jitter of the connection, otherwise
+	/// entities are extrapolated most of the time.
+	pub fn set_interpolation_delay(&mut self, delay_ms: u64) {
+		self.interpolation_delay = delay_ms * 1000000;
+	}
+
+	/// # Set the maximum extrapolation
+	///
+	/// Sets how many milliseconds the movement of an entity is continued when no newer snapshot
+	/// has arrived.
+	pub fn set_max_extrapolation(&mut self, extrapolation_ms: u64) {
+		self.max_extrapolation = extrapolation_ms * 1000000;
+	}
+
+	/// # Apply a frame
+	///
+	/// Applies the entity packet contained in the frame. Returns false if the frame does not
+	/// contain an entity packet, so it can be handled elsewhere.
+	///
+	/// # Failure
+	/// In case the packet cannot be decoded, an error is returned.
+	pub fn apply(&mut self, frame: &Frame, game_window: &mut RenderWindow) -> Result<bool, PacketError> {
+		let now = time::precise_time_ns();
+
+		if frame.id == SpawnEntity::id() {
+			let spawn = try!(frame.decode::<SpawnEntity>());
+
+			match RemoteEntity::new(game_window, spawn.id, spawn.kind, spawn.max_health, spawn.health, spawn.pos, None, now) {
+				Ok(entity) => {
+					// In case an entity with the id already exists, it is replaced.
+					self.entities.insert(spawn.id, entity);
+				},
+				Err(err) => println!("Unable to spawn entity {}. {}", spawn.id, err)
+			}
+		}
+		else if frame.id == MoveEntity::id() {
+			let movement = try!(frame.decode::<MoveEntity>());
+
+			if let Some(entity) = self.entities.get_mut(&movement.id) {
+				let time = self.clock.time(movement.tick, now);
+				entity.push_snapshot(movement.tick, time, movement.pos, movement.velocity);
+			}
+		}
+		else if frame.id == ChangeHealth::id() {
+			let change = try!(frame.decode::<ChangeHealth>());
+
+			if let Some(entity) = self.entities.get_mut(&change.id) {
+				entity.set_health(change.health);
+			}
+		}
+		else if frame.id == DespawnEntity::id() {
+			let despawn = try!(frame.decode::<DespawnEntity>());
+
+			self.entities.remove(&despawn.id);
+		}
+		else if frame.id == WorldSnapshot::id() {
+			let snapshot = try!(frame.decode::<WorldSnapshot>());
+
+			self.apply_snapshot(&snapshot, now, game_window);
+		}
+		else if frame.id == ControlEntity::id() {
+			let control = try!(frame.decode::<ControlEntity>());
+
+			self.controlled = Some(control.id);
+		}
+		else if frame.id == TickRate::id() {
+			let rate = try!(frame.decode::<TickRate>());
+
+			self.clock.set_tick_rate(rate.tick_rate);
+		}
+		else {
+			return Ok(false);
+		}
+
+		Ok(true)
+	}
+
+	/// Creates the world state from the snapshot and brings the entities up to it. Snapshots that
+	/// are older than the newest one, or relative to a snapshot that is not known, are ignored.
+	/// The snapshot has been received at the time provided in nanoseconds.
+	fn apply_snapshot(&mut self, snapshot: &WorldSnapshot, received: u64, game_window: &mut RenderWindow) {
+		if let Some(&(newest, _)) = self.world_snapshots.back() {
+			if snapshot.tick <= newest {
+				return;
+			}
+		}
+
+		let state = {
+			let baseline = match snapshot.baseline {
+				Some(baseline_tick) => match self.world_snapshots.iter().find(|&&(tick, _)| tick == baseline_tick) {
+					Some(&(_, ref baseline)) => Some(baseline),
+					None => {
+						println!("Snapshot {} is relative to the unknown snapshot {}.", snapshot.tick, baseline_tick);
+						return;
+					}
+				},
+				None => None
+			};
+
+			match snapshot.apply(baseline) {
+				Ok(state) => state,
+				Err(err) => {
+					println!("Unable to apply snapshot {}. {}", snapshot.tick, err);
+					return;
+				}
+			}
+		};
+
+		self.entities.retain(|id, _| state.contains_key(id));
+
+		let time = self.clock.time(snapshot.tick, received);
+
+		for (id, entity_state) in state.iter() {
+			if let Some(entity) = self.entities.get_mut(id) {
+				entity.push_snapshot(snapshot.tick, time, entity_state.pos(), entity_state.velocity());
+				entity.set_health(entity_state.health);
+				continue;
+			}
+
+			match RemoteEntity::new(game_window, *id, entity_state.kind, entity_state.max_health, entity_state.health, entity_state.pos(), Some(snapshot.tick), time) {
+				Ok(entity) => {
+					self.entities.insert(*id, entity);
+				},
+				Err(err) => println!("Unable to spawn entity {}. {}", id, err)
+			}
+		}
+
+		if self.world_snapshots.len() >= MAX_WORLD_SNAPSHOTS {
+			self.world_snapshots.pop_front();
+		}
+		self.world_snapshots.push_back((snapshot.tick, state));
+		self.unacknowledged = Some(snapshot.tick);
+	}
+
+	/// # Acknowledge snapshots
+	///
+	/// Returns the acknowledgement of the newest world snapshot, if one has been applied since the
+	/// last call. It must be sent to the server.
+	pub fn snapshot_ack(&mut self) -> Option<SnapshotAck> {
+		self.unacknowledged.take().map(|tick| SnapshotAck { tick: tick })
+	}
+
+	/// # Update all entities
+	///
+	/// Moves every entity to where it was the interpolation delay before the current time in
+	/// nanoseconds.
+	pub fn update(&mut self, now: u64) {
+		let render_time = now.saturating_sub(self.interpolation_delay);
+
+		for (_, entity) in self.entities.iter_mut() {
+			entity.update(render_time, self.max_extrapolation);
+		}
+	}
+
+	/// # Draw all entities
+	///
+	/// Draws every entity to the window, except the controlled one, which is drawn by the player.
+	pub fn draw(&self, game_window: &mut RenderWindow) {
+		for (id, entity) in self.entities.iter() {
+			if Some(*id) != self.controlled {
+				game_window.draw(entity);
+			}
+		}
+	}
+
+	/// # The controlled entity
+	///
+	/// Returns the entity the client controls, if the server has said which one it is and it
+	/// exists.
+	pub fn controlled(&self) -> Option<&RemoteEntity> {
+		match self.controlled {
+			Some(id) => self.entities.get(&id),
+			None => None
+		}
+	}
+
+	pub fn get(&self, id: u32) -> Option<&RemoteEntity> {
+		self.entities.get(&id)
+	}
+
+	pub fn len(&self) -> usize {
+		self.entities.len()
+	}
+}
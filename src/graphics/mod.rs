@@ -5,11 +5,14 @@
  */
 
 pub mod drawable;
+pub mod font;
 pub mod render_window;
 pub mod sprite;
+pub mod text;
 pub mod texture_manager;
 
 pub use self::drawable::Drawable;
 pub use self::render_window::RenderWindow;
 pub use self::sprite::Sprite;
+pub use self::text::Text;
 pub use self::texture_manager::TextureManager;
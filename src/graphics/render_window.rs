@@ -6,6 +6,7 @@
 // drawables would have to be shareable.
 
 use sdl2::{EventPump, Sdl};
+use sdl2::pixels::Color;
 use sdl2::render::Renderer;
 pub use sdl2::event::EventPollIterator;
 
@@ -55,6 +56,16 @@ impl<'a> RenderWindow<'a> {
 		}
 	}
 
+	/// # Set the title
+	///
+	/// Changes the title of the window, which is also a simple way to tell the player something
+	/// without drawing any text.
+	pub fn set_title(&mut self, title: &str) {
+		if let Some(window) = self.renderer.lock().unwrap().window_mut() {
+			window.set_title(title);
+		}
+	}
+
 	/// # Create a sprite
 	///
 	/// This function creates a new sprite bound to the renderer of this window. It takes the name
@@ -87,6 +98,10 @@ impl<'a> RenderWindow<'a> {
 	/// Clear the screen in all black colour to avoid artifacts that otherwise may occur.
 	pub fn clear(&mut self) {
 		let mut renderer = self.renderer.lock().unwrap();
+
+		// Drawables may have changed the draw colour, which is also the one the screen is cleared
+		// with.
+		renderer.set_draw_color(Color::RGB(0, 0, 0));
 		renderer.clear();
 	}
 
@@ -0,0 +1,81 @@
+//! # Text
+//!
+//! A single line of text drawn with the bitmap font. Every pixel of a glyph is filled as a
+//! rectangle, so text can be drawn in any colour and scale without any textures.
+
+extern crate sdl2;
+use sdl2::pixels::Color;
+use sdl2::rect::Rect;
+use sdl2::render::Renderer;
+use graphics::Drawable;
+use graphics::font::{self, GLYPH_WIDTH, GLYPH_HEIGHT};
+
+pub struct Text {
+	string: String,
+	x: i32,
+	y: i32,
+	color: Color,
+	scale: u32
+}
+
+impl Text {
+	/// # Create a new text
+	///
+	/// The position is the top left corner of the first character. The text is drawn at its
+	/// original size, unless it is scaled with set_scale().
+	pub fn new(string: &str, pos: (i32, i32), color: Color) -> Text {
+		Text {
+			string: String::from(string),
+			x: pos.0,
+			y: pos.1,
+			color: color,
+			scale: 1
+		}
+	}
+
+	/// # Scale the text
+	///
+	/// Every pixel of the font is drawn as a square with the side length provided.
+	pub fn set_scale(&mut self, scale: u32) {
+		self.scale = scale;
+	}
+
+	/// # The width of the text
+	///
+	/// Returns the width in pixels the text takes up when it is drawn.
+	pub fn width(&self) -> u32 {
+		self.string.chars().count() as u32 * GLYPH_WIDTH * self.scale
+	}
+
+	/// # The height of the text
+	///
+	/// Returns the height in pixels of the line the text is drawn on.
+	pub fn height(&self) -> u32 {
+		GLYPH_HEIGHT * self.scale
+	}
+}
+
+impl Drawable for Text {
+	fn draw(&self, renderer: &mut Renderer) {
+		let scale = self.scale as i32;
+		let mut pixels = Vec::new();
+
+		for (index, c) in self.string.chars().enumerate() {
+			let left = self.x + index as i32 * GLYPH_WIDTH as i32 * scale;
+
+			for (row, bits) in font::glyph(c).iter().enumerate() {
+				for column in 0..GLYPH_WIDTH {
+					if bits & (1 << (GLYPH_WIDTH - 1 - column)) != 0 {
+						pixels.push(Rect::new_unwrap(left + column as i32 * scale, self.y + row as i32 * scale, self.scale, self.scale));
+					}
+				}
+			}
+		}
+
+		// All pixels are filled at once, since filling them one by one is slow for longer texts.
+		if !pixels.is_empty() {
+			renderer.set_draw_color(self.color);
+			renderer.fill_rects(&pixels);
+		}
+	}
+}
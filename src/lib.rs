@@ -0,0 +1,25 @@
+//! # Stupid game library
+//!
+//! The parts of the game that do not need a window: the packets, the connection hub of the server,
+//! the connection of the client to it and the internal library. Both the server and the client are
+//! built on top of it, and so are the tests and the fuzz targets, which need to reach the packets
+//! and the hub from outside of the server.
+
+#![feature(append)]
+#![feature(associated_consts)]
+
+#[cfg(feature = "async")]
+extern crate futures;
+extern crate mio;
+#[macro_use]
+extern crate packet_derive;
+extern crate rand;
+extern crate socket2;
+extern crate time;
+#[cfg(feature = "async")]
+extern crate tokio;
+
+pub mod net;
+pub mod packets;
+pub mod srv;
+pub mod sys;
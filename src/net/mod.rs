@@ -0,0 +1,7 @@
+//! # Client networking
+//!
+//! The clients counterpart to the servers connection hub. Everything the client needs to talk to
+//! the server lives here.
+
+pub mod server_connection;
+pub use self::server_connection::ServerConnection;
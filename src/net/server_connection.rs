@@ -0,0 +1,444 @@
+//! # Connection to the server
+//!
+//! The server connection holds the TCP stream and the UDP socket the client uses to talk to the
+//! server. Receiving happens in the background, so the game loop can fetch all packets that have
+//! arrived since the last frame without ever having to wait for the network.
+//!
+//! Like the connection hub, the connection can run its datagrams through a simulated network, to
+//! see how the client copes with a bad one. The stream is never simulated on the client.
+
+use std::io;
+use std::net::{TcpStream, UdpSocket, SocketAddr, ToSocketAddrs, Shutdown};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Sender, Receiver, TryIter};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use packets::{Channel, Frame, Packet, PacketError, Hello, Welcome, Reject, Disconnect, Ping, Pong, PROTOCOL_VERSION};
+use packets::{Authenticate, Challenge, RejectedDatagrams, SessionAuth, SharedKey, Side};
+use packets::auth;
+use packets::frame;
+use srv::{NetworkConditions, NetworkSimulator};
+use time;
+
+/// The largest datagram that is read from the UDP socket.
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// The time after which the UDP receiver checks whether the connection is still open, and whether
+/// reliable datagrams have to be sent again.
+const UDP_POLL_INTERVAL_MS: u64 = 20;
+
+/// The time between two datagrams that do not contain anything but the session token, and the
+/// seal in authenticated sessions. They tell the server where to send datagrams, and keep NATs
+/// from forgetting about the client.
+const UDP_BIND_INTERVAL_MS: u64 = 1000;
+
+/// The time the server has to answer the hello of the client.
+const HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+/// The simulated network the datagrams the client sends go through, which both the game loop and
+/// the UDP thread send on.
+type SafeSimulator = Arc<Mutex<NetworkSimulator>>;
+
+pub struct ServerConnection {
+	// Writing is locked, since the receiving thread answers pings on its own.
+	stream: Arc<Mutex<TcpStream>>,
+	udp_socket: UdpSocket,
+	udp_channel: Arc<Mutex<Channel>>,
+	// If the network is simulated, datagrams are sent through the simulator instead.
+	outgoing: Option<SafeSimulator>,
+	server_address: SocketAddr,
+	// Where the server receives datagrams. It has the address of the stream, but the port the
+	// server has welcomed the client with.
+	udp_address: SocketAddr,
+	received: Receiver<Frame>,
+	open: Arc<AtomicBool>,
+	session_id: u32,
+	token: u64
+}
+
+impl ServerConnection {
+	/// # Connect to a server
+	///
+	/// Opens the TCP connection to the server and a UDP socket to send datagrams from. The server
+	/// tells the client which port it receives datagrams on, which may differ from the port of the
+	/// stream. The client introduces itself with the name provided.
+	///
+	/// If the server demands a key, the client proves it knows the key provided, and all datagrams
+	/// of the session are signed.
+	///
+	/// With network conditions, every datagram goes through a simulated network on its way to and
+	/// from the socket, one for each direction, seeded differently. The stream is used as it is.
+	///
+	/// # Failure
+	/// In case the server cannot be reached or rejects the client, for instance because it speaks
+	/// a different protocol version or the key is wrong, the reason is returned.
+	pub fn connect<A: ToSocketAddrs>(addr: A, name: &str, key: Option<&SharedKey>, conditions: Option<NetworkConditions>) -> Result<ServerConnection, String> {
+		let mut stream = match TcpStream::connect(addr) {
+			Ok(stream) => stream,
+			Err(err) => return Err(format!("Unable to connect to server. {}", err))
+		};
+
+		let (welcome, auth) = try!(ServerConnection::handshake(&mut stream, name, key));
+
+		let server_address = stream.peer_addr().unwrap();
+		let udp_address = SocketAddr::new(server_address.ip(), welcome.udp_port);
+
+		// The server recognises datagrams by the token, so any port will do. The socket is bound to
+		// the same interface the stream uses, though, so the server is reachable from it.
+		let udp_socket = match UdpSocket::bind((stream.local_addr().unwrap().ip(), 0)) {
+			Ok(socket) => socket,
+			Err(err) => return Err(format!("Unable to open port on Udp. {}", err))
+		};
+
+		let (sender, received) = mpsc::channel();
+		let open = Arc::new(AtomicBool::new(true));
+
+		let stream_clone = stream.try_clone().unwrap();
+		let stream = Arc::new(Mutex::new(stream));
+		let writer = stream.clone();
+		let sender_clone = sender.clone();
+		let open_clone = open.clone();
+		thread::spawn(move || {
+			ServerConnection::receive_tcp(stream_clone, writer, sender_clone, open_clone);
+		});
+
+		let udp_socket_clone = udp_socket.try_clone().unwrap();
+		let authenticated = auth.is_some();
+		let udp_channel = match auth {
+			Some(auth) => Channel::authenticated(auth),
+			None => Channel::new()
+		};
+		let udp_channel = Arc::new(Mutex::new(udp_channel));
+		let udp_channel_clone = udp_channel.clone();
+		let outgoing = conditions.map(|conditions| Arc::new(Mutex::new(NetworkSimulator::new(conditions))));
+		let incoming = conditions.map(|conditions| NetworkSimulator::new(NetworkConditions { seed: !conditions.seed, ..conditions }));
+		let outgoing_clone = outgoing.clone();
+		let token = welcome.token;
+		let open_clone = open.clone();
+		thread::spawn(move || {
+			ServerConnection::receive_udp(udp_socket_clone, udp_channel_clone, outgoing_clone, incoming, udp_address, token, sender, open_clone);
+		});
+
+		println!("Connected to server {} (session {}{})", server_address, welcome.session_id, if authenticated { ", authenticated" } else { "" });
+
+		Ok(ServerConnection {
+			stream: stream,
+			udp_socket: udp_socket,
+			udp_channel: udp_channel,
+			outgoing: outgoing,
+			server_address: server_address,
+			udp_address: udp_address,
+			received: received,
+			open: open,
+			session_id: welcome.session_id,
+			token: welcome.token
+		})
+	}
+
+	/// # Introduce the client
+	///
+	/// Sends the hello to the server and waits for it to either welcome or reject the client. If
+	/// the server challenges the client instead, the challenge is answered with the key, and the
+	/// session it authenticates is returned along with the welcome.
+	fn handshake(stream: &mut TcpStream, name: &str, key: Option<&SharedKey>) -> Result<(Welcome, Option<SessionAuth>), String> {
+		let hello = Hello {
+			version: PROTOCOL_VERSION,
+			name: String::from(name)
+		};
+
+		if let Err(err) = Frame::new(&hello).write_to(stream) {
+			return Err(format!("Unable to greet the server. {}", err));
+		}
+
+		stream.set_read_timeout(Some(Duration::from_millis(HANDSHAKE_TIMEOUT_MS))).unwrap();
+		let mut frame = try!(ServerConnection::read_answer(stream));
+
+		let mut auth = None;
+		if frame.id == Challenge::id() {
+			let key = match key {
+				Some(key) => key,
+				None => return Err(String::from("The server requires a key."))
+			};
+
+			let challenge = match frame.decode::<Challenge>() {
+				Ok(challenge) => challenge,
+				Err(err) => return Err(format!("Invalid challenge. {}", err))
+			};
+
+			let nonce = auth::nonce();
+			let authenticate = Authenticate {
+				nonce: nonce,
+				proof: key.prove(&challenge.nonce, &nonce)
+			};
+
+			if let Err(err) = Frame::new(&authenticate).write_to(stream) {
+				return Err(format!("Unable to answer the challenge. {}", err));
+			}
+
+			frame = try!(ServerConnection::read_answer(stream));
+			auth = Some(key.session(&challenge.nonce, &nonce, Side::Client));
+		}
+		stream.set_read_timeout(None).unwrap();
+
+		if frame.id == Reject::id() {
+			return match frame.decode::<Reject>() {
+				Ok(reject) => Err(format!("The server rejected the connection. {}", reject.message)),
+				Err(err) => Err(format!("The server rejected the connection. {}", err))
+			};
+		}
+
+		// A server that does not ask for the key might not be the one the key belongs to.
+		if key.is_some() && auth.is_none() {
+			return Err(String::from("The server did not ask for the key."));
+		}
+
+		match frame.decode::<Welcome>() {
+			Ok(welcome) => Ok((welcome, auth)),
+			Err(err) => Err(format!("Invalid answer to the greeting. {}", err))
+		}
+	}
+
+	fn read_answer(stream: &mut TcpStream) -> Result<Frame, String> {
+		match Frame::read_from(stream) {
+			Ok(frame) => Ok(frame),
+			Err(err) => Err(format!("The server did not answer the greeting. {}", err))
+		}
+	}
+
+	fn receive_tcp(mut stream: TcpStream, writer: Arc<Mutex<TcpStream>>, sender: Sender<Frame>, open: Arc<AtomicBool>) {
+		while open.load(Ordering::Relaxed) {
+			let frame = match Frame::read_from(&mut stream) {
+				Ok(frame) => frame,
+				Err(PacketError::Truncated) => {
+					println!("The server closed the connection.");
+					break
+				},
+				Err(err) => {
+					println!("Connection to the server has been closed due to: {}", err);
+					break
+				}
+			};
+
+			// Pings are answered right away, so that the game loop does not distort the round trip
+			// time the server measures.
+			if frame.id == Ping::id() {
+				match frame.decode::<Ping>() {
+					Ok(ping) => {
+						let mut writer = writer.lock().unwrap();
+						let _ = Frame::new(&Pong { timestamp: ping.timestamp }).write_to(&mut *writer);
+					},
+					Err(err) => println!("Invalid ping received from the server. {}", err)
+				}
+
+				continue;
+			}
+
+			// The server closes the connection right after a disconnect, so there is nothing left to
+			// read. The game is told about it like about any other packet.
+			let disconnect = frame.id == Disconnect::id();
+
+			// If the receiver is gone, nobody is interested in the packets any longer.
+			if sender.send(frame).is_err() || disconnect {
+				break;
+			}
+		}
+
+		open.store(false, Ordering::Relaxed);
+	}
+
+	fn receive_udp(udp_socket: UdpSocket, udp_channel: Arc<Mutex<Channel>>, outgoing: Option<SafeSimulator>, mut incoming: Option<NetworkSimulator>, udp_address: SocketAddr, token: u64, sender: Sender<Frame>, open: Arc<AtomicBool>) {
+		// The timeout is necessary, because otherwise the thread could never notice the connection
+		// being closed while no datagrams arrive.
+		udp_socket.set_read_timeout(Some(Duration::from_millis(UDP_POLL_INTERVAL_MS))).unwrap();
+
+		let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+		let mut last_bind = 0;
+
+		while open.load(Ordering::Relaxed) {
+			let now = time::precise_time_ns();
+			if now - last_bind >= UDP_BIND_INTERVAL_MS * 1000000 {
+				let empty = udp_channel.lock().unwrap().empty();
+				let _ = ServerConnection::send_datagram(&udp_socket, &outgoing, ServerConnection::with_token(token, &empty), udp_address, now);
+				last_bind = now;
+			}
+
+			let datagrams = {
+				let mut udp_channel = udp_channel.lock().unwrap();
+				let datagrams = udp_channel.resend(now);
+
+				// The server no longer acknowledges anything, so the connection is as good as lost.
+				if udp_channel.is_failed() {
+					println!("The server stopped acknowledging datagrams.");
+					break;
+				}
+
+				datagrams
+			};
+
+			for datagram in datagrams {
+				let _ = ServerConnection::send_datagram(&udp_socket, &outgoing, ServerConnection::with_token(token, &datagram), udp_address, now);
+			}
+
+			// On a simulated network, whatever has made it through by now is sent or received, and
+			// the socket is not waited for longer than until the next datagram arrives.
+			if let Some(ref outgoing) = outgoing {
+				for (addr, datagram) in outgoing.lock().unwrap().deliver(now) {
+					let _ = udp_socket.send_to(&datagram, addr);
+				}
+			}
+
+			let arrived = match incoming {
+				Some(ref mut incoming) => incoming.deliver(now),
+				None => Vec::new()
+			};
+
+			for (remote, datagram) in arrived {
+				if !ServerConnection::receive_datagram(&udp_socket, &udp_channel, &outgoing, &sender, &datagram, remote, udp_address, token) {
+					return;
+				}
+			}
+
+			if let Some(ref outgoing) = outgoing {
+				let next_arrival = outgoing.lock().unwrap().next_arrival().into_iter().chain(incoming.as_ref().and_then(|incoming| incoming.next_arrival())).min();
+				let timeout = match next_arrival {
+					Some(arrival) => UDP_POLL_INTERVAL_MS.min(arrival.saturating_sub(now) / 1000000 + 1),
+					None => UDP_POLL_INTERVAL_MS
+				};
+				udp_socket.set_read_timeout(Some(Duration::from_millis(timeout))).unwrap();
+			}
+
+			let (size, remote) = match udp_socket.recv_from(&mut buffer) {
+				Ok(received) => received,
+				Err(_) => continue
+			};
+
+			match incoming {
+				Some(ref mut incoming) => incoming.send(buffer[..size].to_vec(), remote, time::precise_time_ns()),
+				None => if !ServerConnection::receive_datagram(&udp_socket, &udp_channel, &outgoing, &sender, &buffer[..size], remote, udp_address, token) {
+					return;
+				}
+			}
+		}
+
+		open.store(false, Ordering::Relaxed);
+	}
+
+	/// Reads the datagram with the UDP channel, acknowledges it and hands the frames it releases
+	/// to the game. Returns false if the game is no longer interested in them.
+	fn receive_datagram(udp_socket: &UdpSocket, udp_channel: &Arc<Mutex<Channel>>, outgoing: &Option<SafeSimulator>, sender: &Sender<Frame>, datagram: &[u8], remote: SocketAddr, udp_address: SocketAddr, token: u64) -> bool {
+		// Datagrams that do not come from the server are ignored.
+		if remote != udp_address {
+			return true;
+		}
+
+		let received = match udp_channel.lock().unwrap().receive(datagram) {
+			Ok(received) => received,
+			Err(err) => {
+				println!("Invalid datagram received from the server. {}", err);
+				return true;
+			}
+		};
+
+		if let Some(ack) = received.ack {
+			let _ = ServerConnection::send_datagram(udp_socket, outgoing, ServerConnection::with_token(token, &ack), udp_address, time::precise_time_ns());
+		}
+
+		for frame in received.frames {
+			if sender.send(frame).is_err() {
+				return false;
+			}
+		}
+
+		true
+	}
+
+	/// Sends the datagram right away, or hands it to the simulated network, which counts as sent.
+	/// Whatever has made it through the simulated network by now is sent along with it.
+	fn send_datagram(udp_socket: &UdpSocket, outgoing: &Option<SafeSimulator>, datagram: Vec<u8>, addr: SocketAddr, now: u64) -> io::Result<usize> {
+		let outgoing = match *outgoing {
+			Some(ref outgoing) => outgoing,
+			None => return udp_socket.send_to(&datagram, addr)
+		};
+
+		let size = datagram.len();
+		let mut outgoing = outgoing.lock().unwrap();
+		outgoing.send(datagram, addr, now);
+
+		for (addr, datagram) in outgoing.deliver(now) {
+			let _ = udp_socket.send_to(&datagram, addr);
+		}
+
+		Ok(size)
+	}
+
+	/// Prefixes the channel datagram with the session token, so the server knows who sent it.
+	fn with_token(token: u64, datagram: &[u8]) -> Vec<u8> {
+		let mut bytes = frame::token_to_bytes(token);
+		bytes.extend(datagram.iter().cloned());
+
+		bytes
+	}
+
+	/// # Poll received frames
+	///
+	/// Returns an iterator over all frames that have been received since the last call. It never
+	/// blocks, so it can be called once every frame of the game loop.
+	pub fn poll_frames(&mut self) -> TryIter<Frame> {
+		self.received.try_iter()
+	}
+
+	/// # Send a packet using TCP
+	///
+	/// The packet is guaranteed to arrive intact and in order, but may take longer than a packet
+	/// sent with send_udp().
+	pub fn send_tcp<P: Packet>(&mut self, data: &P) -> Result<usize, PacketError> {
+		let mut stream = self.stream.lock().unwrap();
+		Frame::new(data).write_to(&mut *stream).map_err(PacketError::from)
+	}
+
+	/// # Send a packet using UDP
+	///
+	/// Fast, but depending on the delivery of the packet type, it may be lost or arrive out of
+	/// order.
+	pub fn send_udp<P: Packet>(&mut self, data: &P) -> Result<usize, PacketError> {
+		let now = time::precise_time_ns();
+		let datagram = match self.udp_channel.lock().unwrap().send(data, now) {
+			Some(datagram) => ServerConnection::with_token(self.token, &datagram),
+			// Queued until earlier datagrams have been acknowledged, the UDP thread sends it then.
+			None => return Ok(0)
+		};
+
+		ServerConnection::send_datagram(&self.udp_socket, &self.outgoing, datagram, self.udp_address, now).map_err(PacketError::from)
+	}
+
+	pub fn server_address(&self) -> SocketAddr {
+		self.server_address
+	}
+
+	pub fn session_id(&self) -> u32 {
+		self.session_id
+	}
+
+	/// # The rejected datagrams
+	///
+	/// Returns how many datagrams claiming to be from the server have been rejected, because they
+	/// were forged or replayed. This only happens in authenticated sessions.
+	pub fn rejected_datagrams(&self) -> RejectedDatagrams {
+		self.udp_channel.lock().unwrap().rejected()
+	}
+
+	pub fn is_open(&self) -> bool {
+		self.open.load(Ordering::Relaxed)
+	}
+}
+
+impl Drop for ServerConnection {
+	fn drop(&mut self) {
+		// Both receiving threads stop on their own, once the connection is marked as closed and the
+		// stream has been shut down.
+		self.open.store(false, Ordering::Relaxed);
+		let _ = self.stream.lock().unwrap().shutdown(Shutdown::Both);
+	}
+}
@@ -0,0 +1,301 @@
+//! # Authentication
+//!
+//! A server may demand that its clients know a key it shares with them beforehand. After the
+//! hello, the server challenges the client with a random nonce, and the client answers with a
+//! nonce of its own and a proof that it knows the key. The key itself is never sent.
+//!
+//! Both sides then derive a key for the session from the shared key and the two nonces. Every
+//! datagram of the session carries a counter and a signature made with it, so datagrams that have
+//! been forged or altered are rejected. Since the counter is signed aswell, a datagram that has
+//! been received before is rejected too, even though its signature is valid.
+
+use packets::PacketError;
+use std::io::Cursor;
+
+use packets::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use packets::hmac::{Hmac, KeyInit, Mac};
+use packets::sha2::Sha256;
+use rand;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// The size of the nonces both sides choose during the handshake.
+pub const NONCE_SIZE: usize = 16;
+
+/// The size of the proof the client answers the challenge with.
+pub const PROOF_SIZE: usize = 32;
+
+/// The size of the counter appended to every datagram of an authenticated session.
+const COUNTER_SIZE: usize = 8;
+
+/// The size of the signature following the counter. Signatures are cut down to this size, which
+/// is plenty for datagrams that are only valid during a single session.
+const SIGNATURE_SIZE: usize = 16;
+
+/// The amount of bytes every datagram of an authenticated session is longer than without.
+pub const SEAL_SIZE: usize = COUNTER_SIZE + SIGNATURE_SIZE;
+
+/// How far a counter may lag behind the newest one, for datagrams that arrive out of order to
+/// still be accepted. Anything older is rejected, since it cannot be told apart from a replay.
+const REPLAY_WINDOW: u64 = 64;
+
+// Every key that is derived from the shared key is derived for a single purpose, so that a value
+// computed for one can never be mistaken for another.
+const PROOF_LABEL: &'static [u8] = b"stupid client proof";
+const SESSION_LABEL: &'static [u8] = b"stupid session key";
+
+pub type Nonce = [u8; NONCE_SIZE];
+
+/// # Create a nonce
+///
+/// Returns a random nonce, which must never be used for more than a single handshake.
+pub fn nonce() -> Nonce {
+	rand::random()
+}
+
+/// Which end of the connection the datagrams are signed by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Side {
+	Client,
+	Server
+}
+
+impl Side {
+	fn to_u8(&self) -> u8 {
+		match self {
+			&Side::Client => 0,
+			&Side::Server => 1
+		}
+	}
+
+	fn other(&self) -> Side {
+		match self {
+			&Side::Client => Side::Server,
+			&Side::Server => Side::Client
+		}
+	}
+}
+
+/// The datagrams that have been rejected during a session.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RejectedDatagrams {
+	/// Datagrams without a valid signature.
+	pub forged: u64,
+
+	/// Datagrams with a valid signature that have been received before.
+	pub replayed: u64
+}
+
+/// # A pre-shared key
+///
+/// The key the server and its clients have agreed on before they ever connect.
+#[derive(Clone)]
+pub struct SharedKey {
+	key: Vec<u8>
+}
+
+impl SharedKey {
+	/// # Create a shared key
+	///
+	/// Any amount of bytes can be used as a key, but longer keys are harder to guess.
+	///
+	/// # Failure
+	/// An empty key would not protect anything, so an error is returned instead.
+	pub fn new(key: &[u8]) -> Result<SharedKey, String> {
+		if key.is_empty() {
+			return Err(String::from("The key must not be empty."));
+		}
+
+		Ok(SharedKey {
+			key: key.to_vec()
+		})
+	}
+
+	/// # Prove the key
+	///
+	/// Creates the proof of the client that it knows the key, for the nonces of this handshake.
+	pub fn prove(&self, server_nonce: &Nonce, client_nonce: &Nonce) -> [u8; PROOF_SIZE] {
+		let mut proof = [0; PROOF_SIZE];
+		proof.copy_from_slice(&self.derive(PROOF_LABEL, server_nonce, client_nonce).finalize().into_bytes());
+
+		proof
+	}
+
+	/// # Check a proof
+	///
+	/// Returns true if the proof has been created with this key for the nonces provided. The
+	/// comparison takes the same time no matter where the proof is wrong.
+	pub fn verify(&self, server_nonce: &Nonce, client_nonce: &Nonce, proof: &[u8]) -> bool {
+		self.derive(PROOF_LABEL, server_nonce, client_nonce).verify_slice(proof).is_ok()
+	}
+
+	/// # Start the session
+	///
+	/// Derives the key of the session from the nonces of the handshake. Both sides derive the same
+	/// key, but sign their datagrams differently, so that datagrams cannot be sent back to where
+	/// they came from.
+	pub fn session(&self, server_nonce: &Nonce, client_nonce: &Nonce, side: Side) -> SessionAuth {
+		let mut key = [0; 32];
+		key.copy_from_slice(&self.derive(SESSION_LABEL, server_nonce, client_nonce).finalize().into_bytes());
+
+		SessionAuth {
+			key: key,
+			side: side,
+			next_counter: 0,
+			newest: None,
+			received: 0,
+			rejected: RejectedDatagrams::default()
+		}
+	}
+
+	fn derive(&self, label: &[u8], server_nonce: &Nonce, client_nonce: &Nonce) -> HmacSha256 {
+		let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+		mac.update(label);
+		mac.update(server_nonce);
+		mac.update(client_nonce);
+
+		mac
+	}
+}
+
+/// # The authentication of a session
+///
+/// Signs the datagrams sent during a session and checks the ones received.
+pub struct SessionAuth {
+	key: [u8; 32],
+	side: Side,
+	next_counter: u64,
+
+	// The highest counter that has been received, and which of the counters before it have been
+	// received aswell, with the lowest bit being the newest one.
+	newest: Option<u64>,
+	received: u64,
+
+	rejected: RejectedDatagrams
+}
+
+impl SessionAuth {
+	/// # Seal a datagram
+	///
+	/// Appends the next counter and the signature of the datagram and the counter.
+	pub fn seal(&mut self, datagram: &[u8]) -> Vec<u8> {
+		let counter = self.next_counter;
+		self.next_counter += 1;
+
+		let mut sealed = Vec::with_capacity(datagram.len() + SEAL_SIZE);
+		sealed.extend(datagram.iter().cloned());
+
+		sealed.write_u64::<BigEndian>(counter).unwrap();
+
+		let signature = self.sign(self.side, &sealed).finalize().into_bytes();
+		sealed.extend(signature[..SIGNATURE_SIZE].iter().cloned());
+
+		sealed
+	}
+
+	/// # Open a datagram
+	///
+	/// Checks the signature and the counter of a datagram sealed by the other side, and returns
+	/// the datagram without them.
+	///
+	/// # Failure
+	/// If the datagram has not been signed with the key of the session, or has been received
+	/// before, it is counted and an error is returned.
+	pub fn open(&mut self, sealed: &[u8]) -> Result<Vec<u8>, PacketError> {
+		let (datagram, counter) = match self.check(sealed) {
+			Ok(checked) => checked,
+			Err(err) => {
+				match err {
+					PacketError::Replayed => self.rejected.replayed += 1,
+					_ => self.rejected.forged += 1
+				}
+
+				return Err(err);
+			}
+		};
+
+		self.remember(counter);
+		Ok(datagram.to_vec())
+	}
+
+	/// # Verify a datagram
+	///
+	/// Returns whether open() would accept the datagram, without opening it. Nothing is counted or
+	/// remembered, so the datagram can still be opened afterwards.
+	pub fn verify(&self, sealed: &[u8]) -> bool {
+		self.check(sealed).is_ok()
+	}
+
+	/// # The rejected datagrams
+	///
+	/// Returns how many datagrams have been rejected since the session started.
+	pub fn rejected(&self) -> RejectedDatagrams {
+		self.rejected
+	}
+
+	fn sign(&self, sender: Side, data: &[u8]) -> HmacSha256 {
+		let mut mac = HmacSha256::new_from_slice(&self.key).unwrap();
+		mac.update(&[sender.to_u8()]);
+		mac.update(data);
+
+		mac
+	}
+
+	/// Checks the signature and the counter of the datagram, and returns the datagram without them
+	/// along with the counter.
+	fn check<'a>(&self, sealed: &'a [u8]) -> Result<(&'a [u8], u64), PacketError> {
+		if sealed.len() < SEAL_SIZE {
+			return Err(PacketError::Forged);
+		}
+
+		let (signed, signature) = sealed.split_at(sealed.len() - SIGNATURE_SIZE);
+		if self.sign(self.side.other(), signed).verify_truncated_left(signature).is_err() {
+			return Err(PacketError::Forged);
+		}
+
+		let (datagram, counter) = signed.split_at(signed.len() - COUNTER_SIZE);
+		let counter = Cursor::new(counter).read_u64::<BigEndian>().unwrap();
+		if !self.is_new(counter) {
+			return Err(PacketError::Replayed);
+		}
+
+		Ok((datagram, counter))
+	}
+
+	/// Returns true, unless the counter has been received before or is too old to tell.
+	fn is_new(&self, counter: u64) -> bool {
+		let newest = match self.newest {
+			Some(newest) => newest,
+			None => return true
+		};
+
+		if counter > newest {
+			return true;
+		}
+
+		let age = newest - counter;
+		age < REPLAY_WINDOW && self.received & (1 << age) == 0
+	}
+
+	/// Remembers a counter that is new.
+	fn remember(&mut self, counter: u64) {
+		let newest = match self.newest {
+			Some(newest) => newest,
+			None => {
+				self.newest = Some(counter);
+				self.received = 1;
+				return;
+			}
+		};
+
+		if counter > newest {
+			let shift = counter - newest;
+			self.received = if shift >= REPLAY_WINDOW { 0 } else { self.received << shift };
+			self.received |= 1;
+			self.newest = Some(counter);
+		}
+		else {
+			self.received |= 1 << (newest - counter);
+		}
+	}
+}
@@ -0,0 +1,17 @@
+//! # Authenticate the client
+//!
+//! The clients answer to a challenge. It proves that the client knows the key of the server,
+//! without containing the key itself.
+
+use packets::auth::{Nonce, PROOF_SIZE};
+
+#[derive(Packet)]
+#[packet(id = 20)]
+pub struct Authenticate {
+	/// The random nonce of the client. Together with the nonce of the server, it makes the proof
+	/// and the session key unique to this handshake.
+	pub nonce: Nonce,
+
+	/// The proof that the client knows the key, created for both nonces.
+	pub proof: [u8; PROOF_SIZE]
+}
@@ -0,0 +1,13 @@
+//! # Challenge a client
+//!
+//! The servers answer to a hello, if it demands a key from its clients. The client has to prove
+//! that it knows the key before it is welcomed.
+
+use packets::auth::Nonce;
+
+#[derive(Packet)]
+#[packet(id = 19)]
+pub struct Challenge {
+	/// The random nonce of the server, which the proof of the client has to include.
+	pub nonce: Nonce
+}
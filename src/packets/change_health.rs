@@ -0,0 +1,48 @@
+//! # Change the health of an entity
+//!
+//! Sent whenever an entity has been damaged or healed. It carries both the amount, as returned by
+//! Entity::damage() or Entity::heal(), and the resulting health, which the receiver takes as is.
+
+use packets::{Field, PacketError};
+
+use std::io::Cursor;
+use packets::byteorder::{self, ReadBytesExt, WriteBytesExt};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HealthChange {
+	Damage,
+	Heal
+}
+
+impl Field for HealthChange {
+	fn read_field(data: &mut Cursor<&[u8]>) -> Result<HealthChange, PacketError> {
+		match try!(data.read_u8()) {
+			0 => Ok(HealthChange::Damage),
+			1 => Ok(HealthChange::Heal),
+			value => Err(PacketError::InvalidValue { field: "health change", value: value as u64 })
+		}
+	}
+
+	fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+		data.write_u8(match self {
+			&HealthChange::Damage => 0,
+			&HealthChange::Heal => 1
+		})
+	}
+}
+
+#[derive(Packet)]
+#[packet(id = 7, delivery = ReliableOrdered)]
+pub struct ChangeHealth {
+	/// The id of the entity whose health has changed.
+	pub id: u32,
+
+	/// Whether the entity has been damaged or healed.
+	pub change: HealthChange,
+
+	/// The amount of damage or healing that has been done.
+	pub amount: u16,
+
+	/// The health of the entity after the change.
+	pub health: u16
+}
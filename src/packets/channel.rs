@@ -0,0 +1,460 @@
+//! # Channels over UDP
+//!
+//! UDP datagrams may be lost, duplicated or arrive out of order. The channel numbers every
+//! message it sends, so the receiving channel can acknowledge, deduplicate and reorder them as
+//! the delivery of the packet type demands. Reliable messages are kept until they have been
+//! acknowledged, and sent again whenever resend() finds them to be overdue. The longer a message
+//! goes unacknowledged, the longer the channel waits before sending it again, and once it has
+//! been sent too often, the channel gives up and counts as failed.
+//!
+//! A reliable message is never sent while the oldest unacknowledged one of its delivery is a
+//! whole window behind it, since the other side could no longer tell them apart. It waits in the
+//! channel instead, until the older ones have been acknowledged.
+//!
+//! The channel does not own a socket. It only turns packets into datagrams and datagrams back into
+//! frames, so it can be driven by any kind of socket, even one that loses datagrams on purpose.
+//!
+//! In an authenticated session, the channel seals every datagram it creates and opens every
+//! datagram it receives, so nothing that has been forged or replayed ever gets past it.
+
+use packets::{Frame, Packet, PacketError, RejectedDatagrams, SessionAuth, expect_end};
+
+use std::cmp;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Cursor;
+use packets::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// A datagram carrying a packet.
+const MESSAGE: u8 = 0;
+
+/// A datagram acknowledging a reliable message.
+const ACK: u8 = 1;
+
+/// The time in milliseconds after which a reliable message that has not been acknowledged is sent
+/// again.
+pub const RESEND_TIMEOUT_MS: u64 = 100;
+
+/// The longest time in milliseconds the channel waits before sending a message again, however
+/// often it has been sent before.
+pub const MAX_RESEND_TIMEOUT_MS: u64 = 1000;
+
+/// How often a reliable message is sent again, before the channel gives up on it.
+pub const MAX_RESENDS: u32 = 20;
+
+/// How far a sequence number may lag behind the newest one, for duplicates to still be detected
+/// and for ordered messages to be buffered. The oldest unacknowledged message of a delivery is
+/// never further behind the newest one sent than that either.
+pub const SEQUENCE_WINDOW: u16 = 1024;
+
+/// # How a packet is delivered
+///
+/// Every packet type chooses how it is delivered when it is sent using UDP.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Delivery {
+	/// The packet may be lost, duplicated or arrive out of order.
+	Unreliable,
+
+	/// The packet arrives exactly once, but may overtake other packets.
+	ReliableUnordered,
+
+	/// The packet arrives exactly once, and after all ordered packets sent before it.
+	ReliableOrdered
+}
+
+impl Delivery {
+	fn from_u8(value: u8) -> Result<Delivery, PacketError> {
+		match value {
+			0 => Ok(Delivery::Unreliable),
+			1 => Ok(Delivery::ReliableUnordered),
+			2 => Ok(Delivery::ReliableOrdered),
+			value => Err(PacketError::InvalidValue { field: "delivery", value: value as u64 })
+		}
+	}
+
+	fn to_u8(&self) -> u8 {
+		match self {
+			&Delivery::Unreliable => 0,
+			&Delivery::ReliableUnordered => 1,
+			&Delivery::ReliableOrdered => 2
+		}
+	}
+}
+
+/// A reliable message waiting to be acknowledged.
+struct Pending {
+	datagram: Vec<u8>,
+	last_sent: u64,
+	resends: u32
+}
+
+/// # The result of a received datagram
+pub struct Received {
+	/// The frames that are ready to be handled, in the order they must be handled in. A single
+	/// ordered message may release many frames that had been waiting for it.
+	pub frames: Vec<Frame>,
+
+	/// The acknowledgement that must be sent back to the other channel, if any.
+	pub ack: Option<Vec<u8>>
+}
+
+pub struct Channel {
+	// Every delivery has its own sequence numbers, so ordered messages never wait for others.
+	next_sequence: HashMap<Delivery, u16>,
+	pending: HashMap<(Delivery, u16), Pending>,
+
+	// The reliable messages that are sent once fewer messages of their delivery are pending.
+	queued: HashMap<Delivery, VecDeque<Frame>>,
+
+	// Set once a message has been sent too often without being acknowledged.
+	failed: bool,
+
+	received_unordered: HashSet<u16>,
+	newest_unordered: Option<u16>,
+
+	next_ordered: u16,
+	ordered_buffer: HashMap<u16, Frame>,
+
+	resend_timeout: u64,
+
+	// Only set for authenticated sessions.
+	auth: Option<SessionAuth>
+}
+
+impl Channel {
+	pub fn new() -> Channel {
+		Channel {
+			next_sequence: HashMap::new(),
+			pending: HashMap::new(),
+			queued: HashMap::new(),
+			failed: false,
+			received_unordered: HashSet::new(),
+			newest_unordered: None,
+			next_ordered: 0,
+			ordered_buffer: HashMap::new(),
+			resend_timeout: RESEND_TIMEOUT_MS * 1000000,
+			auth: None
+		}
+	}
+
+	/// # Create an authenticated channel
+	///
+	/// Creates a channel that signs every datagram with the key of the session, and only accepts
+	/// datagrams signed by the other side.
+	pub fn authenticated(auth: SessionAuth) -> Channel {
+		let mut channel = Channel::new();
+		channel.auth = Some(auth);
+
+		channel
+	}
+
+	/// # Check the origin of a datagram
+	///
+	/// Returns whether the datagram has been signed by the other side and has not been received
+	/// before, without receiving it. Without authentication, there is no telling, so every
+	/// datagram is taken to be authentic.
+	pub fn is_authentic(&self, data: &[u8]) -> bool {
+		match self.auth {
+			Some(ref auth) => auth.verify(data),
+			None => true
+		}
+	}
+
+	/// # The rejected datagrams
+	///
+	/// Returns how many datagrams have been rejected, because they were forged or have been
+	/// received before. Without authentication, nothing is ever rejected for that.
+	pub fn rejected(&self) -> RejectedDatagrams {
+		match self.auth {
+			Some(ref auth) => auth.rejected(),
+			None => RejectedDatagrams::default()
+		}
+	}
+
+	/// # Create an empty datagram
+	///
+	/// Creates a datagram without a message, which only tells the other side where the channel can
+	/// be reached. It is received like any other datagram, but does not contain any frames.
+	pub fn empty(&mut self) -> Vec<u8> {
+		self.seal(Vec::new())
+	}
+
+	/// # Send a packet
+	///
+	/// Creates the datagram for the packet, delivered as the packet type demands. The current time
+	/// is in nanoseconds and is used to decide when the packet must be sent again.
+	///
+	/// If too many reliable messages of the same delivery are waiting for their acknowledgement,
+	/// the packet is queued and None is returned. Its datagram is returned by resend() once it can
+	/// be sent.
+	pub fn send<P: Packet>(&mut self, packet: &P, now: u64) -> Option<Vec<u8>> {
+		self.send_frame(&Frame::new(packet), P::delivery(), now)
+	}
+
+	/// # Send a frame
+	///
+	/// Like send(), but for a frame that has already been created.
+	pub fn send_frame(&mut self, frame: &Frame, delivery: Delivery, now: u64) -> Option<Vec<u8>> {
+		let queued = self.queued.get(&delivery).map_or(false, |queued| !queued.is_empty());
+		if delivery != Delivery::Unreliable && (queued || self.in_flight(delivery) >= SEQUENCE_WINDOW) {
+			self.queued.entry(delivery).or_insert_with(VecDeque::new).push_back(Frame {
+				id: frame.id,
+				data: frame.data.clone()
+			});
+
+			return None;
+		}
+
+		Some(self.transmit(frame, delivery, now))
+	}
+
+	/// Numbers the message and creates its datagram. Reliable messages are kept until they have
+	/// been acknowledged.
+	fn transmit(&mut self, frame: &Frame, delivery: Delivery, now: u64) -> Vec<u8> {
+		let sequence = {
+			let next = self.next_sequence.entry(delivery).or_insert(0);
+			let sequence = *next;
+			*next = next.wrapping_add(1);
+
+			sequence
+		};
+
+		let mut datagram = vec![MESSAGE, delivery.to_u8()];
+		datagram.write_u16::<BigEndian>(sequence).unwrap();
+		datagram.extend(frame.to_bytes());
+
+		if delivery != Delivery::Unreliable {
+			self.pending.insert((delivery, sequence), Pending {
+				datagram: datagram.clone(),
+				last_sent: now,
+				resends: 0
+			});
+		}
+
+		self.seal(datagram)
+	}
+
+	/// How far the next sequence number of the delivery is ahead of the oldest reliable message
+	/// that still waits for its acknowledgement. The other side is at most that far behind.
+	fn in_flight(&self, delivery: Delivery) -> u16 {
+		let next = *self.next_sequence.get(&delivery).unwrap_or(&0);
+
+		self.pending.keys()
+			.filter(|&&(pending, _)| pending == delivery)
+			.map(|&(_, sequence)| next.wrapping_sub(sequence))
+			.max()
+			.unwrap_or(0)
+	}
+
+	/// # Receive a datagram
+	///
+	/// Reads a datagram that has been created by the other channel. Acknowledgements are consumed
+	/// by the channel, messages are returned as soon as their delivery allows it.
+	///
+	/// # Failure
+	/// If the datagram is malformed, an error is returned. Nothing is acknowledged in that case.
+	/// In an authenticated session, this includes datagrams that have been forged or replayed.
+	pub fn receive(&mut self, data: &[u8]) -> Result<Received, PacketError> {
+		let opened = match self.auth {
+			Some(ref mut auth) => try!(auth.open(data)),
+			None => data.to_vec()
+		};
+		let data = &opened[..];
+
+		if data.is_empty() {
+			return Ok(Received {
+				frames: Vec::new(),
+				ack: None
+			});
+		}
+
+		let mut cursor = Cursor::new(data);
+
+		let kind = try!(cursor.read_u8());
+		let delivery = try!(Delivery::from_u8(try!(cursor.read_u8())));
+		let sequence = try!(cursor.read_u16::<BigEndian>());
+
+		match kind {
+			ACK => {
+				try!(expect_end(&cursor));
+				self.pending.remove(&(delivery, sequence));
+
+				Ok(Received {
+					frames: Vec::new(),
+					ack: None
+				})
+			},
+			MESSAGE => {
+				let frame = try!(Frame::from_bytes(&data[cursor.position() as usize..]));
+
+				let frames = match delivery {
+					Delivery::Unreliable => Some(vec![frame]),
+					Delivery::ReliableUnordered => self.receive_unordered(sequence, frame),
+					Delivery::ReliableOrdered => self.receive_ordered(sequence, frame)
+				};
+
+				// Messages that could not be taken yet are not acknowledged, so they will be sent
+				// again later. Neither are stray copies of messages that are too old to tell.
+				match frames {
+					Some(frames) => Ok(Received {
+						frames: frames,
+						ack: if delivery == Delivery::Unreliable { None } else { Some(self.seal(ack(delivery, sequence))) }
+					}),
+					None => Ok(Received {
+						frames: Vec::new(),
+						ack: None
+					})
+				}
+			},
+			kind => Err(PacketError::InvalidValue { field: "channel datagram kind", value: kind as u64 })
+		}
+	}
+
+	fn receive_unordered(&mut self, sequence: u16, frame: Frame) -> Option<Vec<Frame>> {
+		// Whether anything older than the window has been received before cannot be told any
+		// longer. The other side never has messages that far apart on their way, so it can only
+		// be a stray copy of a message that has already been acknowledged. It is neither handled
+		// nor acknowledged again.
+		if let Some(newest) = self.newest_unordered {
+			if !sequence_greater_than(sequence, newest) && newest.wrapping_sub(sequence) >= SEQUENCE_WINDOW {
+				return None;
+			}
+		}
+
+		if !self.received_unordered.insert(sequence) {
+			return Some(Vec::new());
+		}
+
+		let newest = match self.newest_unordered {
+			Some(newest) if !sequence_greater_than(sequence, newest) => newest,
+			_ => sequence
+		};
+		self.newest_unordered = Some(newest);
+
+		if self.received_unordered.len() > 2 * SEQUENCE_WINDOW as usize {
+			self.received_unordered.retain(|&received| newest.wrapping_sub(received) < SEQUENCE_WINDOW);
+		}
+
+		Some(vec![frame])
+	}
+
+	fn receive_ordered(&mut self, sequence: u16, frame: Frame) -> Option<Vec<Frame>> {
+		// Messages that have been handled before only have to be acknowledged again.
+		if sequence != self.next_ordered && !sequence_greater_than(sequence, self.next_ordered) {
+			return Some(Vec::new());
+		}
+
+		// Messages too far ahead are not buffered, so the buffer cannot grow without limit.
+		if sequence.wrapping_sub(self.next_ordered) >= SEQUENCE_WINDOW {
+			return None;
+		}
+
+		self.ordered_buffer.insert(sequence, frame);
+
+		let mut frames = Vec::new();
+		while let Some(frame) = self.ordered_buffer.remove(&self.next_ordered) {
+			frames.push(frame);
+			self.next_ordered = self.next_ordered.wrapping_add(1);
+		}
+
+		Some(frames)
+	}
+
+	/// # Resend overdue messages
+	///
+	/// Returns the datagrams of all reliable messages that have not been acknowledged in time, and
+	/// of the queued messages that can be sent by now. They must be sent to the other channel.
+	/// Every time a message is sent again, the time to wait for its acknowledgement doubles, up to
+	/// the maximum. Once a message has been sent again too often, the channel fails and nothing
+	/// is sent any longer.
+	pub fn resend(&mut self, now: u64) -> Vec<Vec<u8>> {
+		if self.failed {
+			return Vec::new();
+		}
+
+		let mut datagrams = Vec::new();
+		let max_timeout = cmp::max(self.resend_timeout, MAX_RESEND_TIMEOUT_MS * 1000000);
+
+		for (_, pending) in self.pending.iter_mut() {
+			let timeout = cmp::min(self.resend_timeout << cmp::min(pending.resends, 16), max_timeout);
+			if now.saturating_sub(pending.last_sent) < timeout {
+				continue;
+			}
+
+			if pending.resends == MAX_RESENDS {
+				self.failed = true;
+				return Vec::new();
+			}
+
+			datagrams.push(pending.datagram.clone());
+			pending.last_sent = now;
+			pending.resends += 1;
+		}
+
+		// Every copy is sealed on its own, since the other side rejects a counter it has seen
+		// before.
+		let mut datagrams: Vec<Vec<u8>> = datagrams.into_iter().map(|datagram| self.seal(datagram)).collect();
+
+		for delivery in [Delivery::ReliableUnordered, Delivery::ReliableOrdered].iter() {
+			while self.in_flight(*delivery) < SEQUENCE_WINDOW {
+				let frame = match self.queued.get_mut(delivery).and_then(|queued| queued.pop_front()) {
+					Some(frame) => frame,
+					None => break
+				};
+
+				datagrams.push(self.transmit(&frame, *delivery, now));
+			}
+		}
+
+		datagrams
+	}
+
+	/// # Check for failure
+	///
+	/// Returns whether a reliable message has been sent again too often without being
+	/// acknowledged. The other side cannot be reached any longer, or does not answer.
+	pub fn is_failed(&self) -> bool {
+		self.failed
+	}
+
+	/// # Set the resend timeout
+	///
+	/// Sets the time in milliseconds after which unacknowledged messages are sent again.
+	pub fn set_resend_timeout(&mut self, timeout_ms: u64) {
+		self.resend_timeout = timeout_ms * 1000000;
+	}
+
+	/// Seals the datagram if the session is authenticated, and returns it as is otherwise.
+	fn seal(&mut self, datagram: Vec<u8>) -> Vec<u8> {
+		match self.auth {
+			Some(ref mut auth) => auth.seal(&datagram),
+			None => datagram
+		}
+	}
+
+	/// # Count unacknowledged messages
+	///
+	/// Returns the amount of reliable messages that have been sent, but not yet acknowledged.
+	pub fn unacknowledged(&self) -> usize {
+		self.pending.len()
+	}
+
+	/// # Count queued messages
+	///
+	/// Returns the amount of reliable messages that wait for others to be acknowledged, before
+	/// they are sent.
+	pub fn queued(&self) -> usize {
+		self.queued.values().map(|queued| queued.len()).sum()
+	}
+}
+
+fn ack(delivery: Delivery, sequence: u16) -> Vec<u8> {
+	let mut datagram = vec![ACK, delivery.to_u8()];
+	datagram.write_u16::<BigEndian>(sequence).unwrap();
+
+	datagram
+}
+
+/// Compares sequence numbers, taking into account that they wrap around.
+fn sequence_greater_than(a: u16, b: u16) -> bool {
+	a != b && a.wrapping_sub(b) < 32768
+}
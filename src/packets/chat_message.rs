@@ -0,0 +1,68 @@
+//! # Chat message
+//!
+//! A line of chat the server relays to a client, along with who wrote it and where. Messages of
+//! the server itself, like the answer to a whisper nobody could receive, arrive the same way.
+
+use packets::{Field, PacketError, ReadFieldsExt, WriteFieldsExt};
+
+use std::io::Cursor;
+use packets::byteorder::{self, ReadBytesExt, WriteBytesExt};
+
+/// The longest text a single chat message may have, in characters. The server refuses anything
+/// longer.
+pub const MAX_CHAT_LENGTH: usize = 200;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ChatChannel {
+	/// Everyone on the server.
+	Global,
+
+	/// Every member of the group with the name.
+	Group(String),
+
+	/// Only the users with the name, and the sender.
+	Whisper(String),
+
+	/// The server talking to a single client. Clients cannot send into this channel.
+	System
+}
+
+impl Field for ChatChannel {
+	fn read_field(data: &mut Cursor<&[u8]>) -> Result<ChatChannel, PacketError> {
+		match try!(data.read_u8()) {
+			0 => Ok(ChatChannel::Global),
+			1 => Ok(ChatChannel::Group(try!(data.read_string()))),
+			2 => Ok(ChatChannel::Whisper(try!(data.read_string()))),
+			3 => Ok(ChatChannel::System),
+			value => Err(PacketError::InvalidValue { field: "chat channel", value: value as u64 })
+		}
+	}
+
+	/// Writes the kind of the channel, followed by the name of the group or the recipient, if any.
+	fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+		match self {
+			&ChatChannel::Global => data.write_u8(0),
+			&ChatChannel::Group(ref group) => {
+				try!(data.write_u8(1));
+				data.write_string(group)
+			},
+			&ChatChannel::Whisper(ref recipient) => {
+				try!(data.write_u8(2));
+				data.write_string(recipient)
+			},
+			&ChatChannel::System => data.write_u8(3)
+		}
+	}
+}
+
+#[derive(Packet)]
+#[packet(id = 16)]
+pub struct ChatMessage {
+	/// The name of the user who wrote the message. Empty for messages of the server.
+	pub sender: String,
+
+	/// Where the message has been sent to.
+	pub channel: ChatChannel,
+
+	pub text: String
+}
@@ -0,0 +1,11 @@
+//! # Control an entity
+//!
+//! Tells the client which entity it controls. The inputs of the player are applied to this
+//! entity by the server.
+
+#[derive(Packet)]
+#[packet(id = 10, delivery = ReliableOrdered)]
+pub struct ControlEntity {
+	/// The id of the entity the client controls from now on.
+	pub id: u32
+}
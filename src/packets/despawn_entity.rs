@@ -0,0 +1,12 @@
+//! # Despawn an entity
+//!
+//! Removes the entity with the given id from the game world. Afterwards, the id may be used for
+//! a different entity.
+
+// Despawns must stay in order with spawns, otherwise a reused id could remove the wrong entity.
+#[derive(Packet)]
+#[packet(id = 8, delivery = ReliableOrdered)]
+pub struct DespawnEntity {
+	/// The id of the entity that has to be removed.
+	pub id: u32
+}
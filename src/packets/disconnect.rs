@@ -0,0 +1,55 @@
+//! # Disconnect a client
+//!
+//! Sent by the server before it closes the connection to a client on purpose, so that the client
+//! can tell the player why, instead of just losing the connection.
+
+use packets::{Field, PacketError};
+
+use std::io::Cursor;
+use packets::byteorder::{self, ReadBytesExt, WriteBytesExt};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DisconnectReason {
+	/// The server is shutting down and disconnects everyone.
+	ServerShutdown,
+
+	/// The server decided to remove this client in particular.
+	Kicked,
+
+	/// The client kept sending more than the server allows.
+	Flooding,
+
+	/// The client did not acknowledge datagrams, however often they have been sent.
+	Unreachable
+}
+
+impl Field for DisconnectReason {
+	fn read_field(data: &mut Cursor<&[u8]>) -> Result<DisconnectReason, PacketError> {
+		match try!(data.read_u8()) {
+			0 => Ok(DisconnectReason::ServerShutdown),
+			1 => Ok(DisconnectReason::Kicked),
+			2 => Ok(DisconnectReason::Flooding),
+			3 => Ok(DisconnectReason::Unreachable),
+			value => Err(PacketError::InvalidValue { field: "disconnect reason", value: value as u64 })
+		}
+	}
+
+	fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+		data.write_u8(match self {
+			&DisconnectReason::ServerShutdown => 0,
+			&DisconnectReason::Kicked => 1,
+			&DisconnectReason::Flooding => 2,
+			&DisconnectReason::Unreachable => 3
+		})
+	}
+}
+
+#[derive(Packet)]
+#[packet(id = 14)]
+pub struct Disconnect {
+	/// Why the client is being disconnected.
+	pub reason: DisconnectReason,
+
+	/// A human readable explanation, which the client may show to the player.
+	pub message: String
+}
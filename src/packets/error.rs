@@ -0,0 +1,92 @@
+//! # Packet errors
+//!
+//! Everything that can go wrong while a packet is being decoded. Since the data comes from the
+//! network, none of these are programming errors and must never crash the endpoint.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use packets::byteorder;
+
+#[derive(Debug)]
+pub enum PacketError {
+	/// The data ended before the packet or frame was complete.
+	Truncated,
+
+	/// The frame contained an id that no packet has been registered for.
+	UnknownId(u8),
+
+	/// The length prefix of a frame was outside of the allowed range.
+	InvalidLength(u32),
+
+	/// A field contained a value that is not allowed for it, like an unknown enum variant.
+	InvalidValue {
+		field: &'static str,
+		value: u64
+	},
+
+	/// A string field did not contain valid UTF-8.
+	InvalidString,
+
+	/// The packet was complete, but there were still bytes left in the frame.
+	TrailingBytes(usize),
+
+	/// The datagram has not been signed with the key of the session.
+	Forged,
+
+	/// The datagram has been signed correctly, but has been received before.
+	Replayed,
+
+	/// The underlying stream or socket failed.
+	Io(io::Error)
+}
+
+impl Display for PacketError {
+	fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+		match self {
+			&PacketError::Truncated => write!(formatter, "The packet has been cut off."),
+			&PacketError::UnknownId(id) => write!(formatter, "Packet id {} has not been registered.", id),
+			&PacketError::InvalidLength(length) => write!(formatter, "Invalid frame length {}.", length),
+			&PacketError::InvalidValue { field, value } => write!(formatter, "Invalid value {} for {}.", value, field),
+			&PacketError::InvalidString => write!(formatter, "String is not valid UTF-8."),
+			&PacketError::TrailingBytes(count) => write!(formatter, "{} bytes left after the packet.", count),
+			&PacketError::Forged => write!(formatter, "The datagram has not been signed with the key of the session."),
+			&PacketError::Replayed => write!(formatter, "The datagram has been received before."),
+			&PacketError::Io(ref err) => write!(formatter, "{}", err)
+		}
+	}
+}
+
+impl Error for PacketError {
+	fn description(&self) -> &str {
+		match self {
+			&PacketError::Truncated => "packet truncated",
+			&PacketError::UnknownId(_) => "unknown packet id",
+			&PacketError::InvalidLength(_) => "invalid frame length",
+			&PacketError::InvalidValue { .. } => "invalid field value",
+			&PacketError::InvalidString => "invalid string",
+			&PacketError::TrailingBytes(_) => "trailing bytes",
+			&PacketError::Forged => "forged datagram",
+			&PacketError::Replayed => "replayed datagram",
+			&PacketError::Io(ref err) => err.description()
+		}
+	}
+}
+
+impl From<io::Error> for PacketError {
+	fn from(err: io::Error) -> PacketError {
+		match err.kind() {
+			io::ErrorKind::UnexpectedEof => PacketError::Truncated,
+			_ => PacketError::Io(err)
+		}
+	}
+}
+
+impl From<byteorder::Error> for PacketError {
+	fn from(err: byteorder::Error) -> PacketError {
+		match err {
+			byteorder::Error::UnexpectedEOF => PacketError::Truncated,
+			byteorder::Error::Io(err) => PacketError::from(err)
+		}
+	}
+}
@@ -0,0 +1,284 @@
+//! # Variable length fields
+//!
+//! Extensions to the byteorder traits, so that packets can contain strings, lists and optional
+//! fields. Strings and lists are prefixed with their length as a big endian u16, optional fields
+//! with a single byte that is 1 if the value is present and 0 otherwise.
+//!
+//! Everything a packet can contain implements the Field trait, which derived packets are encoded
+//! with. Numbers are big endian, and booleans a single byte that is either 0 or 1.
+
+use packets::PacketError;
+use sys::Vector;
+
+use std::io::{self, Cursor, Read};
+use std::ops::{Add, Div, Mul, Sub};
+use std::u16;
+use packets::byteorder::{self, BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// # A field of a packet
+///
+/// Something that can be written into a packet and read back from it. Reading must accept exactly
+/// what writing produces, so the two have to be implemented symmetrically.
+pub trait Field: Sized {
+	/// # Read the field
+	///
+	/// # Failure
+	/// If the data is cut off or not a valid value, an error is returned.
+	fn read_field(data: &mut Cursor<&[u8]>) -> Result<Self, PacketError>;
+
+	/// # Write the field
+	///
+	/// # Failure
+	/// Fields that are too long to be written, like strings longer than 65535 bytes, return an
+	/// error.
+	fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()>;
+}
+
+macro_rules! byte_field {
+	($kind:ty, $read:ident, $write:ident) => {
+		impl Field for $kind {
+			fn read_field(data: &mut Cursor<&[u8]>) -> Result<$kind, PacketError> {
+				Ok(try!(data.$read()))
+			}
+
+			fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+				data.$write(*self)
+			}
+		}
+	}
+}
+
+macro_rules! number_field {
+	($kind:ty, $read:ident, $write:ident) => {
+		impl Field for $kind {
+			fn read_field(data: &mut Cursor<&[u8]>) -> Result<$kind, PacketError> {
+				Ok(try!(data.$read::<BigEndian>()))
+			}
+
+			fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+				data.$write::<BigEndian>(*self)
+			}
+		}
+	}
+}
+
+byte_field!(u8, read_u8, write_u8);
+byte_field!(i8, read_i8, write_i8);
+number_field!(u16, read_u16, write_u16);
+number_field!(i16, read_i16, write_i16);
+number_field!(u32, read_u32, write_u32);
+number_field!(i32, read_i32, write_i32);
+number_field!(u64, read_u64, write_u64);
+number_field!(i64, read_i64, write_i64);
+number_field!(f32, read_f32, write_f32);
+number_field!(f64, read_f64, write_f64);
+
+impl Field for bool {
+	fn read_field(data: &mut Cursor<&[u8]>) -> Result<bool, PacketError> {
+		match try!(data.read_u8()) {
+			0 => Ok(false),
+			1 => Ok(true),
+			value => Err(PacketError::InvalidValue { field: "boolean", value: value as u64 })
+		}
+	}
+
+	fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+		data.write_u8(if *self { 1 } else { 0 })
+	}
+}
+
+impl Field for String {
+	fn read_field(data: &mut Cursor<&[u8]>) -> Result<String, PacketError> {
+		data.read_string()
+	}
+
+	fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+		data.write_string(self)
+	}
+}
+
+impl<T: Field + PartialEq + Add + Sub + Mul + Div> Field for Vector<T> {
+	fn read_field(data: &mut Cursor<&[u8]>) -> Result<Vector<T>, PacketError> {
+		Ok(Vector {
+			x: try!(T::read_field(data)),
+			y: try!(T::read_field(data))
+		})
+	}
+
+	fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+		try!(self.x.write_field(data));
+		self.y.write_field(data)
+	}
+}
+
+impl<T: Field> Field for Vec<T> {
+	fn read_field(data: &mut Cursor<&[u8]>) -> Result<Vec<T>, PacketError> {
+		data.read_list(|data| T::read_field(data))
+	}
+
+	fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+		data.write_list(self, |data, item| item.write_field(data))
+	}
+}
+
+impl<T: Field> Field for Option<T> {
+	fn read_field(data: &mut Cursor<&[u8]>) -> Result<Option<T>, PacketError> {
+		data.read_option(|data| T::read_field(data))
+	}
+
+	fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+		data.write_option(self, |data, item| item.write_field(data))
+	}
+}
+
+// Byte arrays have a fixed size, so they are written without a length.
+macro_rules! array_field {
+	($($size:expr),*) => {
+		$(
+			impl Field for [u8; $size] {
+				fn read_field(data: &mut Cursor<&[u8]>) -> Result<[u8; $size], PacketError> {
+					let mut bytes = [0; $size];
+					try!(data.read_exact(&mut bytes));
+
+					Ok(bytes)
+				}
+
+				fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+					data.extend(self.iter().cloned());
+					Ok(())
+				}
+			}
+		)*
+	}
+}
+
+array_field!(16, 32);
+
+pub trait WriteFieldsExt: WriteBytesExt {
+	/// # Write a string
+	///
+	/// Writes the string as UTF-8. Strings longer than 65535 bytes cannot be written.
+	fn write_string(&mut self, string: &str) -> byteorder::Result<()> {
+		try!(write_length(self, string.len()));
+		try!(self.write_all(string.as_bytes()));
+
+		Ok(())
+	}
+
+	/// # Write a vector
+	///
+	/// Writes the x and then the y coordinate of the vector.
+	fn write_vector(&mut self, vector: &Vector<f32>) -> byteorder::Result<()> {
+		try!(self.write_f32::<BigEndian>(vector.x));
+		self.write_f32::<BigEndian>(vector.y)
+	}
+
+	/// # Write a list
+	///
+	/// Writes the amount of items, followed by every item written with the function provided.
+	fn write_list<T, F>(&mut self, items: &[T], mut write_item: F) -> byteorder::Result<()>
+		where F: FnMut(&mut Self, &T) -> byteorder::Result<()> {
+		try!(write_length(self, items.len()));
+		for item in items {
+			try!(write_item(self, item));
+		}
+
+		Ok(())
+	}
+
+	/// # Write an optional value
+	///
+	/// Writes whether the value exists, and if so the value itself with the function provided.
+	fn write_option<T, F>(&mut self, item: &Option<T>, mut write_item: F) -> byteorder::Result<()>
+		where F: FnMut(&mut Self, &T) -> byteorder::Result<()> {
+		match item {
+			&Some(ref item) => {
+				try!(self.write_u8(1));
+				write_item(self, item)
+			},
+			&None => self.write_u8(0)
+		}
+	}
+}
+
+impl<W: WriteBytesExt + ?Sized> WriteFieldsExt for W {}
+
+pub trait ReadFieldsExt: ReadBytesExt {
+	/// # Read a string
+	///
+	/// Reads a string that has been written with write_string().
+	fn read_string(&mut self) -> Result<String, PacketError> {
+		let length = try!(self.read_u16::<BigEndian>());
+
+		let mut bytes = vec![0; length as usize];
+		try!(self.read_exact(&mut bytes));
+
+		match String::from_utf8(bytes) {
+			Ok(string) => Ok(string),
+			Err(_) => Err(PacketError::InvalidString)
+		}
+	}
+
+	/// # Read a vector
+	///
+	/// Reads a vector that has been written with write_vector().
+	fn read_vector(&mut self) -> Result<Vector<f32>, PacketError> {
+		let x = try!(self.read_f32::<BigEndian>());
+		let y = try!(self.read_f32::<BigEndian>());
+
+		Ok(Vector::new(x, y))
+	}
+
+	/// # Read a list
+	///
+	/// Reads a list that has been written with write_list(), reading every item with the function
+	/// provided.
+	fn read_list<T, F>(&mut self, mut read_item: F) -> Result<Vec<T>, PacketError>
+		where F: FnMut(&mut Self) -> Result<T, PacketError> {
+		let length = try!(self.read_u16::<BigEndian>());
+
+		let mut items = Vec::with_capacity(length as usize);
+		for _ in 0..length {
+			items.push(try!(read_item(self)));
+		}
+
+		Ok(items)
+	}
+
+	/// # Read an optional value
+	///
+	/// Reads an optional value that has been written with write_option().
+	fn read_option<T, F>(&mut self, mut read_item: F) -> Result<Option<T>, PacketError>
+		where F: FnMut(&mut Self) -> Result<T, PacketError> {
+		match try!(self.read_u8()) {
+			0 => Ok(None),
+			1 => Ok(Some(try!(read_item(self)))),
+			flag => Err(PacketError::InvalidValue { field: "option flag", value: flag as u64 })
+		}
+	}
+}
+
+impl<R: ReadBytesExt + ?Sized> ReadFieldsExt for R {}
+
+/// # Check for the end of a packet
+///
+/// Packets are read from a cursor over the frame's data. After the last field has been read, this
+/// makes sure that nothing is left over, since that would mean the packet was not what it seemed.
+pub fn expect_end(data: &Cursor<&[u8]>) -> Result<(), PacketError> {
+	let remaining = data.get_ref().len() as u64 - data.position();
+
+	if remaining == 0 {
+		Ok(())
+	}
+	else {
+		Err(PacketError::TrailingBytes(remaining as usize))
+	}
+}
+
+fn write_length<W: WriteBytesExt + ?Sized>(writer: &mut W, length: usize) -> byteorder::Result<()> {
+	if length > u16::MAX as usize {
+		return Err(byteorder::Error::Io(io::Error::new(io::ErrorKind::InvalidInput, format!("Length {} does not fit into a field.", length))));
+	}
+
+	writer.write_u16::<BigEndian>(length as u16)
+}
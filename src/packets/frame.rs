@@ -0,0 +1,178 @@
+//! # Packet framing
+//!
+//! Packets do not have a constant size, so every packet is wrapped into a frame before it is put on
+//! the wire. A frame starts with the length of its content as a big endian u32, followed by the id
+//! of the packet and the packet's data. The length includes the id byte.
+
+use packets::{Packet, PacketError};
+
+use std::io::{Read, Write};
+use std::io;
+use packets::byteorder::{BigEndian, ByteOrder, ReadBytesExt, WriteBytesExt};
+
+/// The size of the length prefix in front of every frame.
+pub const LENGTH_SIZE: usize = 4;
+
+/// The size of the session token every datagram from a client starts with. The channel datagram
+/// follows directly after it.
+pub const TOKEN_SIZE: usize = 8;
+
+/// The largest frame content that is accepted. Anything larger than this is considered to be
+/// garbage, so that a broken length prefix cannot make the endpoint allocate gigabytes.
+pub const MAX_FRAME_SIZE: u32 = 65536;
+
+pub struct Frame {
+	/// The id of the packet that is contained in this frame.
+	pub id: u8,
+
+	/// The raw data of the packet, without id and length prefix.
+	pub data: Vec<u8>
+}
+
+impl Frame {
+	/// # Frame a packet
+	///
+	/// Creates a frame that contains the provided packet.
+	pub fn new<P: Packet>(packet: &P) -> Frame {
+		Frame {
+			id: P::id(),
+			data: packet.to_bytes()
+		}
+	}
+
+	/// # Decode the packet
+	///
+	/// Decodes the contained packet as a packet of type P. If the frame contains a different kind
+	/// of packet, UnknownId is returned.
+	pub fn decode<P: Packet>(&self) -> Result<P, PacketError> {
+		if self.id != P::id() {
+			return Err(PacketError::UnknownId(self.id));
+		}
+
+		P::from_bytes(&self.data)
+	}
+
+	/// # Write to bytes
+	///
+	/// Creates the byte representation of the frame, including the length prefix, so it can be
+	/// sent as is.
+	pub fn to_bytes(&self) -> Vec<u8> {
+		let mut bytes = Vec::with_capacity(LENGTH_SIZE + 1 + self.data.len());
+
+		bytes.write_u32::<BigEndian>(self.data.len() as u32 + 1).unwrap();
+		bytes.write_u8(self.id).unwrap();
+		bytes.extend(self.data.iter().cloned());
+
+		bytes
+	}
+
+	/// # Create from bytes
+	///
+	/// Reads exactly one frame from the slice, as it is the case with a UDP datagram. The length
+	/// prefix must match the amount of data in the slice.
+	pub fn from_bytes(data: &[u8]) -> Result<Frame, PacketError> {
+		if data.len() < LENGTH_SIZE + 1 {
+			return Err(PacketError::Truncated);
+		}
+
+		let length = BigEndian::read_u32(&data[..LENGTH_SIZE]);
+		if length == 0 || length > MAX_FRAME_SIZE {
+			return Err(PacketError::InvalidLength(length));
+		}
+		else if (length as usize) > data.len() - LENGTH_SIZE {
+			return Err(PacketError::Truncated);
+		}
+		else if (length as usize) < data.len() - LENGTH_SIZE {
+			return Err(PacketError::TrailingBytes(data.len() - LENGTH_SIZE - length as usize));
+		}
+
+		Ok(Frame {
+			id: data[LENGTH_SIZE],
+			data: data[LENGTH_SIZE + 1..].to_vec()
+		})
+	}
+
+	/// # Read from a stream
+	///
+	/// Blocks until a complete frame has been read from the stream. In case the stream is closed
+	/// before the frame is complete, Truncated is returned.
+	pub fn read_from<R: Read>(reader: &mut R) -> Result<Frame, PacketError> {
+		let length = try!(reader.read_u32::<BigEndian>());
+		if length == 0 || length > MAX_FRAME_SIZE {
+			return Err(PacketError::InvalidLength(length));
+		}
+
+		let id = try!(reader.read_u8());
+
+		let mut data = vec![0; length as usize - 1];
+		try!(reader.read_exact(&mut data));
+
+		Ok(Frame {
+			id: id,
+			data: data
+		})
+	}
+
+	/// # Take from a buffer
+	///
+	/// Removes the first frame from the buffer of bytes that have been received from a stream so
+	/// far. If the frame is not complete yet, None is returned and the buffer stays unchanged, so
+	/// it can be tried again once more bytes have arrived.
+	pub fn take_from(buffer: &mut Vec<u8>) -> Result<Option<Frame>, PacketError> {
+		if buffer.len() < LENGTH_SIZE {
+			return Ok(None);
+		}
+
+		let length = BigEndian::read_u32(&buffer[..LENGTH_SIZE]);
+		if length == 0 || length > MAX_FRAME_SIZE {
+			return Err(PacketError::InvalidLength(length));
+		}
+		else if buffer.len() < LENGTH_SIZE + length as usize {
+			return Ok(None);
+		}
+
+		let rest = buffer.split_off(LENGTH_SIZE + length as usize);
+		let frame = Frame {
+			id: buffer[LENGTH_SIZE],
+			data: buffer[LENGTH_SIZE + 1..].to_vec()
+		};
+		*buffer = rest;
+
+		Ok(Some(frame))
+	}
+
+	/// # Write to a stream
+	///
+	/// Writes the entire frame to the stream and returns the amount of bytes that have been
+	/// written.
+	pub fn write_to<W: Write>(&self, writer: &mut W) -> io::Result<usize> {
+		let bytes = self.to_bytes();
+		try!(writer.write_all(&bytes));
+
+		Ok(bytes.len())
+	}
+}
+
+/// # Write the session token
+///
+/// Creates the prefix of a datagram sent by a client, which the channel datagram is appended to.
+/// Sent on its own, it tells the server which address the client can be reached at.
+pub fn token_to_bytes(token: u64) -> Vec<u8> {
+	let mut bytes = Vec::with_capacity(TOKEN_SIZE);
+	bytes.write_u64::<BigEndian>(token).unwrap();
+
+	bytes
+}
+
+/// # Read the session token
+///
+/// Reads the session token a datagram sent by a client starts with. If the datagram is too short
+/// to even contain the token, None is returned.
+pub fn read_token(datagram: &[u8]) -> Option<u64> {
+	if datagram.len() < TOKEN_SIZE {
+		None
+	}
+	else {
+		Some(BigEndian::read_u64(&datagram[..TOKEN_SIZE]))
+	}
+}
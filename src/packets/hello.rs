@@ -0,0 +1,35 @@
+//! # Say hello to the server
+//!
+//! The first packet a client sends after connecting. The server only accepts the connection if
+//! the client speaks the same protocol version.
+
+use packets::PacketError;
+
+use std::io::Cursor;
+use packets::byteorder::{BigEndian, ReadBytesExt};
+
+/// The longest name a client may introduce itself with, in characters. The server refuses anything
+/// longer.
+pub const MAX_NAME_LENGTH: usize = 24;
+
+#[derive(Packet)]
+#[packet(id = 1)]
+pub struct Hello {
+	/// The protocol version the client has been built with. It must always stay the first field,
+	/// so that it can be read by peek_version() regardless of the version.
+	pub version: u16,
+
+	/// The name the client would like to be known by. It must not be taken by anyone else, and
+	/// may not contain any whitespace, so that others can whisper to it.
+	pub name: String
+}
+
+impl Hello {
+	/// # Read only the version
+	///
+	/// Reads the protocol version from the data of a hello, without decoding the rest. The rest of
+	/// the packet may look different in other versions, but the version is always found.
+	pub fn peek_version(data: &[u8]) -> Result<u16, PacketError> {
+		Ok(try!(Cursor::new(data).read_u16::<BigEndian>()))
+	}
+}
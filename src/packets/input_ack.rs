@@ -0,0 +1,19 @@
+//! # Acknowledge inputs
+//!
+//! Tells the client up to which input the server has moved the entity it controls, and where the
+//! entity ended up. The client replays all inputs the server has not applied yet from there on.
+//! Since the next acknowledgement replaces this one anyway, it does not matter if one gets lost.
+//! They may arrive out of order, though, so the client ignores any that is older than the newest
+//! one it has seen.
+
+use sys::Vector;
+
+#[derive(Packet)]
+#[packet(id = 11)]
+pub struct InputAck {
+	/// The sequence number of the last input that has been applied.
+	pub sequence: u32,
+
+	/// The position of the controlled entity after the input has been applied.
+	pub pos: Vector<f32>
+}
@@ -0,0 +1,11 @@
+//! # Join a chat group
+//!
+//! Asks the server to add the client to a group, so that it reads and may write what is said in
+//! there. Groups do not have to exist beforehand.
+
+#[derive(Packet)]
+#[packet(id = 17)]
+pub struct JoinGroup {
+	/// The name of the group.
+	pub group: String
+}
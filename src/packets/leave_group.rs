@@ -0,0 +1,10 @@
+//! # Leave a chat group
+//!
+//! Asks the server to remove the client from a group it has joined before.
+
+#[derive(Packet)]
+#[packet(id = 18)]
+pub struct LeaveGroup {
+	/// The name of the group.
+	pub group: String
+}
@@ -3,46 +3,142 @@
 //! These packets are net entities that are used to send data from the one endpoint to another.
 //! A packet is defined by a single byte in the stream and then may be read accordingly. They
 //! resemble commands, and may be understood differently depending on the type of endpoint.
+//! Since packets vary in size, they are always sent inside of a length-prefixed frame.
+//!
+//! Most packets derive their encoding with `#[derive(Packet)]`, which writes the fields in the
+//! order they are declared. Only packets that pack their fields more tightly than that implement
+//! the Packet trait by hand.
 
 extern crate byteorder;
+extern crate hmac;
+extern crate sha2;
+
+pub mod auth;
+pub use self::auth::{RejectedDatagrams, SessionAuth, SharedKey, Side};
+
+pub mod authenticate;
+pub use self::authenticate::Authenticate;
+
+pub mod challenge;
+pub use self::challenge::Challenge;
+
+pub mod change_health;
+pub use self::change_health::{ChangeHealth, HealthChange};
+
+pub mod channel;
+pub use self::channel::{Channel, Delivery};
+
+pub mod chat_message;
+pub use self::chat_message::{ChatChannel, ChatMessage, MAX_CHAT_LENGTH};
+
+pub mod control_entity;
+pub use self::control_entity::ControlEntity;
+
+pub mod despawn_entity;
+pub use self::despawn_entity::DespawnEntity;
+
+pub mod disconnect;
+pub use self::disconnect::{Disconnect, DisconnectReason};
+
+pub mod error;
+pub use self::error::PacketError;
+
+pub mod fields;
+pub use self::fields::{Field, ReadFieldsExt, WriteFieldsExt, expect_end};
+
+pub mod frame;
+pub use self::frame::Frame;
+
+pub mod hello;
+pub use self::hello::{Hello, MAX_NAME_LENGTH};
+
+pub mod input_ack;
+pub use self::input_ack::InputAck;
+
+pub mod join_group;
+pub use self::join_group::JoinGroup;
+
+pub mod leave_group;
+pub use self::leave_group::LeaveGroup;
+
+pub mod move_entity;
+pub use self::move_entity::MoveEntity;
+
+pub mod ping;
+pub use self::ping::Ping;
+
+pub mod pong;
+pub use self::pong::Pong;
+
+pub mod player_input;
+pub use self::player_input::PlayerInput;
+
+pub mod registry;
+pub use self::registry::PacketRegistry;
+
+pub mod reject;
+pub use self::reject::{Reject, RejectReason};
+
+pub mod send_chat;
+pub use self::send_chat::SendChat;
+
+pub mod snapshot_ack;
+pub use self::snapshot_ack::SnapshotAck;
 
 pub mod spawn_entity;
 pub use self::spawn_entity::SpawnEntity;
 
-impl Packet {
-	/// # Get a packet's size
-	///
-	/// This function will check which packet the given id refers to and provide its size, so that
-	/// it can be read from the stream and then constructed.
-	pub fn get_matching_size(id: u8) -> u32 {
-		match id {
-			0 => SpawnEntity::SIZE,
-			_ => panic!("Incorrect packet id.")
-		}
-	}
-}
+pub mod tick_rate;
+pub use self::tick_rate::TickRate;
+
+pub mod welcome;
+pub use self::welcome::Welcome;
+
+pub mod world_snapshot;
+pub use self::world_snapshot::{EntityState, WorldSnapshot, WorldState};
+
+/// The version of the protocol described by these packets. It has to be increased with every
+/// change to a packet, so that endpoints built from different versions refuse each other. The
+/// packet tests pin the wire format of every version, and fail until it has been increased.
+pub const PROTOCOL_VERSION: u16 = 13;
 
-pub trait Packet {
+pub trait Packet: Sized {
 	/// # The packets id
 	///
 	/// Every packet has a unique number, through which it can be identified.
 	fn id() -> u8;
 
-	/// # The data size
+	/// # The delivery
 	///
-	/// Every packet must have a constant data size, so that it is clear how much has to be read
-	/// from the sockets stream.
-	const SIZE: u32;
+	/// How the packet is delivered when it is sent using UDP. Packets that are sent using TCP
+	/// always arrive reliably and in order. Unless overridden, packets are sent unreliably.
+	fn delivery() -> Delivery {
+		Delivery::Unreliable
+	}
 
 	/// # Create from bytes
 	///
-	/// This function takes a slice of data, from which the packet can be constructed the size of
-	/// the slice must exactly match the associated constant "size".
-	fn from_bytes(data: &[u8]) -> Self;
+	/// This function takes the data of a frame, from which the packet can be constructed. The
+	/// slice contains exactly the data that has been written by to_bytes().
+	///
+	/// # Failure
+	/// The data comes straight from the network, so it may be cut off or contain garbage. In that
+	/// case, or if data is left over after the packet has been read, an error is returned.
+	fn from_bytes(data: &[u8]) -> Result<Self, PacketError>;
 
 	/// # Write to bytes
 	///
-	/// This function writes the packet into a slice of data which has the length of the associated
-	/// constant "size".
+	/// This function writes the packet into a vector of data. The length may vary between
+	/// packets of the same type, since it is stored in the frame.
 	fn to_bytes(&self) -> Vec<u8>;
 }
+
+/// # Claim a packet id
+///
+/// Every packet implements this for the id it uses, with the id as the length of the array. Since
+/// the same trait cannot be implemented twice, two packets with the same id do not compile.
+/// Derived packets do this on their own, packets implemented by hand have to do it themselves.
+pub trait PacketId<Id> {}
+
+/// The type every packet id is claimed for.
+pub struct PacketIds;
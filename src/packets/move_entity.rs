@@ -0,0 +1,23 @@
+//! # Move an entity
+//!
+//! Tells where an entity is and where it is heading. Since the next update replaces this one
+//! anyway, it does not matter if one gets lost, and one that arrives after a newer one is ignored.
+
+use sys::Vector;
+
+#[derive(Packet)]
+#[packet(id = 6)]
+pub struct MoveEntity {
+	/// The tick of the server the entity has been there at.
+	pub tick: u32,
+
+	/// The id of the entity that has moved.
+	pub id: u32,
+
+	/// The new position of the entity.
+	pub pos: Vector<f32>,
+
+	/// The velocity of the entity in units per second, so its movement can be continued until
+	/// the next update arrives.
+	pub velocity: Vector<f32>
+}
@@ -0,0 +1,12 @@
+//! # Ping the other endpoint
+//!
+//! Sent regularly to check whether the other endpoint is still alive. It must be answered with a
+//! pong carrying the same timestamp, so the sender can measure the round trip time.
+
+#[derive(Packet)]
+#[packet(id = 4)]
+pub struct Ping {
+	/// The time the ping has been sent at, in nanoseconds on the senders clock. Only the sender
+	/// must make sense of it.
+	pub timestamp: u64
+}
@@ -0,0 +1,120 @@
+//! # The input of a player
+//!
+//! The client samples the movement keys once every input step and sends them to the server, which
+//! moves the entity the player controls accordingly. Every input is numbered, so the server can
+//! tell the client which of its inputs it has applied so far.
+
+use packets::{Delivery, Packet, PacketError, PacketId, PacketIds, expect_end};
+use sys::Vector;
+
+use std::io::Cursor;
+use packets::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// The speed in pixels per second an entity controlled by a player moves with.
+pub const PLAYER_SPEED: f32 = 400.0;
+
+/// The time in seconds a single input lasts. Client and server both move the entity for exactly
+/// this long per input, so the client can predict where the server will put it.
+pub const INPUT_STEP: f32 = 1.0 / 60.0;
+
+const LEFT: u8 = 1;
+const RIGHT: u8 = 2;
+const UP: u8 = 4;
+const DOWN: u8 = 8;
+
+#[derive(Clone, Copy, PartialEq)]
+pub struct PlayerInput {
+	/// The number of the input. It increases by one with every input the client sends.
+	pub sequence: u32,
+
+	pub left: bool,
+	pub right: bool,
+	pub up: bool,
+	pub down: bool
+}
+
+impl PlayerInput {
+	/// # The direction of the input
+	///
+	/// Returns the normalised direction the player wants to move in. If opposing keys are pressed
+	/// at the same time, left and up win. Without any key pressed, the zero vector is returned.
+	pub fn direction(&self) -> Vector<f32> {
+		let mut direction: Vector<f32> = Vector::new(0.0, 0.0);
+
+		if self.left {
+			direction.x = -1.0;
+		}
+		else if self.right {
+			direction.x = 1.0;
+		}
+
+		if self.up {
+			direction.y = -1.0;
+		}
+		else if self.down {
+			direction.y = 1.0;
+		}
+
+		// Normalize the vector, so that the speed is the same in every direction.
+		if direction != Vector::new(0.0, 0.0) {
+			direction = direction.normalise();
+		}
+
+		direction
+	}
+
+	/// # The movement of the input
+	///
+	/// Returns how far the entity moves during the input step.
+	pub fn movement(&self) -> Vector<f32> {
+		self.direction() * PLAYER_SPEED * INPUT_STEP
+	}
+}
+
+impl Packet for PlayerInput {
+	fn id() -> u8 {
+		9
+	}
+
+	// Every input moves the entity, so none of them may be lost or applied out of order.
+	fn delivery() -> Delivery {
+		Delivery::ReliableOrdered
+	}
+
+	fn from_bytes(data: &[u8]) -> Result<PlayerInput, PacketError> {
+		let mut data = Cursor::new(data);
+
+		let sequence = try!(data.read_u32::<BigEndian>());
+		let keys = try!(data.read_u8());
+		if keys & !(LEFT | RIGHT | UP | DOWN) != 0 {
+			return Err(PacketError::InvalidValue { field: "player input keys", value: keys as u64 });
+		}
+
+		try!(expect_end(&data));
+		Ok(PlayerInput {
+			sequence: sequence,
+			    left: keys & LEFT != 0,
+			   right: keys & RIGHT != 0,
+			      up: keys & UP != 0,
+			    down: keys & DOWN != 0
+		})
+	}
+
+	fn to_bytes(&self) -> Vec<u8> {
+		let mut data = Vec::new();
+
+		let mut keys = 0;
+		if self.left  { keys |= LEFT; }
+		if self.right { keys |= RIGHT; }
+		if self.up    { keys |= UP; }
+		if self.down  { keys |= DOWN; }
+
+		data.write_u32::<BigEndian>(self.sequence).unwrap();
+		data.write_u8(keys).unwrap();
+
+		data
+	}
+}
+
+// The bits are packed more tightly than derived packets could, so the id is claimed by hand.
+impl PacketId<[(); 9]> for PacketIds {}
@@ -0,0 +1,10 @@
+//! # Answer a ping
+//!
+//! The answer to a ping. It carries the timestamp of the ping unchanged.
+
+#[derive(Packet)]
+#[packet(id = 5)]
+pub struct Pong {
+	/// The timestamp of the ping this pong answers.
+	pub timestamp: u64
+}
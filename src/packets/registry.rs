@@ -0,0 +1,70 @@
+//! # Packet registry
+//!
+//! The registry knows every packet type an endpoint understands. Packet types are registered
+//! once together with a handler, and every frame that is received afterwards is decoded into
+//! the matching packet and given to that handler. The context is passed along with the packet, so
+//! the handler knows where it came from, for instance the address of the sender.
+
+use packets::{Frame, Packet, PacketError};
+
+use std::collections::HashMap;
+
+type Handler<C> = Box<Fn(&C, &[u8]) -> Result<(), PacketError> + Send + Sync>;
+
+pub struct PacketRegistry<C> {
+	handlers: HashMap<u8, Handler<C>>
+}
+
+impl<C> PacketRegistry<C> {
+	pub fn new() -> PacketRegistry<C> {
+		PacketRegistry {
+			handlers: HashMap::new()
+		}
+	}
+
+	/// # Register a packet type
+	///
+	/// Registers the packet type P, so that every frame with its id is decoded and given to the
+	/// handler provided.
+	///
+	/// # Failure
+	/// Every id can only be registered once. In case another handler has already been registered
+	/// for the id of P, an error is returned and the registry stays unchanged.
+	pub fn register<P, F>(&mut self, handler: F) -> Result<(), String>
+		where P: Packet + 'static, F: Fn(&C, P) + Send + Sync + 'static {
+		if self.is_registered(P::id()) {
+			return Err(format!("Packet id {} has already been registered.", P::id()));
+		}
+
+		self.handlers.insert(P::id(), Box::new(move |context: &C, data: &[u8]| {
+			let packet = try!(P::from_bytes(data));
+			handler(context, packet);
+
+			Ok(())
+		}));
+
+		Ok(())
+	}
+
+	/// # Check for a packet id
+	///
+	/// Returns true if a handler for the id has been registered.
+	pub fn is_registered(&self, id: u8) -> bool {
+		self.handlers.contains_key(&id)
+	}
+
+	/// # Dispatch a frame
+	///
+	/// Decodes the packet contained in the frame and calls the handler that has been registered
+	/// for it.
+	///
+	/// # Failure
+	/// If no packet type with the id of the frame has been registered, or the packet cannot be
+	/// decoded, an error is returned and the handler is not called.
+	pub fn dispatch(&self, context: &C, frame: &Frame) -> Result<(), PacketError> {
+		match self.handlers.get(&frame.id) {
+			Some(handler) => handler(context, &frame.data),
+			None => Err(PacketError::UnknownId(frame.id))
+		}
+	}
+}
@@ -0,0 +1,60 @@
+//! # Reject a client
+//!
+//! The servers answer to a hello it does not accept. The connection is closed right after this
+//! packet has been sent.
+
+use packets::{Field, PacketError};
+
+use std::io::Cursor;
+use packets::byteorder::{self, ReadBytesExt, WriteBytesExt};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RejectReason {
+	/// The client and server speak different versions of the protocol.
+	VersionMismatch,
+
+	/// The client did not start with a valid hello.
+	InvalidHandshake,
+
+	/// The server demands a key, and the client could not prove that it knows it.
+	AuthenticationFailed,
+
+	/// The name of the client is empty, too long or contains characters that are not allowed.
+	InvalidName,
+
+	/// Somebody with the same name is already connected.
+	NameTaken
+}
+
+impl Field for RejectReason {
+	fn read_field(data: &mut Cursor<&[u8]>) -> Result<RejectReason, PacketError> {
+		match try!(data.read_u8()) {
+			0 => Ok(RejectReason::VersionMismatch),
+			1 => Ok(RejectReason::InvalidHandshake),
+			2 => Ok(RejectReason::AuthenticationFailed),
+			3 => Ok(RejectReason::InvalidName),
+			4 => Ok(RejectReason::NameTaken),
+			value => Err(PacketError::InvalidValue { field: "reject reason", value: value as u64 })
+		}
+	}
+
+	fn write_field(&self, data: &mut Vec<u8>) -> byteorder::Result<()> {
+		data.write_u8(match self {
+			&RejectReason::VersionMismatch => 0,
+			&RejectReason::InvalidHandshake => 1,
+			&RejectReason::AuthenticationFailed => 2,
+			&RejectReason::InvalidName => 3,
+			&RejectReason::NameTaken => 4
+		})
+	}
+}
+
+#[derive(Packet)]
+#[packet(id = 3)]
+pub struct Reject {
+	/// Why the client has been rejected.
+	pub reason: RejectReason,
+
+	/// A human readable explanation, which the client may show to the player.
+	pub message: String
+}
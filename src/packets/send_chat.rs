@@ -0,0 +1,15 @@
+//! # Send a chat message
+//!
+//! Something the player has written into the chat. The server checks whether the client may send
+//! into the channel and relays the text as a chat message to everyone who should read it.
+
+use packets::ChatChannel;
+
+#[derive(Packet)]
+#[packet(id = 15)]
+pub struct SendChat {
+	/// Where the message should go.
+	pub channel: ChatChannel,
+
+	pub text: String
+}
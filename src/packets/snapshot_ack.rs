@@ -0,0 +1,11 @@
+//! # Acknowledge a snapshot
+//!
+//! Tells the server the newest world snapshot the client has received, so that the following
+//! snapshots can be sent relative to it.
+
+#[derive(Packet)]
+#[packet(id = 13)]
+pub struct SnapshotAck {
+	/// The tick of the snapshot that has been received.
+	pub tick: u32
+}
@@ -2,12 +2,11 @@
 //!
 //! Command to spawn an entity with the correct type and the given instructions.
 
-use packets::Packet;
 use sys::Vector;
 
-use std::io::Cursor;
-use packets::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
-
+// An entity that has not been spawned cannot be updated, so this must never be lost.
+#[derive(Packet)]
+#[packet(id = 0, delivery = ReliableOrdered)]
 pub struct SpawnEntity {
 	/// The id of the entity in the game world. Later it is going to be referenced by its id only.
 	pub id: u32,
@@ -24,40 +23,3 @@ pub struct SpawnEntity {
 	// The position of the entity.
 	pub pos: Vector<f32>
 }
-
-impl Packet for SpawnEntity {
-	const SIZE: u32 = 13;
-
-	fn id() -> u8 {
-		0
-	}
-
-	fn from_bytes(data: &[u8]) -> SpawnEntity {
-		let mut data = Cursor::new(data);
-		let mut pos = Vector::new(0.0, 0.0);
-
-		pos.x = data.read_f32::<BigEndian>().unwrap();
-		pos.y = data.read_f32::<BigEndian>().unwrap();
-
-		SpawnEntity {
-			        id: data.read_u32::<BigEndian>().unwrap(),
-			      kind: data.read_u8().unwrap(),
-			max_health: data.read_u16::<BigEndian>().unwrap(),
-			    health: data.read_u16::<BigEndian>().unwrap(),
-			       pos: pos
-		}
-	}
-
-	fn to_bytes(&self) -> Vec<u8> {
-		let mut data = Vec::new();
-
-		data.write_f32::<BigEndian>(self.pos.x).unwrap();
-		data.write_f32::<BigEndian>(self.pos.y).unwrap();
-		data.write_u32::<BigEndian>(self.id).unwrap();
-		data.write_u8(self.kind).unwrap();
-		data.write_u16::<BigEndian>(self.max_health).unwrap();
-		data.write_u16::<BigEndian>(self.health).unwrap();
-
-		data
-	}
-}
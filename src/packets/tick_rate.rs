@@ -0,0 +1,12 @@
+//! # Tell the tick rate
+//!
+//! Tells the client how many ticks the server runs per second. The ticks are evenly spaced from
+//! the start of the server on, so together with the tick an update has been sent in, the client
+//! can tell when it happened on the server, no matter how long it took to arrive.
+
+#[derive(Packet)]
+#[packet(id = 21, delivery = ReliableOrdered)]
+pub struct TickRate {
+	/// The amount of ticks per second.
+	pub tick_rate: u16
+}
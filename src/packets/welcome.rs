@@ -0,0 +1,19 @@
+//! # Welcome a client
+//!
+//! The servers answer to a hello it accepts. From this moment on, the client is a user of the
+//! server.
+
+#[derive(Packet)]
+#[packet(id = 2)]
+pub struct Welcome {
+	/// The id the server has assigned to this session. It is unique as long as the server runs.
+	pub session_id: u32,
+
+	/// The secret token of this session. Every datagram the client sends must start with it, so
+	/// the server knows which user it belongs to, no matter which address it comes from.
+	pub token: u64,
+
+	/// The port the server receives datagrams on, which may differ from the port of the stream.
+	/// The datagrams of the client must be sent there.
+	pub udp_port: u16
+}
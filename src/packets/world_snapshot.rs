@@ -0,0 +1,338 @@
+//! # World snapshot
+//!
+//! The state of every entity a client knows about, sent once every tick. Instead of the full
+//! state, only what changed since a snapshot the client has acknowledged is sent. Every entity
+//! that changed is sent with a bitmask telling which of its fields follow, entities that did not
+//! change are left out entirely. Positions and velocities are quantised to fixed point, so that
+//! they do not change from tiny rounding errors, and velocities fit into two bytes.
+//!
+//! Snapshots are sent unreliably. If one gets lost, the next one is still relative to a snapshot
+//! the client has, since the server only ever uses acknowledged ones.
+
+use packets::{Packet, PacketError, PacketId, PacketIds, ReadFieldsExt, WriteFieldsExt, expect_end};
+use sys::Vector;
+
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::{i16, i32};
+use packets::byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+
+/// The amount of steps a position is quantised to per pixel.
+const POSITION_SCALE: f32 = 16.0;
+
+/// The amount of steps a velocity is quantised to per pixel per second.
+const VELOCITY_SCALE: f32 = 8.0;
+
+const KIND: u8 = 1;
+const MAX_HEALTH: u8 = 2;
+const HEALTH: u8 = 4;
+const X: u8 = 8;
+const Y: u8 = 16;
+const VELOCITY_X: u8 = 32;
+const VELOCITY_Y: u8 = 64;
+const REMOVED: u8 = 128;
+
+/// The fields every entity that is new to the client must be sent with.
+const ALL_FIELDS: u8 = KIND | MAX_HEALTH | HEALTH | X | Y | VELOCITY_X | VELOCITY_Y;
+
+/// # The state of an entity
+///
+/// Everything a client is told about an entity, already quantised the way it is sent.
+#[derive(Clone, Copy, PartialEq)]
+pub struct EntityState {
+	pub kind: u8,
+	pub max_health: u16,
+	pub health: u16,
+	x: i32,
+	y: i32,
+	velocity_x: i16,
+	velocity_y: i16
+}
+
+impl EntityState {
+	pub fn new(kind: u8, max_health: u16, health: u16, pos: Vector<f32>, velocity: Vector<f32>) -> EntityState {
+		EntityState {
+			kind: kind,
+			max_health: max_health,
+			health: health,
+			x: quantise(pos.x, POSITION_SCALE, i32::MIN as f32, i32::MAX as f32) as i32,
+			y: quantise(pos.y, POSITION_SCALE, i32::MIN as f32, i32::MAX as f32) as i32,
+			velocity_x: quantise(velocity.x, VELOCITY_SCALE, i16::MIN as f32, i16::MAX as f32) as i16,
+			velocity_y: quantise(velocity.y, VELOCITY_SCALE, i16::MIN as f32, i16::MAX as f32) as i16
+		}
+	}
+
+	pub fn pos(&self) -> Vector<f32> {
+		Vector::new(self.x as f32 / POSITION_SCALE, self.y as f32 / POSITION_SCALE)
+	}
+
+	pub fn velocity(&self) -> Vector<f32> {
+		Vector::new(self.velocity_x as f32 / VELOCITY_SCALE, self.velocity_y as f32 / VELOCITY_SCALE)
+	}
+}
+
+/// Scales the value and rounds it to the closest step, limited to what the target type can hold.
+fn quantise(value: f32, scale: f32, min: f32, max: f32) -> f32 {
+	let value = (value * scale).round();
+
+	if value.is_nan() {
+		0.0
+	}
+	else if value < min {
+		min
+	}
+	else if value > max {
+		max
+	}
+	else {
+		value
+	}
+}
+
+/// The state of every entity by its id.
+pub type WorldState = HashMap<u32, EntityState>;
+
+/// # The change of a single entity
+///
+/// Contains only the fields that are different from the baseline. An entity that has been removed
+/// has no fields at all.
+pub struct EntityDelta {
+	pub id: u32,
+	pub removed: bool,
+	pub kind: Option<u8>,
+	pub max_health: Option<u16>,
+	pub health: Option<u16>,
+	x: Option<i32>,
+	y: Option<i32>,
+	velocity_x: Option<i16>,
+	velocity_y: Option<i16>
+}
+
+impl EntityDelta {
+	/// Creates the delta that turns the old state into the new one. Without an old state, every
+	/// field is contained.
+	fn between(id: u32, old: Option<&EntityState>, new: &EntityState) -> EntityDelta {
+		EntityDelta {
+			id: id,
+			removed: false,
+			kind: changed(old.map(|old| old.kind), new.kind),
+			max_health: changed(old.map(|old| old.max_health), new.max_health),
+			health: changed(old.map(|old| old.health), new.health),
+			x: changed(old.map(|old| old.x), new.x),
+			y: changed(old.map(|old| old.y), new.y),
+			velocity_x: changed(old.map(|old| old.velocity_x), new.velocity_x),
+			velocity_y: changed(old.map(|old| old.velocity_y), new.velocity_y)
+		}
+	}
+
+	fn removed(id: u32) -> EntityDelta {
+		EntityDelta {
+			id: id,
+			removed: true,
+			kind: None,
+			max_health: None,
+			health: None,
+			x: None,
+			y: None,
+			velocity_x: None,
+			velocity_y: None
+		}
+	}
+
+	/// The bitmask of the fields that are contained.
+	fn mask(&self) -> u8 {
+		let mut mask = 0;
+
+		if self.removed              { mask |= REMOVED; }
+		if self.kind.is_some()       { mask |= KIND; }
+		if self.max_health.is_some() { mask |= MAX_HEALTH; }
+		if self.health.is_some()     { mask |= HEALTH; }
+		if self.x.is_some()          { mask |= X; }
+		if self.y.is_some()          { mask |= Y; }
+		if self.velocity_x.is_some() { mask |= VELOCITY_X; }
+		if self.velocity_y.is_some() { mask |= VELOCITY_Y; }
+
+		mask
+	}
+
+	/// Applies the delta to the old state of the entity. An entity the client does not know yet
+	/// must contain every field.
+	fn apply(&self, old: Option<&EntityState>) -> Result<EntityState, PacketError> {
+		let old = match old {
+			Some(old) => *old,
+			None => {
+				if self.mask() != ALL_FIELDS {
+					return Err(PacketError::InvalidValue { field: "fields of new entity", value: self.mask() as u64 });
+				}
+
+				EntityState {
+					kind: 0,
+					max_health: 0,
+					health: 0,
+					x: 0,
+					y: 0,
+					velocity_x: 0,
+					velocity_y: 0
+				}
+			}
+		};
+
+		Ok(EntityState {
+			kind: self.kind.unwrap_or(old.kind),
+			max_health: self.max_health.unwrap_or(old.max_health),
+			health: self.health.unwrap_or(old.health),
+			x: self.x.unwrap_or(old.x),
+			y: self.y.unwrap_or(old.y),
+			velocity_x: self.velocity_x.unwrap_or(old.velocity_x),
+			velocity_y: self.velocity_y.unwrap_or(old.velocity_y)
+		})
+	}
+}
+
+fn changed<T: PartialEq>(old: Option<T>, new: T) -> Option<T> {
+	match old {
+		Some(ref old) if *old == new => None,
+		_ => Some(new)
+	}
+}
+
+pub struct WorldSnapshot {
+	/// The tick of the server the snapshot has been taken at.
+	pub tick: u32,
+
+	/// The tick of the snapshot this one is relative to. Without a baseline, the snapshot contains
+	/// every entity in full.
+	pub baseline: Option<u32>,
+
+	/// The entities that changed since the baseline.
+	pub entities: Vec<EntityDelta>
+}
+
+impl WorldSnapshot {
+	/// # Create a snapshot
+	///
+	/// Creates the snapshot of the current state, relative to the baseline state and the tick it
+	/// has been taken at, if there is one.
+	pub fn delta(tick: u32, baseline: Option<(u32, &WorldState)>, current: &WorldState) -> WorldSnapshot {
+		let mut entities = Vec::new();
+
+		let (baseline_tick, baseline) = match baseline {
+			Some((baseline_tick, baseline)) => (Some(baseline_tick), Some(baseline)),
+			None => (None, None)
+		};
+
+		for (id, state) in current.iter() {
+			let old = baseline.and_then(|baseline| baseline.get(id));
+			if old != Some(state) {
+				entities.push(EntityDelta::between(*id, old, state));
+			}
+		}
+
+		if let Some(baseline) = baseline {
+			for (id, _) in baseline.iter() {
+				if !current.contains_key(id) {
+					entities.push(EntityDelta::removed(*id));
+				}
+			}
+		}
+
+		WorldSnapshot {
+			tick: tick,
+			baseline: baseline_tick,
+			entities: entities
+		}
+	}
+
+	/// # Apply the snapshot
+	///
+	/// Creates the full state from the baseline state, which must be the one the snapshot is
+	/// relative to.
+	///
+	/// # Failure
+	/// If an entity that is not part of the baseline is not sent in full, an error is returned.
+	pub fn apply(&self, baseline: Option<&WorldState>) -> Result<WorldState, PacketError> {
+		let mut state = match baseline {
+			Some(baseline) => baseline.clone(),
+			None => WorldState::new()
+		};
+
+		for delta in &self.entities {
+			if delta.removed {
+				state.remove(&delta.id);
+			}
+			else {
+				let entity = try!(delta.apply(state.get(&delta.id)));
+				state.insert(delta.id, entity);
+			}
+		}
+
+		Ok(state)
+	}
+}
+
+impl Packet for WorldSnapshot {
+	fn id() -> u8 {
+		12
+	}
+
+	fn from_bytes(data: &[u8]) -> Result<WorldSnapshot, PacketError> {
+		let mut data = Cursor::new(data);
+
+		let world_snapshot = WorldSnapshot {
+			    tick: try!(data.read_u32::<BigEndian>()),
+			baseline: try!(data.read_option(|data| Ok(try!(data.read_u32::<BigEndian>())))),
+			entities: try!(data.read_list(read_delta))
+		};
+
+		try!(expect_end(&data));
+		Ok(world_snapshot)
+	}
+
+	fn to_bytes(&self) -> Vec<u8> {
+		let mut data = Vec::new();
+
+		data.write_u32::<BigEndian>(self.tick).unwrap();
+		data.write_option(&self.baseline, |data, baseline| data.write_u32::<BigEndian>(*baseline)).unwrap();
+		data.write_list(&self.entities, |data, delta| {
+			try!(data.write_u32::<BigEndian>(delta.id));
+			try!(data.write_u8(delta.mask()));
+
+			if let Some(kind) = delta.kind             { try!(data.write_u8(kind)); }
+			if let Some(max_health) = delta.max_health { try!(data.write_u16::<BigEndian>(max_health)); }
+			if let Some(health) = delta.health         { try!(data.write_u16::<BigEndian>(health)); }
+			if let Some(x) = delta.x                   { try!(data.write_i32::<BigEndian>(x)); }
+			if let Some(y) = delta.y                   { try!(data.write_i32::<BigEndian>(y)); }
+			if let Some(velocity_x) = delta.velocity_x { try!(data.write_i16::<BigEndian>(velocity_x)); }
+			if let Some(velocity_y) = delta.velocity_y { try!(data.write_i16::<BigEndian>(velocity_y)); }
+
+			Ok(())
+		}).unwrap();
+
+		data
+	}
+}
+
+// The entities are only sent with the fields that changed, so the id is claimed by hand.
+impl PacketId<[(); 12]> for PacketIds {}
+
+fn read_delta(data: &mut Cursor<&[u8]>) -> Result<EntityDelta, PacketError> {
+	let id = try!(data.read_u32::<BigEndian>());
+	let mask = try!(data.read_u8());
+
+	// A removed entity has no fields, and an entity that did not change is not sent at all.
+	if mask == 0 || (mask & REMOVED != 0 && mask != REMOVED) {
+		return Err(PacketError::InvalidValue { field: "entity delta mask", value: mask as u64 });
+	}
+
+	Ok(EntityDelta {
+		        id: id,
+		   removed: mask & REMOVED != 0,
+		      kind: if mask & KIND != 0 { Some(try!(data.read_u8())) } else { None },
+		max_health: if mask & MAX_HEALTH != 0 { Some(try!(data.read_u16::<BigEndian>())) } else { None },
+		    health: if mask & HEALTH != 0 { Some(try!(data.read_u16::<BigEndian>())) } else { None },
+		         x: if mask & X != 0 { Some(try!(data.read_i32::<BigEndian>())) } else { None },
+		         y: if mask & Y != 0 { Some(try!(data.read_i32::<BigEndian>())) } else { None },
+		velocity_x: if mask & VELOCITY_X != 0 { Some(try!(data.read_i16::<BigEndian>())) } else { None },
+		velocity_y: if mask & VELOCITY_Y != 0 { Some(try!(data.read_i16::<BigEndian>())) } else { None }
+	})
+}
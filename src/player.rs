@@ -2,13 +2,25 @@
 //!
 //! The player is used to process all input from the actual player, and translate it into commands
 //! in the game, that a character can execute.
+//!
+//! When playing on a server, the inputs are applied to the character right away instead of waiting
+//! for the server to move it. The inputs are kept until the server acknowledges them, so once the
+//! server says where the character really is, the inputs it has not seen yet can be applied again
+//! from there.
 
 use graphics::RenderWindow;
 use sdl2::keyboard::Keycode;
 use sdl2::event::Event;
 use character::Character;
+use packets::{InputAck, PlayerInput};
+use packets::player_input::{INPUT_STEP, PLAYER_SPEED};
+use std::collections::VecDeque;
 use std::fmt::{Display, Error, Formatter};
-use sys::{Movable, Vector};
+use sys::Movable;
+
+/// The amount of inputs that are kept while waiting for the server to acknowledge them. At one
+/// input per step, this is enough for a few seconds.
+const MAX_PENDING_INPUTS: usize = 300;
 
 pub struct Player {
 	character: Character,
@@ -17,7 +29,18 @@ pub struct Player {
 	left_pressed: bool,
 	right_pressed: bool,
 	up_pressed: bool,
-	down_pressed: bool
+	down_pressed: bool,
+
+	// The inputs that have been sent to the server, but not yet acknowledged, oldest first.
+	pending_inputs: VecDeque<PlayerInput>,
+	next_sequence: u32,
+
+	// The sequence number of the newest input the server has acknowledged. Acknowledgements are
+	// sent unreliably, so an older one may still arrive after it.
+	last_ack: Option<u32>,
+
+	// The time that has passed since the last input, but is not enough for another one yet.
+	input_time: f32
 }
 
 impl Player {
@@ -29,7 +52,11 @@ impl Player {
 			left_pressed: false,
 			right_pressed: false,
 			up_pressed: false,
-			down_pressed: false
+			down_pressed: false,
+			pending_inputs: VecDeque::new(),
+			next_sequence: 0,
+			last_ack: None,
+			input_time: 0.0
 		}
 	}
 
@@ -51,31 +78,81 @@ impl Player {
 		}
 	}
 
+	/// # The input state
+	///
+	/// Returns which of the movement keys are currently pressed, as it is sent to the server.
+	pub fn input(&self) -> PlayerInput {
+		PlayerInput {
+			sequence: self.next_sequence,
+			left: self.left_pressed,
+			right: self.right_pressed,
+			up: self.up_pressed,
+			down: self.down_pressed
+		}
+	}
+
 	pub fn update(&mut self, game_window: &RenderWindow) {
-		let mut mov_vec: Vector<f32> = Vector::new(0.0, 0.0);
+		let mov_vec = self.input().direction() * PLAYER_SPEED * game_window.frame_duration();
 
-		if self.left_pressed {
-			mov_vec.x = -1.0;
-		}
-		else if self.right_pressed {
-			mov_vec.x = 1.0;
-		}
+		self.character.translate(mov_vec.x, mov_vec.y);
+	}
 
-		if self.up_pressed {
-			mov_vec.y = -1.0;
-		}
-		else if self.down_pressed {
-			mov_vec.y = 1.0;
+	/// # Predict the movement
+	///
+	/// Creates an input for every input step that has passed during the frame and moves the
+	/// character accordingly, just like the server is going to. Returns the new inputs, which
+	/// must be sent to the server.
+	pub fn predict(&mut self, frame_duration: f32) -> Vec<PlayerInput> {
+		let mut inputs = Vec::new();
+
+		self.input_time += frame_duration;
+		while self.input_time >= INPUT_STEP {
+			self.input_time -= INPUT_STEP;
+
+			let input = self.input();
+			self.next_sequence = self.next_sequence.wrapping_add(1);
+
+			let movement = input.movement();
+			self.character.translate(movement.x, movement.y);
+
+			// If the server does not answer for a long time, the oldest inputs are forgotten. The
+			// prediction may be wrong until the next acknowledgement in that case.
+			if self.pending_inputs.len() >= MAX_PENDING_INPUTS {
+				self.pending_inputs.pop_front();
+			}
+			self.pending_inputs.push_back(input);
+
+			inputs.push(input);
 		}
 
-		// Normalize the vector, so that the speed is the same in every direction.
-		if mov_vec != Vector::new(0.0, 0.0) {
-			let normalised = mov_vec.normalise();
+		inputs
+	}
 
-			mov_vec = normalised * 400.0 * game_window.frame_duration();
+	/// # Reconcile with the server
+	///
+	/// Moves the character to where the server says it is, forgets all inputs the server has
+	/// applied and applies the remaining ones again. Acknowledgements that are not newer than the
+	/// last one are ignored, since the character has already been moved past them.
+	pub fn reconcile(&mut self, ack: &InputAck) {
+		if let Some(last_ack) = self.last_ack {
+			if ack.sequence <= last_ack {
+				return;
+			}
 		}
+		self.last_ack = Some(ack.sequence);
 
-		self.character.translate(mov_vec.x, mov_vec.y);
+		while let Some(input) = self.pending_inputs.pop_front() {
+			if input.sequence > ack.sequence {
+				self.pending_inputs.push_front(input);
+				break;
+			}
+		}
+
+		self.character.set_pos(ack.pos);
+		for input in self.pending_inputs.iter() {
+			let movement = input.movement();
+			self.character.translate(movement.x, movement.y);
+		}
 	}
 
 	pub fn character(&self) -> &Character {
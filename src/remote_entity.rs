@@ -0,0 +1,161 @@
+//! # An entity controlled by the server
+//!
+//! Every entity the server tells the client about is represented by a remote entity. The client
+//! does not decide anything about it, it only shows what the server says. Its position is taken
+//! from the snapshots the server sends, a little behind the newest one.
+
+use entity::{self, Entity};
+use sdl2::render::Renderer;
+use sys::{Movable, Vector};
+use graphics::{Sprite, Drawable, RenderWindow};
+use snapshot_buffer::SnapshotBuffer;
+
+pub struct RemoteEntity {
+	id: u32,
+	kind: u8,
+	health: u16,
+	max_health: u16,
+	velocity: Vector<f32>,
+
+	// The tick of the newest update from the server, if it came with one.
+	newest_tick: Option<u32>,
+	snapshots: SnapshotBuffer,
+	sprite: Sprite
+}
+
+impl RemoteEntity {
+	/// # Create a remote entity
+	///
+	/// Creates the entity with the sprite that belongs to its kind. The position is the first
+	/// snapshot of the entity, from the tick of the server if it is known, at the time provided in
+	/// nanoseconds.
+	///
+	/// # Failure
+	/// In case the texture of the kind cannot be loaded, an error is returned.
+	pub fn new(game_window: &mut RenderWindow, id: u32, kind: u8, max_health: u16, health: u16, pos: Vector<f32>, tick: Option<u32>, time: u64) -> Result<RemoteEntity, String> {
+		let sprite = try!(game_window.create_sprite(RemoteEntity::texture(kind), (pos.x, pos.y), None));
+
+		let mut snapshots = SnapshotBuffer::new();
+		snapshots.push(time, pos, Vector::new(0.0, 0.0));
+
+		Ok(RemoteEntity {
+			id: id,
+			kind: kind,
+			health: health,
+			max_health: max_health,
+			velocity: Vector::new(0.0, 0.0),
+			newest_tick: tick,
+			snapshots: snapshots,
+			sprite: sprite
+		})
+	}
+
+	/// # The texture of a kind
+	///
+	/// Returns the name of the texture entities of the given kind are drawn with.
+	// TODO: There is only one kind of entity for now. This should be part of some sort of
+	// database, together with the textures.
+	fn texture(_kind: u8) -> &'static str {
+		"character.bmp"
+	}
+
+	pub fn id(&self) -> u32 {
+		self.id
+	}
+
+	pub fn kind(&self) -> u8 {
+		self.kind
+	}
+
+	/// # Set the health
+	///
+	/// The server has the final say on the health, so it is taken as is, only limited by the
+	/// maximum health.
+	pub fn set_health(&mut self, health: u16) {
+		self.health = if health < self.max_health { health } else { self.max_health };
+	}
+
+	pub fn velocity(&self) -> Vector<f32> {
+		self.velocity
+	}
+
+	/// # Add a snapshot
+	///
+	/// Remembers where the entity was and where it was heading in the tick of the server, at the
+	/// time provided in nanoseconds. The entity only gets there once the render time has caught
+	/// up. Updates that arrive after one from a later tick are ignored.
+	pub fn push_snapshot(&mut self, tick: u32, time: u64, pos: Vector<f32>, velocity: Vector<f32>) {
+		if let Some(newest) = self.newest_tick {
+			if tick <= newest {
+				return;
+			}
+		}
+
+		self.newest_tick = Some(tick);
+		self.velocity = velocity;
+		self.snapshots.push(time, pos, velocity);
+	}
+
+	/// # Update the entity
+	///
+	/// Moves the entity to where it was at the render time, according to the snapshots. Past the
+	/// newest snapshot, the movement is continued for no longer than the maximum extrapolation.
+	/// Both are in nanoseconds.
+	pub fn update(&mut self, render_time: u64, max_extrapolation: u64) {
+		if let Some(pos) = self.snapshots.sample(render_time, max_extrapolation) {
+			self.set_pos(pos);
+		}
+	}
+}
+
+impl Entity for RemoteEntity {
+	fn health(&self) -> u16 {
+		self.health
+	}
+
+	fn max_health(&self) -> u16 {
+		self.max_health
+	}
+
+	fn damage<T: Entity>(&mut self, raw_damage: u16, _: &T) -> u16 {
+		let actual_damage = raw_damage;
+		self.health = entity::damaged(self.health, actual_damage);
+
+		actual_damage
+	}
+
+	fn heal<T: Entity>(&mut self, raw_healing: u16, _: &T) -> u16 {
+		let actual_healing = raw_healing;
+		self.health = entity::healed(self.health, actual_healing, self.max_health());
+
+		actual_healing
+	}
+}
+
+impl Movable for RemoteEntity {
+	fn translate(&mut self, x: f32, y: f32) {
+		self.sprite.translate(x, y);
+	}
+
+	fn set_pos(&mut self, pos: Vector<f32>) {
+		self.sprite.set_pos(pos);
+	}
+
+	fn x(&self) -> f32 {
+		self.sprite.x()
+	}
+
+	fn y(&self) -> f32 {
+		self.sprite.y()
+	}
+
+	fn pos(&self) -> Vector<f32> {
+		self.sprite.pos()
+	}
+}
+
+impl Drawable for RemoteEntity {
+	fn draw(&self, renderer: &mut Renderer) {
+		self.sprite.draw(renderer);
+	}
+}
@@ -8,10 +8,320 @@
 #![feature(append)]
 #![feature(associated_consts)]
 
-mod packets;
-mod srv;
-mod sys;
+extern crate stupid;
+extern crate time;
+
+use stupid::packets::{PacketRegistry, PlayerInput, ControlEntity, DisconnectReason, SharedKey, SnapshotAck, TickRate};
+use stupid::srv::{BindConfig, Chat, ConnectionHub, Interest, KeepAlive, NetworkConditions, RateLimit, SnapshotHistory, World};
+use stupid::sys::Movable;
+
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, BufRead};
+use std::net::SocketAddr;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+/// The port the server listens on for TCP and UDP, unless another one is given on the command
+/// line.
+const DEFAULT_PORT: u16 = 4242;
+
+/// The ticks per second, unless another rate is given on the command line.
+const DEFAULT_TICK_RATE: u16 = 30;
+
+/// The distance around their character users hear about entities in, unless another one is given
+/// on the command line.
+const DEFAULT_INTEREST_RADIUS: f32 = 800.0;
+
+/// The amount of ticks the server may fall behind, before it stops trying to catch up.
+const MAX_TICKS_BEHIND: u64 = 5;
+
+/// The amount of inputs that may wait for the next tick per user. Anything beyond is dropped, so a
+/// user cannot move faster by sending more inputs than the input step allows.
+const MAX_QUEUED_INPUTS: usize = 30;
+
+/// The time between two reports of the bandwidth used for every user.
+const BANDWIDTH_REPORT_INTERVAL_MS: u64 = 5000;
+
+/// The inputs of every user that arrived since the last tick, in the order they arrived in.
+type SafeInputMap = Arc<Mutex<HashMap<SocketAddr, Vec<PlayerInput>>>>;
+
+/// The tick of the newest snapshot every user has acknowledged since the last tick.
+type SafeAckMap = Arc<Mutex<HashMap<SocketAddr, u32>>>;
 
 pub fn main() {
-	println!("This is awesome main function.");
+	// The address, the tick rate, the interest radius and the UDP port may be given on the command
+	// line, in that order. An address that is only a port listens on all interfaces.
+	let bind = match env::args().nth(1) {
+		Some(address) => match address.parse::<u16>() {
+			Ok(port) => BindConfig::any(port),
+			Err(_) => match address.parse::<SocketAddr>() {
+				Ok(address) => BindConfig::new(address),
+				Err(err) => exit_with_usage(format!("Invalid address '{}'. {}", address, err))
+			}
+		},
+		None => BindConfig::any(DEFAULT_PORT)
+	};
+
+	let tick_rate = match env::args().nth(2) {
+		Some(rate) => match rate.parse::<u16>() {
+			Ok(rate) if rate > 0 => rate,
+			Ok(_) => exit_with_usage(String::from("The tick rate must be at least 1.")),
+			Err(err) => exit_with_usage(format!("Invalid tick rate '{}'. {}", rate, err))
+		},
+		None => DEFAULT_TICK_RATE
+	};
+
+	let interest_radius = match env::args().nth(3) {
+		Some(radius) => match radius.parse::<f32>() {
+			Ok(radius) if radius > 0.0 => radius,
+			Ok(_) => exit_with_usage(String::from("The interest radius must be positive.")),
+			Err(err) => exit_with_usage(format!("Invalid interest radius '{}'. {}", radius, err))
+		},
+		None => DEFAULT_INTEREST_RADIUS
+	};
+
+	let bind = match env::args().nth(4) {
+		Some(port) => match port.parse::<u16>() {
+			Ok(port) => bind.with_udp_port(port),
+			Err(err) => exit_with_usage(format!("Invalid Udp port '{}'. {}", port, err))
+		},
+		None => bind
+	};
+
+	let inputs: SafeInputMap = Arc::new(Mutex::new(HashMap::new()));
+
+	let mut registry = PacketRegistry::new();
+	let inputs_clone = inputs.clone();
+	registry.register::<PlayerInput, _>(move |addr, input| {
+		let mut input_map = inputs_clone.lock().unwrap();
+		let queued = input_map.entry(*addr).or_insert(Vec::new());
+
+		if queued.len() < MAX_QUEUED_INPUTS {
+			queued.push(input);
+		}
+	}).unwrap();
+
+	let acks: SafeAckMap = Arc::new(Mutex::new(HashMap::new()));
+	let acks_clone = acks.clone();
+	registry.register::<SnapshotAck, _>(move |addr, ack| {
+		let mut ack_map = acks_clone.lock().unwrap();
+		let newest = ack_map.entry(*addr).or_insert(ack.tick);
+
+		if ack.tick > *newest {
+			*newest = ack.tick;
+		}
+	}).unwrap();
+
+	let chat = Chat::new();
+	chat.register(&mut registry).unwrap();
+
+	// The key clients have to know is taken from the environment, so it does not show up in the
+	// list of processes. Without one, everyone is welcome.
+	let key = match env::var("STUPID_KEY") {
+		Ok(key) => match SharedKey::new(key.as_bytes()) {
+			Ok(key) => Some(key),
+			Err(err) => exit_with_usage(format!("Invalid key. {}", err))
+		},
+		Err(_) => None
+	};
+	let authenticated = key.is_some();
+
+	// Bad network conditions can be simulated for testing, like `latency=100,jitter=20,loss=0.05`.
+	let conditions = match env::var("STUPID_NETWORK") {
+		Ok(conditions) => match conditions.parse::<NetworkConditions>() {
+			Ok(conditions) => Some(conditions),
+			Err(err) => exit_with_usage(format!("Invalid network conditions. {}", err))
+		},
+		Err(_) => None
+	};
+
+	let mut hub = match ConnectionHub::new(bind, registry, KeepAlive::default(), key, RateLimit::default(), conditions) {
+		Ok(hub) => hub,
+		Err(err) => {
+			println!("Unable to start the server. {}", err);
+			process::exit(1);
+		}
+	};
+
+	println!("Listening on {} using Tcp and {} using Udp with {} ticks per second.", bind.tcp, bind.udp, tick_rate);
+	if authenticated {
+		println!("Clients have to know the key, and all datagrams are signed.");
+	}
+	if let Some(conditions) = conditions {
+		println!("Simulating {} ms latency, {} ms jitter, {}% loss, {}% duplication and {}% reordering with seed {}.", conditions.latency_ms, conditions.jitter_ms, conditions.loss * 100.0, conditions.duplication * 100.0, conditions.reordering * 100.0, conditions.seed);
+	}
+	println!("Type 'quit' to shut the server down.");
+
+	let running = Arc::new(AtomicBool::new(true));
+	let running_clone = running.clone();
+	thread::spawn(move || {
+		read_commands(running_clone);
+	});
+
+	run(&mut hub, &chat, inputs, acks, tick_rate, interest_radius, running);
+
+	hub.shutdown(DisconnectReason::ServerShutdown, "The server is shutting down.");
+}
+
+/// Reads the commands typed into the console, until the server is told to quit. Without a console,
+/// the server runs until it is killed.
+fn read_commands(running: Arc<AtomicBool>) {
+	let stdin = io::stdin();
+
+	for line in stdin.lock().lines() {
+		match line {
+			Ok(ref command) if command.trim() == "quit" => {
+				running.store(false, Ordering::Relaxed);
+				return;
+			},
+			Ok(command) => println!("Unknown command '{}'.", command.trim()),
+			Err(_) => return
+		}
+	}
+}
+
+fn exit_with_usage(message: String) -> ! {
+	println!("{}", message);
+	println!("Usage: server [port or address] [tick rate] [interest radius] [udp port]");
+	process::exit(1);
+}
+
+/// What the server knows about the world as every user sees it.
+struct View {
+	history: SnapshotHistory,
+	interest: Interest
+}
+
+/// # Run the server loop
+///
+/// Every tick, new users get a character and the characters of users that are gone are removed.
+/// Then the latest inputs are applied, the world is stepped, the chat is relayed and a snapshot of
+/// the part around their character is sent to every user. The ticks are spaced evenly, no matter
+/// how long a single tick takes. The loop ends once the server is told to stop running.
+fn run(hub: &mut ConnectionHub, chat: &Chat, inputs: SafeInputMap, acks: SafeAckMap, tick_rate: u16, interest_radius: f32, running: Arc<AtomicBool>) {
+	let mut world = World::new();
+	let mut views: HashMap<SocketAddr, View> = HashMap::new();
+	let mut tick: u32 = 0;
+
+	let tick_duration = 1.0 / tick_rate as f32;
+	let tick_ns = 1000000000 / tick_rate as u64;
+	let mut next_tick = time::precise_time_ns();
+
+	let mut last_report = next_tick;
+	let mut reported_bytes: HashMap<SocketAddr, u64> = HashMap::new();
+
+	while running.load(Ordering::Relaxed) {
+		tick = tick.wrapping_add(1);
+		let users = hub.user_addresses();
+
+		for addr in &users {
+			if !world.is_player(addr) {
+				join(hub, &mut world, *addr, tick_rate);
+				views.insert(*addr, View {
+					history: SnapshotHistory::new(),
+					interest: Interest::new(interest_radius)
+				});
+			}
+		}
+
+		for addr in world.players() {
+			if !users.contains(&addr) {
+				world.remove_player(&addr);
+				views.remove(&addr);
+				reported_bytes.remove(&addr);
+			}
+		}
+
+		for (addr, queued) in inputs.lock().unwrap().drain() {
+			for input in queued {
+				world.apply_input(&addr, &input);
+			}
+		}
+
+		for (addr, ack) in acks.lock().unwrap().drain() {
+			if let Some(view) = views.get_mut(&addr) {
+				view.history.acknowledge(ack);
+			}
+		}
+
+		world.step(tick_duration);
+		chat.relay(hub);
+
+		// Entities appear and disappear on the clients as they appear in and disappear from the
+		// snapshots, so there is no need to spawn or despawn them separately. This includes them
+		// entering or leaving the area of interest.
+		let state = world.state();
+		for addr in &users {
+			let (center, controlled) = match world.character(addr) {
+				Some(character) => (character.pos(), Some(character.id())),
+				None => continue
+			};
+
+			if let Some(view) = views.get_mut(addr) {
+				let visible = view.interest.filter(center, controlled, &state);
+				let _ = hub.send_udp(addr, &view.history.snapshot(tick, &visible));
+			}
+
+			if let Some(ack) = world.input_ack(addr) {
+				let _ = hub.send_udp(addr, &ack);
+			}
+		}
+
+		next_tick += tick_ns;
+		let now = time::precise_time_ns();
+
+		if now - last_report >= BANDWIDTH_REPORT_INTERVAL_MS * 1000000 {
+			report_bandwidth(hub, &users, &mut reported_bytes, (now - last_report) as f32 / 1000000000.0);
+			last_report = now;
+		}
+
+		if now < next_tick {
+			thread::sleep(Duration::new(0, (next_tick - now) as u32));
+		}
+		else if now - next_tick > MAX_TICKS_BEHIND * tick_ns {
+			// The ticks that have been missed are skipped, instead of being run all at once. They
+			// are still counted, so the clients can keep telling the time of the server by them.
+			println!("The server is {} ms behind. Skipping ticks.", (now - next_tick) / 1000000);
+			let skipped = (now - next_tick) / tick_ns;
+			tick = tick.wrapping_add(skipped as u32);
+			next_tick += skipped * tick_ns;
+		}
+	}
+}
+
+/// Gives a new user a character to control, and tells them the tick rate to time the snapshots by.
+fn join(hub: &mut ConnectionHub, world: &mut World, addr: SocketAddr, tick_rate: u16) {
+	let id = world.add_player(addr).id();
+
+	let _ = hub.send_tcp(&addr, &TickRate { tick_rate: tick_rate });
+	let _ = hub.send_tcp(&addr, &ControlEntity { id: id });
+}
+
+/// Prints the bytes per second that have been sent to every user during the elapsed seconds.
+fn report_bandwidth(hub: &ConnectionHub, users: &[SocketAddr], reported_bytes: &mut HashMap<SocketAddr, u64>, elapsed: f32) {
+	for addr in users {
+		let bytes_sent = match hub.bytes_sent(addr) {
+			Some(bytes_sent) => bytes_sent,
+			None => continue
+		};
+
+		let previous = reported_bytes.insert(*addr, bytes_sent).unwrap_or(0);
+		println!("Sending {:.0} bytes per second to {}.", (bytes_sent - previous) as f32 / elapsed, addr);
+
+		if let Some(rejected) = hub.rejected_datagrams(addr) {
+			if rejected.forged > 0 || rejected.replayed > 0 {
+				println!("Rejected {} forged and {} replayed datagrams claiming to be from {}.", rejected.forged, rejected.replayed, addr);
+			}
+		}
+
+		if let Some(dropped) = hub.dropped_packets(addr) {
+			if dropped > 0 {
+				println!("Dropped {} packets from {} for exceeding the rate limit.", dropped, addr);
+			}
+		}
+	}
 }
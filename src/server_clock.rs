@@ -0,0 +1,66 @@
+//! # The clock of the server
+//!
+//! Updates from the server take a different time to arrive every time, so the time they arrive at
+//! says little about when they happened. The server runs its ticks evenly spaced though, so the
+//! tick an update has been sent in tells when it happened on the server. The server clock
+//! translates that into the time of the client.
+//!
+//! The offset between both clocks is estimated from the updates that arrived the fastest. The
+//! estimate only rises slowly, so a single late update does not shift it, but it drops at once
+//! when an update arrives faster than any before.
+
+/// The fraction of the difference an update that arrived slower than expected moves the offset by.
+const OFFSET_SMOOTHING: i64 = 64;
+
+pub struct ServerClock {
+	// The time between two ticks in nanoseconds, once the tick rate is known.
+	tick_interval: Option<u64>,
+
+	// The time of the client in nanoseconds at which the server was at tick zero, as it seems.
+	offset: Option<i64>
+}
+
+impl ServerClock {
+	pub fn new() -> ServerClock {
+		ServerClock {
+			tick_interval: None,
+			offset: None
+		}
+	}
+
+	/// # Set the tick rate
+	///
+	/// Sets how many ticks the server runs per second. The offset is estimated anew from the
+	/// following updates.
+	pub fn set_tick_rate(&mut self, tick_rate: u16) {
+		self.tick_interval = match tick_rate {
+			0 => None,
+			rate => Some(1000000000 / rate as u64)
+		};
+		self.offset = None;
+	}
+
+	/// # The time of a tick
+	///
+	/// Returns the time of the client in nanoseconds that the tick corresponds to, and takes the
+	/// time the update of the tick has been received at into account for the estimate of the
+	/// offset. As long as the tick rate is unknown, that is the time of the update.
+	pub fn time(&mut self, tick: u32, received: u64) -> u64 {
+		let tick_interval = match self.tick_interval {
+			Some(tick_interval) => tick_interval,
+			None => return received
+		};
+
+		let server_time = (tick as u64 * tick_interval) as i64;
+		let sample = received as i64 - server_time;
+
+		let offset = match self.offset {
+			Some(offset) if sample >= offset => offset + (sample - offset) / OFFSET_SMOOTHING,
+			_ => sample
+		};
+		self.offset = Some(offset);
+
+		let time = server_time + offset;
+		if time > 0 { time as u64 } else { 0 }
+	}
+}
@@ -0,0 +1,94 @@
+//! # Snapshot buffer
+//!
+//! Updates from the server arrive at irregular intervals, and some never arrive at all. Showing
+//! each one as soon as it arrives makes entities jump around. Instead, the snapshots are buffered
+//! and the entity is shown a little in the past, somewhere between two snapshots that have
+//! already arrived. Only if no newer snapshot is there in time, the movement is continued for a
+//! short while with the last known velocity.
+
+use std::collections::VecDeque;
+
+use sys::Vector;
+
+/// The amount of snapshots that are kept at most. Older ones are of no use once a newer one is
+/// older than the render time, but this keeps the buffer small if the render time lags behind.
+const MAX_SNAPSHOTS: usize = 64;
+
+/// The state of an entity at a certain time.
+struct Snapshot {
+	/// The time the entity has been there, in nanoseconds.
+	time: u64,
+	pos: Vector<f32>,
+	velocity: Vector<f32>
+}
+
+pub struct SnapshotBuffer {
+	// Ordered by time, oldest first.
+	snapshots: VecDeque<Snapshot>
+}
+
+impl SnapshotBuffer {
+	pub fn new() -> SnapshotBuffer {
+		SnapshotBuffer {
+			snapshots: VecDeque::new()
+		}
+	}
+
+	/// # Add a snapshot
+	///
+	/// Adds the position and velocity the entity had at the time provided, in nanoseconds.
+	/// Snapshots that are not newer than the newest one are ignored.
+	pub fn push(&mut self, time: u64, pos: Vector<f32>, velocity: Vector<f32>) {
+		if let Some(newest) = self.snapshots.back() {
+			if time <= newest.time {
+				return;
+			}
+		}
+
+		if self.snapshots.len() >= MAX_SNAPSHOTS {
+			self.snapshots.pop_front();
+		}
+
+		self.snapshots.push_back(Snapshot {
+			time: time,
+			pos: pos,
+			velocity: velocity
+		});
+	}
+
+	/// # Sample the position
+	///
+	/// Returns the position of the entity at the render time in nanoseconds. Between two
+	/// snapshots, the position is interpolated. After the newest snapshot, it is extrapolated with
+	/// its velocity, but for no longer than the maximum extrapolation in nanoseconds. Returns None
+	/// if there has not been any snapshot yet.
+	///
+	/// Snapshots that are no longer needed for any later render time are dropped.
+	pub fn sample(&mut self, render_time: u64, max_extrapolation: u64) -> Option<Vector<f32>> {
+		// Only the newest snapshot that is not newer than the render time is needed from the past.
+		while self.snapshots.len() >= 2 && self.snapshots[1].time <= render_time {
+			self.snapshots.pop_front();
+		}
+
+		let from = match self.snapshots.front() {
+			Some(from) => from,
+			None => return None
+		};
+
+		// The render time has not caught up with the first snapshot yet.
+		if render_time <= from.time {
+			return Some(from.pos);
+		}
+
+		match self.snapshots.get(1) {
+			Some(to) => {
+				let progress = (render_time - from.time) as f32 / (to.time - from.time) as f32;
+				Some(from.pos + (to.pos - from.pos) * progress)
+			},
+			None => {
+				let extrapolation = if render_time - from.time < max_extrapolation { render_time - from.time } else { max_extrapolation };
+				Some(from.pos + from.velocity * (extrapolation as f32 / 1000000000.0))
+			}
+		}
+	}
+}
@@ -0,0 +1,519 @@
+//! # Asynchronous connection hub
+//!
+//! The counterpart of the connection hub for asynchronous code running on tokio. It speaks the
+//! same protocol and hands out the same sessions, but there is no network thread. Instead, the hub
+//! is a stream of the packets received from all users, and everything else, like accepting users,
+//! resending datagrams and keeping the users alive, happens while it is polled. It has to be
+//! polled regularly, even if the packets are not of any interest.
+//!
+//! The received packets are frames just like those the registry dispatches, and sending accepts
+//! any packet, so the same handlers work with both hubs.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, ErrorKind};
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use futures::{Future, Stream};
+use futures::future::{self, poll_fn, Either};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+use tokio::time::{self as timer, Interval, MissedTickBehavior};
+use srv::{AsyncUser, BindConfig, BindError, Connection, RateLimit, SendError};
+use srv::connection_hub::KeepAlive;
+use srv::session::{self, Greeting, MAX_DATAGRAM_SIZE, READ_SIZE, SHUTDOWN_TIMEOUT_MS};
+use packets::{DisconnectReason, Frame, Hello, Packet, RejectedDatagrams, SessionAuth, SharedKey, Welcome};
+use time;
+
+/// The time between two checks for reliable datagrams that have to be sent again.
+const RESEND_INTERVAL_MS: u64 = 20;
+
+pub struct AsyncConnectionHub {
+	listener: TcpListener,
+	udp_socket: UdpSocket,
+	// Every user is told where to send their datagrams.
+	udp_port: u16,
+	keep_alive: KeepAlive,
+	key: Option<SharedKey>,
+	rate_limit: RateLimit,
+	handshakes: Vec<AsyncHandshake>,
+	users: HashMap<SocketAddr, AsyncUser>,
+	tokens: HashMap<u64, SocketAddr>,
+
+	// Packets that have been received, but not yet taken from the stream.
+	received: VecDeque<(SocketAddr, Frame)>,
+
+	timer: Interval,
+	last_ping: u64,
+	next_session_id: u32,
+
+	// The task that has last polled the hub, which is woken when there is something to write.
+	waker: Option<Waker>
+}
+
+impl AsyncConnectionHub {
+	/// # Create an asynchronous connection hub
+	///
+	/// Opens the sockets for TCP and UDP and starts accepting users as soon as the hub is polled.
+	/// Users that do not send anything for longer than the keepalive allows are dropped.
+	///
+	/// If a key is provided, only clients that prove they know it are welcomed, and all datagrams
+	/// of their sessions are signed. Without a key, anyone speaking the protocol is welcome.
+	///
+	/// Everything a user sends is checked against the rate limit before it is handled. Whatever
+	/// exceeds it is dropped, and users who keep exceeding it are disconnected. In authenticated
+	/// sessions, datagrams that have been forged or replayed do not count against it.
+	///
+	/// Unlike the connection hub, this hub cannot simulate a bad network. Its sockets are always
+	/// used as they are.
+	///
+	/// # Failure
+	/// The sockets are registered with the tokio runtime, so this must be called from within one
+	/// that has IO and time enabled. Otherwise, or if the sockets cannot be opened, an error is
+	/// returned.
+	pub fn new(bind: BindConfig, keep_alive: KeepAlive, key: Option<SharedKey>, rate_limit: RateLimit) -> Result<AsyncConnectionHub, BindError> {
+		let (listener, udp_socket) = try!(bind.bind());
+
+		// The port the system has picked, if the config leaves it to the system.
+		let udp_port = match udp_socket.local_addr() {
+			Ok(addr) => addr.port(),
+			Err(err) => return Err(BindError::Udp(bind.udp, err))
+		};
+
+		if let Err(err) = tokio::runtime::Handle::try_current() {
+			return Err(BindError::Register(io::Error::new(ErrorKind::Other, err)));
+		}
+
+		let listener = match TcpListener::from_std(listener) {
+			Ok(listener) => listener,
+			Err(err) => return Err(BindError::Register(err))
+		};
+
+		let udp_socket = match UdpSocket::from_std(udp_socket) {
+			Ok(socket) => socket,
+			Err(err) => return Err(BindError::Register(err))
+		};
+
+		let mut timer = timer::interval(Duration::from_millis(RESEND_INTERVAL_MS));
+		timer.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+		Ok(AsyncConnectionHub {
+			listener: listener,
+			udp_socket: udp_socket,
+			udp_port: udp_port,
+			keep_alive: keep_alive,
+			key: key,
+			rate_limit: rate_limit,
+			handshakes: Vec::new(),
+			users: HashMap::new(),
+			tokens: HashMap::new(),
+			received: VecDeque::new(),
+			timer: timer,
+			last_ping: time::precise_time_ns(),
+			next_session_id: 1,
+			waker: None
+		})
+	}
+
+	/// # The connected users
+	///
+	/// Returns the addresses of all users that have completed the handshake and have not been
+	/// dropped yet.
+	pub fn user_addresses(&self) -> Vec<SocketAddr> {
+		self.users.keys().cloned().collect()
+	}
+
+	/// # The rejected datagrams of a user
+	///
+	/// Returns how many datagrams that claimed to be from the user have been forged or replayed,
+	/// or None if there is no user with the address.
+	pub fn rejected_datagrams(&self, addr: &SocketAddr) -> Option<RejectedDatagrams> {
+		self.users.get(addr).map(|user| user.session().rejected_datagrams())
+	}
+
+	/// # The packets dropped from a user
+	///
+	/// Returns how many packets of the user have been dropped for exceeding the rate limit, or
+	/// None if there is no user with the address.
+	pub fn dropped_packets(&self, addr: &SocketAddr) -> Option<u64> {
+		self.users.get(addr).map(|user| user.session().dropped_packets())
+	}
+
+	/// # The bytes sent to a user
+	///
+	/// Returns how many bytes have been sent to the user since they connected, or None if there
+	/// is no user with the address.
+	pub fn bytes_sent(&self, addr: &SocketAddr) -> Option<u64> {
+		self.users.get(addr).map(|user| user.session().bytes_sent())
+	}
+
+	/// # Send a packet using TCP
+	///
+	/// Queues the packet to be written to the stream of the user while the hub is polled. It is
+	/// never waited for, so a user who does not read cannot hold up the hub. Everything is
+	/// delivered reliably and in order, just like with the connection hub.
+	pub fn send_tcp<P: Packet>(&mut self, addr: &SocketAddr, data: &P) -> Result<usize, SendError> {
+		let queued = match self.users.get_mut(addr) {
+			Some(user) => try!(user.queue_tcp(data)),
+			None => return Err(SendError::NotConnected(*addr))
+		};
+
+		if let Some(ref waker) = self.waker {
+			waker.wake_by_ref();
+		}
+
+		Ok(queued)
+	}
+
+	/// # Send a packet using UDP
+	///
+	/// The returned future completes once the datagram has been sent. Reliable packets are sent
+	/// again while the hub is polled, until they have been acknowledged.
+	pub fn send_udp<'a, P: Packet>(&'a mut self, addr: &SocketAddr, data: &P) -> impl Future<Output = Result<usize, SendError>> + 'a {
+		let addr = *addr;
+		let (datagram, udp_address) = match self.users.get_mut(&addr).map(|user| user.session_mut()) {
+			Some(session) => match session.udp_address() {
+				Some(udp_address) => match session.udp_channel().send(data, time::precise_time_ns()) {
+					Some(datagram) => (datagram, udp_address),
+					// Queued until earlier datagrams have been acknowledged, the hub sends it then.
+					None => return Either::Right(future::ready(Ok(0)))
+				},
+				None => return Either::Right(future::ready(Err(SendError::NoUdpAddress(addr))))
+			},
+			None => return Either::Right(future::ready(Err(SendError::NotConnected(addr))))
+		};
+
+		let udp_socket = &self.udp_socket;
+		let users = &mut self.users;
+
+		Either::Left(poll_fn(move |cx| {
+			match udp_socket.poll_send_to(cx, &datagram, udp_address) {
+				Poll::Ready(Ok(size)) => {
+					if let Some(user) = users.get_mut(&addr) {
+						user.session_mut().count_sent(size);
+					}
+
+					Poll::Ready(Ok(size))
+				},
+				Poll::Ready(Err(err)) => Poll::Ready(Err(SendError::Io(err))),
+				Poll::Pending => Poll::Pending
+			}
+		}))
+	}
+
+	/// # Disconnect a user
+	///
+	/// Tells the user why they are being disconnected, and closes the connection once that has
+	/// been sent while the hub is polled. The user is dropped shortly after.
+	pub fn disconnect(&mut self, addr: &SocketAddr, reason: DisconnectReason, message: &str) -> Result<(), SendError> {
+		match self.users.get_mut(addr) {
+			Some(user) => user.disconnect(reason, message).map(|_| ()),
+			None => Err(SendError::NotConnected(*addr))
+		}
+	}
+
+	/// # Shut down
+	///
+	/// Disconnects every user for the reason provided. The returned future completes once the
+	/// users have received the disconnect, or it took too long. All sockets are closed when it
+	/// completes.
+	pub fn shutdown(mut self, reason: DisconnectReason, message: &str) -> impl Future<Output = ()> {
+		for (_, user) in self.users.iter_mut() {
+			let _ = user.disconnect(reason, message);
+		}
+
+		let mut deadline = Box::pin(timer::sleep(Duration::from_millis(SHUTDOWN_TIMEOUT_MS)));
+
+		poll_fn(move |cx| {
+			let mut open = false;
+			for (_, user) in self.users.iter_mut() {
+				let _ = user.poll_flush(cx);
+				open = open || user.is_open();
+			}
+
+			if open && deadline.as_mut().poll(cx).is_pending() {
+				return Poll::Pending;
+			}
+
+			// The listening sockets are closed along with the future.
+			self.users.clear();
+			self.tokens.clear();
+			self.handshakes.clear();
+			println!("All users have been dropped.");
+
+			Poll::Ready(())
+		})
+	}
+
+	fn accept_connections(&mut self, cx: &mut Context) {
+		loop {
+			let (stream, remote_address) = match self.listener.poll_accept(cx) {
+				Poll::Ready(Ok(accepted)) => accepted,
+				// Errors like running out of file descriptors do not go away by trying again right
+				// away. The listener is polled again with the hub.
+				Poll::Ready(Err(err)) => {
+					println!("Unable to accept connection. {}", err);
+					return;
+				},
+				Poll::Pending => return
+			};
+
+			let session_id = self.next_session_id;
+			self.next_session_id = self.next_session_id.wrapping_add(1);
+
+			self.handshakes.push(AsyncHandshake {
+				stream: stream,
+				greeting: Greeting::new(remote_address, session_id)
+			});
+		}
+	}
+
+	/// # Continue the handshakes
+	///
+	/// Reads what the streams that have not completed the handshake have to offer. Clients whose
+	/// hello is complete are either welcomed as users or rejected. If the server demands a key,
+	/// the client is challenged first, and only welcomed once it has proven to know the key.
+	fn receive_handshakes(&mut self, cx: &mut Context) {
+		let mut index = 0;
+
+		while index < self.handshakes.len() {
+			let (hello, auth) = match self.handshakes[index].receive(cx, self.key.as_ref()) {
+				Some(Ok(greeting)) => greeting,
+				Some(Err(err)) => {
+					let handshake = self.handshakes.swap_remove(index);
+					println!("Handshake with {} failed. {}", handshake.greeting.remote_address, err);
+					continue;
+				},
+				// The hello or the answer to the challenge is not complete yet.
+				None => {
+					index += 1;
+					continue;
+				}
+			};
+
+			let handshake = self.handshakes.swap_remove(index);
+			let greeting = handshake.greeting;
+			let addr = greeting.remote_address;
+
+			if let Err(reject) = session::claim_name(&self.users, &hello.name) {
+				// Like every reject, it fits into the empty send buffer of the stream.
+				let _ = handshake.stream.try_write(&Frame::new(&reject).to_bytes());
+				println!("Handshake with {} failed. {}", addr, reject.message);
+				continue;
+			}
+
+			let mut user = AsyncUser::new(handshake.stream, addr, hello.name, greeting.session_id, greeting.token, greeting.incoming, auth, self.rate_limit);
+			if let Err(err) = user.queue_tcp(&Welcome { session_id: greeting.session_id, token: greeting.token, udp_port: self.udp_port }) {
+				println!("Unable to welcome {}. {}", addr, err);
+				continue;
+			}
+
+			self.users.insert(addr, user);
+			self.tokens.insert(greeting.token, addr);
+		}
+	}
+
+	/// Rejects all handshakes that have not been completed in time.
+	fn expire_handshakes(&mut self, now: u64) {
+		let mut index = 0;
+
+		while index < self.handshakes.len() {
+			if !self.handshakes[index].greeting.is_expired(now) {
+				index += 1;
+				continue;
+			}
+
+			// Like every reject, it fits into the empty send buffer of the stream.
+			let handshake = self.handshakes.swap_remove(index);
+			let _ = handshake.stream.try_write(&Frame::new(&handshake.greeting.timeout()).to_bytes());
+			println!("Handshake with {} timed out.", handshake.greeting.remote_address);
+		}
+	}
+
+	/// Reads the frames every user has sent through TCP and queues them to be taken from the
+	/// stream.
+	fn receive_tcp(&mut self, cx: &mut Context) {
+		for (addr, user) in self.users.iter_mut() {
+			for frame in user.receive_tcp(cx) {
+				self.received.push_back((*addr, frame));
+			}
+		}
+	}
+
+	/// # Receive from UDP
+	///
+	/// Every datagram starts with the session token of the user that sent it. The rest of the
+	/// datagram is read by the UDP channel of the user, and the packets it contains are queued to
+	/// be taken from the stream.
+	fn receive_udp(&mut self, cx: &mut Context) {
+		let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+
+		loop {
+			let (size, sender) = match self.udp_socket.try_recv_from(&mut buffer) {
+				Ok(received) => received,
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+					match self.udp_socket.poll_recv_ready(cx) {
+						Poll::Ready(Ok(())) => continue,
+						Poll::Ready(Err(err)) => {
+							println!("Error reading from UDP socket. {}", err);
+							return;
+						},
+						Poll::Pending => return
+					}
+				},
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => {
+					// Some systems report datagrams that could not be delivered on the next
+					// receive, which must not stop the others from being received.
+					println!("Error reading from UDP socket. {}", err);
+					continue;
+				}
+			};
+
+			let addr = match session::route(&self.tokens, &buffer[..size]) {
+				Some(addr) => addr,
+				None => continue
+			};
+
+			let received = match self.users.get_mut(&addr).and_then(|user| session::receive_datagram(user, &buffer[..size], sender)) {
+				Some(received) => received,
+				None => continue
+			};
+
+			if let Some(ack) = received.ack {
+				let _ = self.udp_socket.try_send_to(&ack, sender);
+			}
+
+			// The user is always identified by their TCP address, regardless of the protocol.
+			for frame in received.frames {
+				self.received.push_back((addr, frame));
+			}
+		}
+	}
+
+	/// Sends the reliable datagrams of every user again, that have not been acknowledged in time.
+	/// Users whose channel has failed are disconnected.
+	fn resend_udp(&mut self, now: u64) {
+		for (addr, user) in self.users.iter_mut() {
+			let failed = {
+				let session = user.session_mut();
+				let (udp_address, datagrams) = match session.resend(now) {
+					Some(resent) => resent,
+					None => continue
+				};
+
+				for datagram in datagrams {
+					if let Ok(size) = self.udp_socket.try_send_to(&datagram, udp_address) {
+						session.count_sent(size);
+					}
+				}
+
+				session.udp_channel().is_failed()
+			};
+
+			if failed && user.disconnect(DisconnectReason::Unreachable, "Your datagrams stopped arriving.").is_ok() {
+				println!("{} stopped acknowledging datagrams and is disconnected.", addr);
+			}
+		}
+	}
+
+	/// # Keep the users alive
+	///
+	/// Removes the users that have been idle for too long or have already disconnected, and queues
+	/// a ping for all others.
+	fn keep_alive(&mut self) {
+		for (addr, _) in session::keep_alive(&mut self.users, &mut self.tokens, &self.keep_alive) {
+			println!("Dropping user: {}", addr);
+		}
+	}
+
+	/// Writes what is waiting to be sent to every user, as far as the streams accept it.
+	fn flush(&mut self, cx: &mut Context) {
+		for (_, user) in self.users.iter_mut() {
+			let _ = user.poll_flush(cx);
+		}
+	}
+}
+
+impl Stream for AsyncConnectionHub {
+	type Item = (SocketAddr, Frame);
+
+	/// # Poll the next packet
+	///
+	/// Returns the next packet received from any user, together with the address of the user. The
+	/// stream never ends; it is closed by dropping the hub.
+	fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<(SocketAddr, Frame)>> {
+		let hub = &mut *self;
+		hub.waker = Some(cx.waker().clone());
+
+		if let Some(received) = hub.received.pop_front() {
+			return Poll::Ready(Some(received));
+		}
+
+		hub.accept_connections(cx);
+		hub.receive_handshakes(cx);
+		hub.receive_tcp(cx);
+		hub.receive_udp(cx);
+
+		while let Poll::Ready(_) = hub.timer.poll_tick(cx) {
+			let now = time::precise_time_ns();
+			hub.resend_udp(now);
+			hub.expire_handshakes(now);
+
+			if now - hub.last_ping >= hub.keep_alive.ping_interval_ms * 1000000 {
+				hub.keep_alive();
+				hub.last_ping = now;
+			}
+		}
+
+		hub.flush(cx);
+
+		match hub.received.pop_front() {
+			Some(received) => Poll::Ready(Some(received)),
+			None => Poll::Pending
+		}
+	}
+}
+
+/// A stream that has been accepted, but has not completed the handshake yet.
+struct AsyncHandshake {
+	stream: TcpStream,
+	greeting: Greeting
+}
+
+impl AsyncHandshake {
+	/// Reads what the stream has to offer. Returns None as long as the handshake is not complete,
+	/// and otherwise whether the client is welcome. With a key, the client is challenged after
+	/// the hello, and the session it authenticates is returned along with the hello.
+	fn receive(&mut self, cx: &mut Context, key: Option<&SharedKey>) -> Option<Result<(Hello, Option<SessionAuth>), String>> {
+		let mut buffer = [0; READ_SIZE];
+
+		// The greeting rejects the client before it has sent too much, so the stream is read
+		// until nothing is left.
+		loop {
+			let size = match self.stream.try_read(&mut buffer) {
+				Ok(0) => return Some(Err(String::from("The client closed the connection."))),
+				Ok(size) => size,
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+					match self.stream.poll_read_ready(cx) {
+						Poll::Ready(Ok(())) => continue,
+						Poll::Ready(Err(err)) => return Some(Err(format!("{}", err))),
+						Poll::Pending => return None
+					}
+				},
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => return Some(Err(format!("{}", err)))
+			};
+
+			let stream = &self.stream;
+			let result = self.greeting.advance(&buffer[..size], key, |bytes| {
+				let _ = stream.try_write(bytes);
+			});
+
+			if result.is_some() {
+				return result;
+			}
+		}
+	}
+}
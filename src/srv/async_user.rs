@@ -0,0 +1,282 @@
+//! # Asynchronous user
+//!
+//! The counterpart of the user for the asynchronous connection hub. It keeps the same state about
+//! the connection, but the stream is driven by the task polling the hub instead of the network
+//! thread. Nothing ever blocks; whenever the stream is not ready, the task is woken once it is.
+
+use std::io::ErrorKind;
+use std::net::SocketAddr;
+use std::task::{Context, Poll};
+
+use tokio::net::TcpStream;
+use packets::{Disconnect, DisconnectReason, Frame, Packet, Ping, SessionAuth};
+use packets::frame::LENGTH_SIZE;
+use srv::{Connection, RateLimit, SendError, Session};
+use srv::rate_limit::Verdict;
+use srv::session::{MAX_OUTGOING_SIZE, READ_SIZE, READS_PER_TURN};
+use time;
+
+pub struct AsyncUser {
+	// The stream is dropped as soon as the user is closed.
+	stream: Option<TcpStream>,
+
+	// Set once the user has been disconnected. The stream is closed as soon as everything that
+	// has been sent before, including the disconnect, has been written.
+	disconnecting: bool,
+
+	// Bytes that have been read, but do not make up a complete frame yet.
+	incoming: Vec<u8>,
+
+	// Bytes that have been sent, but not yet accepted by the stream.
+	outgoing: Vec<u8>,
+
+	session: Session
+}
+
+impl AsyncUser {
+	/// # New asynchronous user
+	///
+	/// Creates a new user from the stream provided, on which the handshake must already have been
+	/// completed. Anything that has been read from the stream after the handshake is passed along,
+	/// so it is not lost. If the user has authenticated, their datagrams are checked with the
+	/// session provided. What the user sends is limited by the rate limit provided.
+	pub fn new(stream: TcpStream, remote_address: SocketAddr, name: String, session_id: u32, token: u64, incoming: Vec<u8>, auth: Option<SessionAuth>, rate_limit: RateLimit) -> AsyncUser {
+		AsyncUser {
+			stream: Some(stream),
+			disconnecting: false,
+			incoming: incoming,
+			outgoing: Vec::new(),
+			session: Session::new(remote_address, name, session_id, token, auth, rate_limit)
+		}
+	}
+
+	/// # Receive from TCP
+	///
+	/// Reads what the stream has to offer right now, and returns the frames that are complete.
+	/// Once more data arrives, the task of the context is woken. Pongs are part of the connection
+	/// itself and never leave the user. Frames that exceed the rate limit are dropped.
+	///
+	/// No more than a few reads are done at once, so a user cannot hold up the others by sending
+	/// a lot. If something may be left, the task is woken right away to read the rest.
+	pub fn receive_tcp(&mut self, cx: &mut Context) -> Vec<Frame> {
+		let mut buffer = [0; READ_SIZE];
+		let remote_address = self.session.remote_address();
+		let mut reads = 0;
+
+		loop {
+			if reads == READS_PER_TURN {
+				cx.waker().wake_by_ref();
+				break;
+			}
+			reads += 1;
+
+			let result = match self.stream {
+				Some(ref stream) => stream.try_read(&mut buffer),
+				None => break
+			};
+
+			match result {
+				Ok(0) => {
+					println!("{} disconnected.", remote_address);
+					self.close();
+				},
+				Ok(size) => self.incoming.extend(buffer[..size].iter().cloned()),
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+					let ready = match self.stream {
+						Some(ref stream) => stream.poll_read_ready(cx),
+						None => break
+					};
+
+					match ready {
+						Poll::Ready(Ok(())) => continue,
+						Poll::Ready(Err(err)) => {
+							println!("{} has been closed due to: {}", remote_address, err);
+							self.close();
+						},
+						Poll::Pending => break
+					}
+				},
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => {
+					println!("{} has been closed due to: {}", remote_address, err);
+					self.close();
+				}
+			}
+		}
+
+		let mut frames = Vec::new();
+		loop {
+			let frame = match Frame::take_from(&mut self.incoming) {
+				Ok(Some(frame)) => frame,
+				Ok(None) => break,
+				Err(err) => {
+					// Once a frame could not be read, the stream cannot be trusted any longer.
+					println!("{} has been closed due to: {}", remote_address, err);
+					self.close();
+					break;
+				}
+			};
+
+			self.session.mark_seen();
+
+			if !self.admit(LENGTH_SIZE + 1 + frame.data.len()) {
+				continue;
+			}
+
+			if let Some(frame) = self.session.receive_frame(frame) {
+				frames.push(frame);
+			}
+		}
+
+		frames
+	}
+
+	/// # Admit received traffic
+	///
+	/// Checks a packet of the size provided against the rate limit of the user, and returns
+	/// whether it should be handled. A user who keeps exceeding the rate limit is disconnected,
+	/// and nothing they send is handled afterwards.
+	pub fn admit(&mut self, size: usize) -> bool {
+		if self.disconnecting {
+			return false;
+		}
+
+		match self.session.check(size) {
+			Verdict::Accept => true,
+			Verdict::Violation | Verdict::Drop => false,
+			Verdict::Disconnect => {
+				let _ = self.disconnect(DisconnectReason::Flooding, "You sent more than the server allows.");
+				false
+			}
+		}
+	}
+
+	/// # Queue a packet for TCP
+	///
+	/// Appends the packet to the data waiting to be sent, without writing anything yet. It is
+	/// written by the next flush.
+	pub fn queue_tcp<P: Packet>(&mut self, data: &P) -> Result<usize, SendError> {
+		let remote_address = self.session.remote_address();
+		if self.stream.is_none() || self.disconnecting {
+			return Err(SendError::Closed(remote_address));
+		}
+
+		let bytes = Frame::new(data).to_bytes();
+		if self.outgoing.len() + bytes.len() > MAX_OUTGOING_SIZE {
+			println!("{} does not receive fast enough and has been closed.", remote_address);
+			self.close();
+
+			return Err(SendError::Overflow(remote_address));
+		}
+
+		self.outgoing.extend(bytes.iter().cloned());
+		self.session.count_sent(bytes.len());
+
+		Ok(bytes.len())
+	}
+
+	/// # Disconnect the user
+	///
+	/// Queues the reason the user is disconnected for. Once it has been written, the stream is
+	/// closed. Nothing can be sent to the user afterwards.
+	pub fn disconnect(&mut self, reason: DisconnectReason, message: &str) -> Result<usize, SendError> {
+		let queued = self.queue_tcp(&Disconnect {
+			reason: reason,
+			message: String::from(message)
+		});
+
+		self.disconnecting = true;
+		queued
+	}
+
+	/// # Flush the stream
+	///
+	/// Writes as much of the data waiting to be sent as the stream accepts. Returns Pending if
+	/// something is left, in which case the task of the context is woken once the stream accepts
+	/// more.
+	pub fn poll_flush(&mut self, cx: &mut Context) -> Poll<Result<(), SendError>> {
+		let remote_address = self.session.remote_address();
+
+		while !self.outgoing.is_empty() {
+			let result = match self.stream {
+				Some(ref stream) => stream.try_write(&self.outgoing),
+				None => return Poll::Ready(Err(SendError::Closed(remote_address)))
+			};
+
+			match result {
+				Ok(0) => self.close(),
+				Ok(size) => {
+					self.outgoing.drain(..size);
+				},
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => {
+					let ready = match self.stream {
+						Some(ref stream) => stream.poll_write_ready(cx),
+						None => continue
+					};
+
+					match ready {
+						Poll::Ready(Ok(())) => continue,
+						Poll::Ready(Err(err)) => {
+							println!("{} has been closed due to: {}", remote_address, err);
+							self.close();
+						},
+						Poll::Pending => return Poll::Pending
+					}
+				},
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => {
+					println!("{} has been closed due to: {}", remote_address, err);
+					self.close();
+				}
+			}
+		}
+
+		if self.disconnecting {
+			self.close();
+		}
+
+		Poll::Ready(Ok(()))
+	}
+
+	/// # Ping the user
+	///
+	/// Queues a ping with the current time. Once the user answers, the round trip time is updated.
+	pub fn ping(&mut self) -> Result<usize, SendError> {
+		self.queue_tcp(&Ping { timestamp: time::precise_time_ns() })
+	}
+
+	pub fn is_open(&self) -> bool {
+		self.stream.is_some()
+	}
+
+	/// # Close the user
+	///
+	/// Drops the stream, which closes the connection. Anything that has not been written yet is
+	/// lost.
+	pub fn close(&mut self) {
+		self.stream = None;
+		self.outgoing.clear();
+	}
+}
+
+impl Connection for AsyncUser {
+	fn session(&self) -> &Session {
+		&self.session
+	}
+
+	fn session_mut(&mut self) -> &mut Session {
+		&mut self.session
+	}
+
+	fn admit(&mut self, size: usize) -> bool {
+		AsyncUser::admit(self, size)
+	}
+
+	fn ping(&mut self) -> Result<usize, SendError> {
+		AsyncUser::ping(self)
+	}
+
+	fn is_open(&self) -> bool {
+		self.stream.is_some()
+	}
+}
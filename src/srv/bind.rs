@@ -0,0 +1,145 @@
+//! # Binding the sockets
+//!
+//! Where the server can be reached. TCP and UDP are bound separately, so they may use different
+//! ports or even different interfaces. Binding the unspecified IPv6 address `[::]` accepts IPv4
+//! clients aswell, regardless of what the system would do by default. On systems without IPv6, or
+//! that cannot accept both on the same socket, it falls back to the unspecified IPv4 address.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::{self, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use socket2::{Domain, Protocol, Socket, Type};
+
+/// The amount of connections the system may hold for the server before they are accepted.
+const LISTEN_BACKLOG: i32 = 128;
+
+#[derive(Clone, Copy, Debug)]
+pub struct BindConfig {
+	/// The address the server accepts connections on.
+	pub tcp: SocketAddr,
+
+	/// The address the server sends and receives datagrams on.
+	pub udp: SocketAddr
+}
+
+impl BindConfig {
+	/// # Bind to an address
+	///
+	/// Uses the same address for TCP and UDP.
+	pub fn new(address: SocketAddr) -> BindConfig {
+		BindConfig {
+			tcp: address,
+			udp: address
+		}
+	}
+
+	/// # Bind to all interfaces
+	///
+	/// Listens on the port of every interface, both IPv4 and IPv6, for TCP and UDP. Where IPv6 is
+	/// not available, only IPv4 is used.
+	pub fn any(port: u16) -> BindConfig {
+		BindConfig::new(SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port))
+	}
+
+	/// # Use another UDP port
+	///
+	/// Keeps the interface, but sends and receives datagrams on a different port than TCP.
+	pub fn with_udp_port(mut self, port: u16) -> BindConfig {
+		self.udp.set_port(port);
+		self
+	}
+
+	/// # Bind the sockets
+	///
+	/// Opens the TCP listener and the UDP socket. Both are non-blocking. IPv6 sockets accept IPv4
+	/// aswell, as far as the system supports it.
+	pub fn bind(&self) -> Result<(net::TcpListener, net::UdpSocket), BindError> {
+		let listener = match open(self.tcp, Type::STREAM, Protocol::TCP) {
+			Ok(socket) => socket,
+			Err(err) => return Err(BindError::Tcp(self.tcp, err))
+		};
+
+		if let Err(err) = listener.listen(LISTEN_BACKLOG) {
+			return Err(BindError::Tcp(self.tcp, err));
+		}
+
+		let udp_socket = match open(self.udp, Type::DGRAM, Protocol::UDP) {
+			Ok(socket) => socket,
+			Err(err) => return Err(BindError::Udp(self.udp, err))
+		};
+
+		Ok((listener.into(), udp_socket.into()))
+	}
+}
+
+/// Creates a non-blocking socket and binds it to the address. If the address is `[::]` and the
+/// system cannot provide a socket for both IPv4 and IPv6, `0.0.0.0` is bound instead, so that at
+/// least IPv4 clients can connect.
+fn open(address: SocketAddr, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+	let (socket, address) = match create(address, kind, protocol) {
+		Ok(socket) => (socket, address),
+		Err(ref err) if address.ip() == IpAddr::V6(Ipv6Addr::UNSPECIFIED) => {
+			let fallback = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), address.port());
+			println!("Unable to use IPv6 on {}, using {} instead. {}", address, fallback, err);
+
+			(try!(create(fallback, kind, protocol)), fallback)
+		},
+		Err(err) => return Err(err)
+	};
+
+	// A server that has just been restarted should not have to wait for the connections of the
+	// old one to time out.
+	if kind == Type::STREAM {
+		try!(socket.set_reuse_address(true));
+	}
+
+	try!(socket.set_nonblocking(true));
+	try!(socket.bind(&address.into()));
+
+	Ok(socket)
+}
+
+/// Creates a socket for the address. IPv6 sockets are made to accept IPv4 aswell.
+fn create(address: SocketAddr, kind: Type, protocol: Protocol) -> io::Result<Socket> {
+	let socket = try!(Socket::new(Domain::for_address(address), kind, Some(protocol)));
+
+	if address.is_ipv6() {
+		try!(socket.set_only_v6(false));
+	}
+
+	Ok(socket)
+}
+
+#[derive(Debug)]
+pub enum BindError {
+	/// The TCP listener could not be opened on the address.
+	Tcp(SocketAddr, io::Error),
+
+	/// The UDP socket could not be opened on the address.
+	Udp(SocketAddr, io::Error),
+
+	/// The sockets have been opened, but could not be prepared to be polled.
+	Register(io::Error)
+}
+
+impl Display for BindError {
+	fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+		match self {
+			&BindError::Tcp(address, ref err) => write!(formatter, "Unable to listen on {} using Tcp. {}", address, err),
+			&BindError::Udp(address, ref err) => write!(formatter, "Unable to listen on {} using Udp. {}", address, err),
+			&BindError::Register(ref err) => write!(formatter, "Unable to poll the sockets. {}", err)
+		}
+	}
+}
+
+impl Error for BindError {
+	fn description(&self) -> &str {
+		match self {
+			&BindError::Tcp(..) => "unable to bind tcp",
+			&BindError::Udp(..) => "unable to bind udp",
+			&BindError::Register(_) => "unable to poll the sockets"
+		}
+	}
+}
@@ -0,0 +1,168 @@
+//! # Chat
+//!
+//! Users talk to everyone, to the members of a group or to a single user by name. What they send
+//! arrives on the network thread and is queued, so the game loop can relay it along with
+//! everything else it sends each tick. Users always read their own messages as they are relayed,
+//! which tells them where they ended up.
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+use packets::{ChatChannel, ChatMessage, JoinGroup, LeaveGroup, PacketRegistry, SendChat, MAX_CHAT_LENGTH};
+use srv::ConnectionHub;
+
+/// The longest name a group may have, in characters.
+const MAX_GROUP_LENGTH: usize = 32;
+
+/// Something a user asked the chat for.
+enum Request {
+	Send(SendChat),
+	Join(String),
+	Leave(String)
+}
+
+/// The requests of all users that arrived since the last tick, in the order they arrived in.
+type SafeRequestList = Arc<Mutex<Vec<(SocketAddr, Request)>>>;
+
+pub struct Chat {
+	requests: SafeRequestList
+}
+
+impl Chat {
+	pub fn new() -> Chat {
+		Chat {
+			requests: Arc::new(Mutex::new(Vec::new()))
+		}
+	}
+
+	/// # Register the chat packets
+	///
+	/// Registers handlers for every packet the chat understands, which queue them until the next
+	/// call to relay().
+	///
+	/// # Failure
+	/// If any of the packets has already been registered, an error is returned.
+	pub fn register(&self, registry: &mut PacketRegistry<SocketAddr>) -> Result<(), String> {
+		let requests = self.requests.clone();
+		try!(registry.register::<SendChat, _>(move |addr, send_chat| {
+			requests.lock().unwrap().push((*addr, Request::Send(send_chat)));
+		}));
+
+		let requests = self.requests.clone();
+		try!(registry.register::<JoinGroup, _>(move |addr, join_group| {
+			requests.lock().unwrap().push((*addr, Request::Join(join_group.group)));
+		}));
+
+		let requests = self.requests.clone();
+		registry.register::<LeaveGroup, _>(move |addr, leave_group| {
+			requests.lock().unwrap().push((*addr, Request::Leave(leave_group.group)));
+		})
+	}
+
+	/// # Relay the chat
+	///
+	/// Answers every request that has been queued since the last call. Messages are sent to
+	/// everyone who should read them, and users who asked for something that cannot be done are
+	/// told why by the server.
+	pub fn relay(&self, hub: &mut ConnectionHub) {
+		let requests: Vec<(SocketAddr, Request)> = self.requests.lock().unwrap().drain(..).collect();
+
+		for (addr, request) in requests {
+			// The user may have been dropped since the request arrived.
+			let name = match hub.user_name(&addr) {
+				Some(name) => name,
+				None => continue
+			};
+
+			match request {
+				Request::Send(send_chat) => send(hub, addr, name, send_chat),
+				Request::Join(group) => join(hub, addr, &group),
+				Request::Leave(group) => leave(hub, addr, &group)
+			}
+		}
+	}
+}
+
+fn send(hub: &mut ConnectionHub, addr: SocketAddr, name: String, send_chat: SendChat) {
+	// The text is shown as a single line, so anything that would break it is dropped.
+	let text: String = send_chat.text.chars().filter(|c| !c.is_control()).collect();
+	let text = String::from(text.trim());
+
+	if text.is_empty() {
+		return;
+	}
+
+	if text.chars().count() > MAX_CHAT_LENGTH {
+		tell(hub, &addr, format!("Messages may not be longer than {} characters.", MAX_CHAT_LENGTH));
+		return;
+	}
+
+	// The name has been checked in the handshake, so unlike the text, it cannot break the line.
+	let message = ChatMessage {
+		sender: name,
+		channel: send_chat.channel,
+		text: text
+	};
+
+	match message.channel.clone() {
+		ChatChannel::Global => {
+			println!("[Global] {}: {}", message.sender, message.text);
+			hub.broadcast_tcp(&message);
+		},
+		ChatChannel::Group(group) => {
+			if !hub.group_members(&group).contains(&addr) {
+				tell(hub, &addr, format!("You are not in the group '{}'.", group));
+				return;
+			}
+
+			println!("[{}] {}: {}", group, message.sender, message.text);
+			hub.send_group_tcp(&group, &message);
+		},
+		ChatChannel::Whisper(recipient) => {
+			let recipient_address = match hub.user_named(&recipient) {
+				Some(recipient_address) => recipient_address,
+				None => {
+					tell(hub, &addr, format!("Nobody called '{}' is connected.", recipient));
+					return;
+				}
+			};
+
+			println!("[{} -> {}] {}", message.sender, recipient, message.text);
+			let _ = hub.send_tcp(&recipient_address, &message);
+
+			if recipient_address != addr {
+				let _ = hub.send_tcp(&addr, &message);
+			}
+		},
+		ChatChannel::System => tell(hub, &addr, String::from("Only the server can send system messages."))
+	}
+}
+
+fn join(hub: &mut ConnectionHub, addr: SocketAddr, group: &str) {
+	if group.is_empty() || group.chars().count() > MAX_GROUP_LENGTH || group.chars().any(|c| c.is_control() || c.is_whitespace()) {
+		tell(hub, &addr, format!("Group names must be 1 to {} characters long, without spaces.", MAX_GROUP_LENGTH));
+		return;
+	}
+
+	if hub.join_group(&addr, group).is_ok() {
+		tell(hub, &addr, format!("You joined the group '{}'.", group));
+	}
+}
+
+fn leave(hub: &mut ConnectionHub, addr: SocketAddr, group: &str) {
+	if hub.leave_group(&addr, group) {
+		tell(hub, &addr, format!("You left the group '{}'.", group));
+	}
+	else {
+		tell(hub, &addr, format!("You are not in the group '{}'.", group));
+	}
+}
+
+/// Sends a message of the server to a single user.
+fn tell(hub: &mut ConnectionHub, addr: &SocketAddr, text: String) {
+	let _ = hub.send_tcp(addr, &ChatMessage {
+		sender: String::new(),
+		channel: ChatChannel::System,
+		text: text
+	});
+}
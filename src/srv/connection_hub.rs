@@ -1,154 +1,1014 @@
 /*
  * The connection hub class is there to manage incoming connections, aswell as establishing new
  * connections. It is the central hub to communicate with specific clients.
+ *
+ * All sockets are non-blocking and handled by a single network thread, which waits for any of them
+ * to become ready. There is no thread per user, so dropping a user never has to wait for anything.
+ *
+ * Shutting the hub down stops the network thread, tells every user why they are disconnected and
+ * closes all sockets. Dropping the hub does the same, in case it has not been shut down before.
  */
 
-use std::net::{TcpListener, UdpSocket, SocketAddr, ToSocketAddrs};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{self, SocketAddr, Shutdown};
 
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
 
-use std::collections::HashMap;
-use srv::User;
-use packets::{Packet, SpawnEntity};
+use std::collections::{HashMap, HashSet, VecDeque};
+use mio::{Events, Interest, Poll, Token};
+use mio::net::{TcpListener, TcpStream, UdpSocket};
+use srv::{BindConfig, BindError, Connection, NetworkConditions, NetworkSimulator, RateLimit, SendError, User};
+use srv::session::{self, Greeting, MAX_DATAGRAM_SIZE, READ_SIZE, SHUTDOWN_TIMEOUT_MS};
+use packets::{Frame, Packet, PacketRegistry, Hello, Welcome, Reject, RejectReason, DisconnectReason, MAX_NAME_LENGTH, PROTOCOL_VERSION};
+use packets::{RejectedDatagrams, SessionAuth, SharedKey};
+use time;
+
+/// The longest time the network thread waits for a socket to become ready. Reliable datagrams are
+/// checked for resending, and the hub for having been dropped, at least this often.
+const POLL_INTERVAL_MS: u64 = 20;
+
+/// The amount of socket events that are handled at once.
+const MAX_EVENTS: usize = 1024;
+
+const LISTENER: Token = Token(0);
+const UDP: Token = Token(1);
+
+/// The poll token of the first stream. Every stream gets a token of its own, counting up.
+const FIRST_STREAM: usize = 2;
 
 type SafeUserMap = Arc<Mutex<HashMap<SocketAddr, User>>>;
 
+/// Maps the session tokens to the address of the user they have been handed out to. When both
+/// maps are locked, the user map must be locked first.
+type SafeTokenMap = Arc<Mutex<HashMap<u64, SocketAddr>>>;
+
+/// The simulated network the datagrams the hub sends go through, which both the hub and the
+/// network thread send on.
+type SafeSimulator = Arc<Mutex<NetworkSimulator>>;
+
+/// The registry all received packets are dispatched with. The context is the address of the user
+/// the packet has been received from.
+pub type SafeRegistry = Arc<PacketRegistry<SocketAddr>>;
+
+/// # Keepalive settings
+///
+/// Users are pinged regularly, and dropped when nothing has been received from them for too long.
+/// This is the only way to notice connections that broke without being closed.
+#[derive(Clone, Copy)]
+pub struct KeepAlive {
+	/// The time between two pings sent to every user, in milliseconds.
+	pub ping_interval_ms: u64,
+
+	/// The time in milliseconds after which a user that has not sent anything is dropped. It
+	/// should be a multiple of the ping interval, so a few lost pings are not fatal.
+	pub idle_timeout_ms: u64
+}
+
+impl Default for KeepAlive {
+	fn default() -> KeepAlive {
+		KeepAlive {
+			ping_interval_ms: 1000,
+			idle_timeout_ms: 10000
+		}
+	}
+}
+
+/// # Greet a new connection
+///
+/// Checks that the first frame of a client is a hello in the same protocol version, with a name
+/// others can tell and whisper to. If it is not, the reject the client should be sent is returned.
+/// Whether the name is taken already can only be told once the client is added as a user. Whoever accepted the connection is
+/// responsible for sending it, or the welcome if the client is accepted.
+pub fn greet(frame: &Frame) -> Result<Hello, Reject> {
+	if frame.id != Hello::id() {
+		return Err(Reject {
+			reason: RejectReason::InvalidHandshake,
+			message: format!("Expected hello, but received packet {}.", frame.id)
+		});
+	}
+
+	// The version is checked before the rest is decoded, since the hello of a client with a
+	// different version may not even be readable.
+	let version = match Hello::peek_version(&frame.data) {
+		Ok(version) => version,
+		Err(err) => return Err(Reject {
+			reason: RejectReason::InvalidHandshake,
+			message: format!("Invalid hello. {}", err)
+		})
+	};
+
+	if version != PROTOCOL_VERSION {
+		return Err(Reject {
+			reason: RejectReason::VersionMismatch,
+			message: format!("Server speaks protocol version {}, but the client speaks version {}.", PROTOCOL_VERSION, version)
+		});
+	}
+
+	let hello = match frame.decode::<Hello>() {
+		Ok(hello) => hello,
+		Err(err) => return Err(Reject {
+			reason: RejectReason::InvalidHandshake,
+			message: format!("Invalid hello. {}", err)
+		})
+	};
+
+	let name = &hello.name;
+	if name.is_empty() || name.chars().count() > MAX_NAME_LENGTH || name.chars().any(|c| c.is_control() || c.is_whitespace()) {
+		return Err(Reject {
+			reason: RejectReason::InvalidName,
+			message: format!("Names must be 1 to {} characters long, without spaces.", MAX_NAME_LENGTH)
+		});
+	}
+
+	Ok(hello)
+}
+
 pub struct ConnectionHub {
-	listener: TcpListener,
-	udp_socket: UdpSocket,
-	users: SafeUserMap
+	// The same socket the network thread receives from. Datagrams are sent right away, without
+	// going through the network thread.
+	udp_socket: net::UdpSocket,
+	// If the network is simulated, datagrams are sent through the simulator instead.
+	outgoing: Option<SafeSimulator>,
+	users: SafeUserMap,
+	tokens: SafeTokenMap,
+	running: Arc<AtomicBool>,
+	// The network thread hands the sockets back once it has been stopped, so they can be closed
+	// properly.
+	network_thread: Option<JoinHandle<Network>>
 }
 
 impl ConnectionHub {
-	pub fn new(port: u16) -> Result<ConnectionHub, String> {
-		// Try to bind the server to the port specified.
-		let listener = match TcpListener::bind(("127.0.0.1", port)) {
-			Ok(listener) => listener,
-			Err(err) => return Err(format!("Unable to open port on Tcp. {}", err))
+	/// # Create a connection hub
+	///
+	/// Opens the sockets for TCP and UDP and starts accepting users. Every packet received from a
+	/// user, regardless of the protocol, is handled by the registry provided. Users that do not
+	/// send anything for longer than the keepalive allows are dropped automatically.
+	///
+	/// The registry is called from the network thread, so handlers should return quickly.
+	///
+	/// If a key is provided, only clients that prove they know it are welcomed, and all datagrams
+	/// of their sessions are signed. Without a key, anyone speaking the protocol is welcome.
+	///
+	/// Everything a user sends is checked against the rate limit before it is handled. Whatever
+	/// exceeds it is dropped, and users who keep exceeding it are disconnected. In authenticated
+	/// sessions, datagrams that have been forged or replayed do not count against it.
+	///
+	/// With network conditions, every datagram goes through a simulated network on its way to and
+	/// from the socket. Received and sent datagrams each take a network of their own, seeded
+	/// differently, so both directions suffer independently. The streams of the users are only
+	/// delayed by the latency, in both directions, since TCP neither loses nor reorders anything.
+	/// The handshake is not delayed, it is over before the stream belongs to a user.
+	pub fn new(bind: BindConfig, registry: PacketRegistry<SocketAddr>, keep_alive: KeepAlive, key: Option<SharedKey>, rate_limit: RateLimit, conditions: Option<NetworkConditions>) -> Result<ConnectionHub, BindError> {
+		let (listener, udp_socket) = try!(bind.bind());
+
+		// The port the system has picked, if the config leaves it to the system.
+		let udp_port = match udp_socket.local_addr() {
+			Ok(addr) => addr.port(),
+			Err(err) => return Err(BindError::Udp(bind.udp, err))
 		};
 
-		let udp_socket = match UdpSocket::bind(("127.0.0.1", port)) {
+		// The network thread receives from its own handle of the socket.
+		let udp_socket_clone = match udp_socket.try_clone() {
 			Ok(socket) => socket,
-			Err(err) => return Err(format!("Unable to open port on Udp. {}", err))
+			Err(err) => return Err(BindError::Udp(bind.udp, err))
 		};
 
-		let connection_hub = ConnectionHub {
-			listener: listener,
-			udp_socket: udp_socket,
-			users: Arc::new(Mutex::new(HashMap::new()))
+		let poll = match Poll::new() {
+			Ok(poll) => poll,
+			Err(err) => return Err(BindError::Register(err))
 		};
 
-		let listener_clone = connection_hub.listener.try_clone().unwrap();
-		let users_clone = connection_hub.users.clone();
-		thread::spawn(move || {
-			ConnectionHub::accept_connections(listener_clone, users_clone);
-		});
+		let users: SafeUserMap = Arc::new(Mutex::new(HashMap::new()));
+		let tokens: SafeTokenMap = Arc::new(Mutex::new(HashMap::new()));
+		let running = Arc::new(AtomicBool::new(true));
+
+		let outgoing = conditions.map(|conditions| Arc::new(Mutex::new(NetworkSimulator::new(conditions))));
+		let incoming = conditions.map(|conditions| NetworkSimulator::new(NetworkConditions { seed: !conditions.seed, ..conditions }));
 
-		let udp_socket_clone = connection_hub.udp_socket.try_clone().unwrap();
-		let users_clone = connection_hub.users.clone();
-		thread::spawn(move || {
-			ConnectionHub::receive_udp(udp_socket_clone, users_clone);
+		let mut network = Network {
+			poll: poll,
+			listener: TcpListener::from_std(listener),
+			udp_socket: UdpSocket::from_std(udp_socket_clone),
+			udp_port: udp_port,
+			outgoing: outgoing.clone(),
+			incoming: incoming,
+			latency: conditions.map_or(0, |conditions| conditions.latency_ms * 1000000),
+			held: VecDeque::new(),
+			users: users.clone(),
+			tokens: tokens.clone(),
+			registry: Arc::new(registry),
+			keep_alive: keep_alive,
+			key: key,
+			rate_limit: rate_limit,
+			running: running.clone(),
+			handshakes: HashMap::new(),
+			streams: HashMap::new(),
+			unread: HashSet::new(),
+			next_stream: FIRST_STREAM,
+			next_session_id: 1
+		};
+
+		try!(network.register());
+
+		let network_thread = thread::spawn(move || {
+			network.run();
+			network
 		});
 
-		Ok(connection_hub)
+		Ok(ConnectionHub {
+			udp_socket: udp_socket,
+			outgoing: outgoing,
+			users: users,
+			tokens: tokens,
+			running: running,
+			network_thread: Some(network_thread)
+		})
+	}
+
+	/// # The connected users
+	///
+	/// Returns the addresses of all users that have completed the handshake and have not been
+	/// dropped yet.
+	pub fn user_addresses(&self) -> Vec<SocketAddr> {
+		self.users.lock().unwrap().keys().cloned().collect()
+	}
+
+	/// # The name of a user
+	///
+	/// Returns the name the user introduced themselves with, or None if there is no user with the
+	/// address.
+	pub fn user_name(&self, addr: &SocketAddr) -> Option<String> {
+		self.users.lock().unwrap().get(addr).map(|user| String::from(user.session().name()))
+	}
+
+	/// # Find a user by name
+	///
+	/// Returns the address of the user with the name provided, or None if nobody is called that.
+	/// Names are unique regardless of case, so there is never more than one.
+	pub fn user_named(&self, name: &str) -> Option<SocketAddr> {
+		session::find_named(&*self.users.lock().unwrap(), name)
+	}
+
+	/// # The datagrams rejected from a user
+	///
+	/// Returns how many datagrams that claimed to be from the user have been forged or replayed,
+	/// or None if there is no user with the address.
+	pub fn rejected_datagrams(&self, addr: &SocketAddr) -> Option<RejectedDatagrams> {
+		self.users.lock().unwrap().get(addr).map(|user| user.session().rejected_datagrams())
+	}
+
+	/// # The packets dropped from a user
+	///
+	/// Returns how many packets of the user have been dropped for exceeding the rate limit, or
+	/// None if there is no user with the address.
+	pub fn dropped_packets(&self, addr: &SocketAddr) -> Option<u64> {
+		self.users.lock().unwrap().get(addr).map(|user| user.session().dropped_packets())
+	}
+
+	/// # The bytes sent to a user
+	///
+	/// Returns how many bytes have been sent to the user since they connected, or None if there
+	/// is no user with the address.
+	pub fn bytes_sent(&self, addr: &SocketAddr) -> Option<u64> {
+		self.users.lock().unwrap().get(addr).map(|user| user.session().bytes_sent())
 	}
 
-	fn accept_connections(listener: TcpListener, users: SafeUserMap) {
-		for stream in listener.incoming() {
-			let user = User::new(stream.unwrap());
+	/// # Send a packet using TCP
+	///
+	/// This is slower that send_udp(), however one can assume that all packets are received intact
+	/// and the order is precisely as it was before. The packet is never waited for. What the
+	/// stream does not accept right away is sent by the network thread later.
+	pub fn send_tcp<P: Packet>(&mut self, addr: &SocketAddr, data: &P) -> Result<usize, SendError> {
+		let mut user_map = self.users.lock().unwrap();
 
-			let mut user_map = users.lock().unwrap();
-			user_map.insert(user.remote_address(), user);
+		// The user may have been dropped since the address has been looked up.
+		match user_map.get_mut(addr) {
+			Some(user) => user.send_tcp(data),
+			None => Err(SendError::NotConnected(*addr))
 		}
+	}
 
-		drop(listener);
+	/// # Send a packet using UDP
+	///
+	/// This is useful for packets that ought to arrive fast. Whether the packet may be lost or
+	/// arrive out of order depends on the delivery of the packet type. Reliable packets are sent
+	/// again until they have been acknowledged, without holding up other packets like TCP would.
+	pub fn send_udp<P: Packet>(&mut self, addr: &SocketAddr, data: &P) -> Result<usize, SendError> {
+		let mut user_map = self.users.lock().unwrap();
+
+		match user_map.get_mut(addr) {
+			Some(user) => send_datagram(&self.udp_socket, &self.outgoing, user, data),
+			None => Err(SendError::NotConnected(*addr))
+		}
 	}
 
-	fn receive_udp(udp_socket: UdpSocket, users: SafeUserMap) {
-		let mut id: [u8; 1] = [0];
+	/// # Broadcast a packet using TCP
+	///
+	/// Sends the packet to every user. Returns the amount of users it has been sent to, which
+	/// leaves out the users whose stream has failed.
+	pub fn broadcast_tcp<P: Packet>(&mut self, data: &P) -> usize {
+		self.send_tcp_where(data, |_, _| true)
+	}
 
-		loop {
-			let sender = match udp_socket.recv_from(&mut id) {
-				Ok((size, sender)) => {
-					// When the socket is closed, the last received size should be 0.
-					if size == 0 {
-						break;
+	/// # Broadcast a packet using UDP
+	///
+	/// Sends the packet to every user that can be reached using UDP. Returns the amount of users
+	/// it has been sent to.
+	pub fn broadcast_udp<P: Packet>(&mut self, data: &P) -> usize {
+		self.send_udp_where(data, |_, _| true)
+	}
+
+	/// # Broadcast a packet to everyone else using TCP
+	///
+	/// Sends the packet to every user but the one provided, which is usually the user it
+	/// originates from.
+	pub fn broadcast_tcp_except<P: Packet>(&mut self, except: &SocketAddr, data: &P) -> usize {
+		self.send_tcp_where(data, |addr, _| addr != except)
+	}
+
+	/// # Broadcast a packet to everyone else using UDP
+	///
+	/// Sends the packet to every user but the one provided, which is usually the user it
+	/// originates from.
+	pub fn broadcast_udp_except<P: Packet>(&mut self, except: &SocketAddr, data: &P) -> usize {
+		self.send_udp_where(data, |addr, _| addr != except)
+	}
+
+	/// # Join a group
+	///
+	/// Adds the user to the group with the name provided. Groups do not have to be created, they
+	/// exist as long as they have members. A user may be in any amount of groups at once, and
+	/// leaves all of them when they disconnect.
+	pub fn join_group(&mut self, addr: &SocketAddr, group: &str) -> Result<(), String> {
+		match self.users.lock().unwrap().get_mut(addr) {
+			Some(user) => {
+				user.join_group(group);
+				Ok(())
+			},
+			None => Err(format!("A user with address {} is not connected.", addr))
+		}
+	}
+
+	/// # Leave a group
+	///
+	/// Removes the user from the group. Returns whether the user has been a member of it.
+	pub fn leave_group(&mut self, addr: &SocketAddr, group: &str) -> bool {
+		match self.users.lock().unwrap().get_mut(addr) {
+			Some(user) => user.leave_group(group),
+			None => false
+		}
+	}
+
+	/// # The members of a group
+	///
+	/// Returns the addresses of all users that are currently in the group.
+	pub fn group_members(&self, group: &str) -> Vec<SocketAddr> {
+		self.users.lock().unwrap().iter()
+			.filter(|&(_, user)| user.is_in_group(group))
+			.map(|(addr, _)| *addr)
+			.collect()
+	}
+
+	/// # Send a packet to a group using TCP
+	///
+	/// Sends the packet to every member of the group. Returns the amount of users it has been
+	/// sent to, which is 0 if the group does not have any members.
+	pub fn send_group_tcp<P: Packet>(&mut self, group: &str, data: &P) -> usize {
+		self.send_tcp_where(data, |_, user| user.is_in_group(group))
+	}
+
+	/// # Send a packet to a group using UDP
+	///
+	/// Sends the packet to every member of the group that can be reached using UDP. Returns the
+	/// amount of users it has been sent to.
+	pub fn send_group_udp<P: Packet>(&mut self, group: &str, data: &P) -> usize {
+		self.send_udp_where(data, |_, user| user.is_in_group(group))
+	}
+
+	/// # Disconnect a user
+	///
+	/// Tells the user why they are being disconnected, and closes the connection once that has
+	/// been sent. The user is dropped shortly after.
+	pub fn disconnect(&mut self, addr: &SocketAddr, reason: DisconnectReason, message: &str) -> Result<(), SendError> {
+		match self.users.lock().unwrap().get_mut(addr) {
+			Some(user) => user.disconnect(reason, message).map(|_| ()),
+			None => Err(SendError::NotConnected(*addr))
+		}
+	}
+
+	/// # Shut down
+	///
+	/// Stops accepting users and receiving packets, and disconnects every user for the reason
+	/// provided. Returns once the users have received the disconnect, or it took too long, and
+	/// all sockets have been closed.
+	pub fn shutdown(mut self, reason: DisconnectReason, message: &str) {
+		self.close(reason, message);
+	}
+
+	fn close(&mut self, reason: DisconnectReason, message: &str) {
+		// The network thread notices within one poll interval.
+		self.running.store(false, Ordering::Relaxed);
+
+		let network_thread = match self.network_thread.take() {
+			Some(network_thread) => network_thread,
+			None => return
+		};
+
+		match network_thread.join() {
+			Ok(mut network) => network.shutdown(reason, message),
+			Err(_) => println!("The network thread has panicked. The users could not be disconnected.")
+		}
+
+		self.users.lock().unwrap().clear();
+		self.tokens.lock().unwrap().clear();
+		println!("All users have been dropped.");
+	}
+
+	fn send_tcp_where<P: Packet, F: Fn(&SocketAddr, &User) -> bool>(&mut self, data: &P, filter: F) -> usize {
+		let mut user_map = self.users.lock().unwrap();
+		let mut sent = 0;
+
+		for (addr, user) in user_map.iter_mut() {
+			if filter(addr, user) && user.send_tcp(data).is_ok() {
+				sent += 1;
+			}
+		}
+
+		sent
+	}
+
+	fn send_udp_where<P: Packet, F: Fn(&SocketAddr, &User) -> bool>(&mut self, data: &P, filter: F) -> usize {
+		let mut user_map = self.users.lock().unwrap();
+		let mut sent = 0;
+
+		for (addr, user) in user_map.iter_mut() {
+			if filter(addr, user) && send_datagram(&self.udp_socket, &self.outgoing, user, data).is_ok() {
+				sent += 1;
+			}
+		}
+
+		sent
+	}
+}
+
+/// Sends the packet to the user through the UDP channel of the user, and counts the bytes sent. If
+/// the network is simulated, the datagram is handed to the simulator instead, which counts as sent.
+fn send_datagram<P: Packet>(udp_socket: &net::UdpSocket, outgoing: &Option<SafeSimulator>, user: &mut User, data: &P) -> Result<usize, SendError> {
+	let session = user.session_mut();
+	let udp_address = match session.udp_address() {
+		Some(udp_address) => udp_address,
+		None => return Err(SendError::NoUdpAddress(session.remote_address()))
+	};
+
+	let now = time::precise_time_ns();
+	let datagram = match session.udp_channel().send(data, now) {
+		Some(datagram) => datagram,
+		// Queued until earlier datagrams have been acknowledged, the network thread sends it then.
+		None => return Ok(0)
+	};
+
+	if let Some(ref outgoing) = *outgoing {
+		let size = datagram.len();
+		let mut outgoing = outgoing.lock().unwrap();
+		outgoing.send(datagram, udp_address, now);
+		session.count_sent(size);
+
+		// Whatever has made it through is sent right away, instead of waiting for the network
+		// thread to wake up.
+		for (addr, datagram) in outgoing.deliver(now) {
+			let _ = udp_socket.send_to(&datagram, addr);
+		}
+
+		return Ok(size);
+	}
+
+	match udp_socket.send_to(&datagram[..], &udp_address) {
+		Ok(size) => {
+			session.count_sent(size);
+			Ok(size)
+		},
+		Err(err) => Err(SendError::Io(err))
+	}
+}
+
+impl Drop for ConnectionHub {
+	fn drop(&mut self) {
+		self.close(DisconnectReason::ServerShutdown, "The server is shutting down.");
+	}
+}
+
+/// A stream that has been accepted, but has not completed the handshake yet.
+struct Handshake {
+	stream: TcpStream,
+	greeting: Greeting
+}
+
+/// Everything that is owned by the network thread.
+struct Network {
+	poll: Poll,
+	listener: TcpListener,
+	udp_socket: UdpSocket,
+	// Every user is told where to send their datagrams.
+	udp_port: u16,
+	// The simulated networks, if the network is simulated. Only the network thread receives.
+	outgoing: Option<SafeSimulator>,
+	incoming: Option<NetworkSimulator>,
+
+	// The simulated latency of the streams in nanoseconds, and the frames that have been received
+	// from them, but are held back for it, by the time they arrive.
+	latency: u64,
+	held: VecDeque<(u64, SocketAddr, Frame)>,
+	users: SafeUserMap,
+	tokens: SafeTokenMap,
+	registry: SafeRegistry,
+	keep_alive: KeepAlive,
+	key: Option<SharedKey>,
+	rate_limit: RateLimit,
+	running: Arc<AtomicBool>,
+
+	// The streams that have not completed the handshake yet, and those of the users, by their
+	// poll token.
+	handshakes: HashMap<Token, Handshake>,
+	streams: HashMap<Token, SocketAddr>,
+
+	// The streams of the users that have more to read than they have been read this turn.
+	unread: HashSet<Token>,
+	next_stream: usize,
+	next_session_id: u32
+}
+
+impl Network {
+	fn register(&mut self) -> Result<(), BindError> {
+		if let Err(err) = self.poll.registry().register(&mut self.listener, LISTENER, Interest::READABLE) {
+			return Err(BindError::Register(err));
+		}
+
+		if let Err(err) = self.poll.registry().register(&mut self.udp_socket, UDP, Interest::READABLE) {
+			return Err(BindError::Register(err));
+		}
+
+		Ok(())
+	}
+
+	/// # Run the network thread
+	///
+	/// Waits for any of the sockets to become ready and handles them, until the hub is dropped.
+	/// Everything that has to happen regularly, like resending datagrams and pinging users, is
+	/// done in between.
+	fn run(&mut self) {
+		let mut events = Events::with_capacity(MAX_EVENTS);
+		let mut last_ping = time::precise_time_ns();
+
+		while self.running.load(Ordering::Relaxed) {
+			let timeout = self.poll_timeout(time::precise_time_ns());
+			if let Err(err) = self.poll.poll(&mut events, Some(timeout)) {
+				if err.kind() == ErrorKind::Interrupted {
+					continue;
+				}
+
+				println!("Unable to poll the sockets. {}", err);
+				break;
+			}
+
+			for event in events.iter() {
+				match event.token() {
+					LISTENER => self.accept_connections(),
+					UDP => self.receive_udp(),
+					token => {
+						if self.handshakes.contains_key(&token) {
+							self.receive_handshake(token);
+						}
+						else if let Some(&addr) = self.streams.get(&token) {
+							if event.is_readable() {
+								self.receive_tcp(token, addr);
+							}
+
+							if event.is_writable() {
+								if let Some(user) = self.users.lock().unwrap().get_mut(&addr) {
+									user.flush();
+								}
+							}
+						}
 					}
+				}
+			}
+
+			// The streams that have been read as far as a single turn allows take their next turn
+			// along with everyone else.
+			let unread: Vec<Token> = self.unread.drain().collect();
+			for token in unread {
+				if let Some(&addr) = self.streams.get(&token) {
+					self.receive_tcp(token, addr);
+				}
+			}
 
-					sender
-				},
+			let now = time::precise_time_ns();
+			self.simulate(now);
+			self.resend_udp(now);
+			self.expire_handshakes(now);
+
+			if now - last_ping >= self.keep_alive.ping_interval_ms * 1000000 {
+				self.keep_alive();
+				last_ping = now;
+			}
+		}
+	}
+
+	fn accept_connections(&mut self) {
+		loop {
+			let (mut stream, remote_address) = match self.listener.accept() {
+				Ok(accepted) => accepted,
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
 				Err(err) => {
-					println!("Error reading from UDP socket. {}", err);
-					break;
+					println!("Unable to accept connection. {}", err);
+					return;
 				}
 			};
 
-			// Check if there is a connection corresponding to the sender. If not, the packet is
-			// ignored.
-			let users_map = users.lock().unwrap();
-			if !users_map.contains_key(&sender) {
+			let token = Token(self.next_stream);
+			self.next_stream += 1;
+
+			if let Err(err) = self.poll.registry().register(&mut stream, token, Interest::READABLE | Interest::WRITABLE) {
+				println!("Unable to poll the stream of {}. {}", remote_address, err);
 				continue;
 			}
 
-			// Let go of the lock.
-			drop(users_map);
+			let session_id = self.next_session_id;
+			self.next_session_id = self.next_session_id.wrapping_add(1);
+
+			self.handshakes.insert(token, Handshake {
+				stream: stream,
+				greeting: Greeting::new(remote_address, session_id)
+			});
+		}
+	}
+
+	/// # Continue a handshake
+	///
+	/// Reads what the stream has to offer. Once the hello of the client is complete, it is checked
+	/// and the client is either welcomed as a user or rejected. If the server demands a key, the
+	/// client is challenged first, and only welcomed once it has proven to know the key.
+	fn receive_handshake(&mut self, token: Token) {
+		let result = match self.handshakes.get_mut(&token) {
+			Some(handshake) => handshake.receive(self.key.as_ref()),
+			None => return
+		};
+
+		let (hello, auth) = match result {
+			Some(Ok(greeting)) => greeting,
+			Some(Err(err)) => {
+				if let Some(handshake) = self.handshakes.remove(&token) {
+					println!("Handshake with {} failed. {}", handshake.greeting.remote_address, err);
+					let _ = handshake.stream.shutdown(Shutdown::Both);
+				}
+
+				return;
+			},
+			// The hello or the answer to the challenge is not complete yet.
+			None => return
+		};
+
+		let mut handshake = self.handshakes.remove(&token).unwrap();
+		let greeting = handshake.greeting;
+		let addr = greeting.remote_address;
 
-			match id[0] {
-				0 => {
-					let data = vec![0; SpawnEntity::SIZE as usize];
-					let
+		// The users stay locked until the client has been added, so that no two clients can claim
+		// the same name at once.
+		let mut user_map = self.users.lock().unwrap();
+		if let Err(reject) = session::claim_name(&*user_map, &hello.name) {
+			// Like every reject, it fits into the empty send buffer of the stream.
+			let _ = handshake.stream.write(&Frame::new(&reject).to_bytes());
+			println!("Handshake with {} failed. {}", addr, reject.message);
+			let _ = handshake.stream.shutdown(Shutdown::Both);
+			return;
+		}
+
+		let mut user = User::new(handshake.stream, addr, hello.name, greeting.session_id, greeting.token, greeting.incoming, auth, self.rate_limit, self.latency);
+		if let Err(err) = user.send_tcp(&Welcome { session_id: greeting.session_id, token: greeting.token, udp_port: self.udp_port }) {
+			println!("Unable to welcome {}. {}", addr, err);
+			return;
+		}
+
+		user_map.insert(addr, user);
+		self.tokens.lock().unwrap().insert(greeting.token, addr);
+		drop(user_map);
 
-				},
-				undefined => panic!("Invalid packet received. Id {} not registered.", undefined)
+		self.streams.insert(token, addr);
+
+		// Anything the client sent right after the hello has already been read, so it would not
+		// make the stream readable again.
+		self.receive_tcp(token, addr);
+	}
+
+	/// Rejects all handshakes that have not been completed in time.
+	fn expire_handshakes(&mut self, now: u64) {
+		let expired: Vec<Token> = self.handshakes.iter()
+			.filter(|&(_, handshake)| handshake.greeting.is_expired(now))
+			.map(|(token, _)| *token)
+			.collect();
+
+		for token in expired {
+			if let Some(mut handshake) = self.handshakes.remove(&token) {
+				// Like every reject, it fits into the empty send buffer of the stream.
+				let _ = handshake.stream.write(&Frame::new(&handshake.greeting.timeout()).to_bytes());
+				println!("Handshake with {} timed out.", handshake.greeting.remote_address);
+				let _ = handshake.stream.shutdown(Shutdown::Both);
 			}
 		}
 	}
 
-	/// # Send a packet using TCP
+	/// # Receive from TCP
 	///
-	/// This is slower that send_udp(), however one can assume that all packets are received intact
-	/// and the order is precisely as it was before.
-	pub fn send_tcp<A: ToSocketAddrs, P: Packet>(&mut self, addr: &A, data: &P) -> Result<usize, String> {
-		// Most beautiful. (*cough* TODO *cough*)
-		let mut user_map = self.users.lock().unwrap();
-		let mut user = user_map.get_mut(&addr.to_socket_addrs().unwrap().nth(0).unwrap()).unwrap();
+	/// Reads the frames the user has sent and dispatches them. The user map is not locked while
+	/// the handlers run. If the user has sent more than is read at once, the rest is read in the
+	/// next turn. On a simulated network, the frames are held back for the latency instead, and
+	/// dispatched once they have arrived.
+	fn receive_tcp(&mut self, token: Token, addr: SocketAddr) {
+		let frames = match self.users.lock().unwrap().get_mut(&addr) {
+			Some(user) => {
+				let frames = user.receive_tcp();
+				if user.has_unread() {
+					self.unread.insert(token);
+				}
+
+				frames
+			},
+			None => return
+		};
 
-		user.send_tcp(data)
+		if self.latency > 0 {
+			let arrival = time::precise_time_ns() + self.latency;
+			self.held.extend(frames.into_iter().map(|frame| (arrival, addr, frame)));
+			return;
+		}
+
+		// A packet the server does not understand is not a reason to drop the user.
+		for frame in frames {
+			if let Err(err) = self.registry.dispatch(&addr, &frame) {
+				println!("Invalid packet received from '{}'. {}", addr, err);
+			}
+		}
 	}
 
-	/// # Send a packet using UDP
+	/// # Receive from UDP
 	///
-	/// This is useful for packets that ought to arrive fast and may have certain flaws. If it is
-	/// important that the packet's integrity is guaranteed or a series of packets arrives in the
-	/// correct order, consider using send_tcp instead.
-	pub fn send_udp<A: ToSocketAddrs, P: Packet>(&mut self, addr: &A, data: &P) -> Result<usize, String> {
-		let user_map = self.users.lock().unwrap();
-		let addr = addr.to_socket_addrs().unwrap().nth(0).unwrap();
+	/// Reads every datagram the socket has to offer. If the network is simulated, the datagrams
+	/// are handed to the simulator, and only received once they have made it through.
+	fn receive_udp(&mut self) {
+		let mut buffer = vec![0; MAX_DATAGRAM_SIZE];
+
+		loop {
+			let (size, sender) = match self.udp_socket.recv_from(&mut buffer) {
+				Ok(received) => received,
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => return,
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => {
+					// Some systems report datagrams that could not be delivered on the next
+					// receive, which must not stop the others from being received.
+					println!("Error reading from UDP socket. {}", err);
+					continue;
+				}
+			};
 
-		if !user_map.contains_key(&addr) {
-			return Err(format!("A user with address {} is not connected.", addr));
+			match self.incoming {
+				Some(ref mut incoming) => incoming.send(buffer[..size].to_vec(), sender, time::precise_time_ns()),
+				None => self.receive_datagram(&buffer[..size], sender)
+			}
 		}
+	}
 
-		let packet_id = P::id();
-		let mut vec = vec![packet_id];
-		vec.append(&mut data.to_bytes());
+	/// # Receive a datagram
+	///
+	/// Every datagram starts with the session token of the user that sent it. The rest of the
+	/// datagram is read by the UDP channel of the user, and the packets it releases are dispatched.
+	fn receive_datagram(&mut self, datagram: &[u8], sender: SocketAddr) {
+		let addr = match session::route(&self.tokens.lock().unwrap(), datagram) {
+			Some(addr) => addr,
+			None => return
+		};
+
+		let received = match self.users.lock().unwrap().get_mut(&addr) {
+			Some(user) => session::receive_datagram(user, datagram, sender),
+			None => return
+		};
+
+		let received = match received {
+			Some(received) => received,
+			None => return
+		};
 
-		match self.udp_socket.send_to(&vec[..], &addr) {
-			Ok(size) => Ok(size),
-			Err(err) => Err(format!("{}", err))
+		if let Some(ack) = received.ack {
+			let _ = self.send_datagram(ack, sender, time::precise_time_ns());
+		}
+
+		// The user is always identified by their TCP address, regardless of the protocol.
+		for frame in received.frames {
+			if let Err(err) = self.registry.dispatch(&addr, &frame) {
+				println!("Invalid packet received from {}. {}", addr, err);
+			}
 		}
 	}
-}
 
-impl Drop for ConnectionHub {
-	fn drop(&mut self) {
+	/// Sends the datagram right away, or hands it to the simulated network, which counts as sent.
+	fn send_datagram(&self, datagram: Vec<u8>, addr: SocketAddr, now: u64) -> io::Result<usize> {
+		match self.outgoing {
+			Some(ref outgoing) => {
+				let size = datagram.len();
+				outgoing.lock().unwrap().send(datagram, addr, now);
+
+				Ok(size)
+			},
+			None => self.udp_socket.send_to(&datagram, addr)
+		}
+	}
+
+	/// # Simulate the network
+	///
+	/// Sends the datagrams that have made it through the simulated network by now, and receives
+	/// those that have arrived. The same goes for the frames of the streams.
+	fn simulate(&mut self, now: u64) {
+		while self.held.front().map_or(false, |&(arrival, _, _)| arrival <= now) {
+			let (_, addr, frame) = self.held.pop_front().unwrap();
+			if let Err(err) = self.registry.dispatch(&addr, &frame) {
+				println!("Invalid packet received from '{}'. {}", addr, err);
+			}
+		}
+
+		if self.latency > 0 {
+			for (_, user) in self.users.lock().unwrap().iter_mut() {
+				if user.next_delayed().map_or(false, |due| due <= now) {
+					user.flush();
+				}
+			}
+		}
+
+		if let Some(ref outgoing) = self.outgoing {
+			for (addr, datagram) in outgoing.lock().unwrap().deliver(now) {
+				let _ = self.udp_socket.send_to(&datagram, addr);
+			}
+		}
+
+		let arrived = match self.incoming {
+			Some(ref mut incoming) => incoming.deliver(now),
+			None => return
+		};
+
+		for (sender, datagram) in arrived {
+			self.receive_datagram(&datagram, sender);
+		}
+	}
+
+	/// The time to wait for the sockets to become ready, which is cut short if a simulated
+	/// datagram or frame arrives before. Streams that still have something to read are not waited
+	/// for.
+	fn poll_timeout(&self, now: u64) -> Duration {
+		if !self.unread.is_empty() {
+			return Duration::from_millis(0);
+		}
+
+		let outgoing = self.outgoing.as_ref().and_then(|outgoing| outgoing.lock().unwrap().next_arrival());
+		let incoming = self.incoming.as_ref().and_then(|incoming| incoming.next_arrival());
+		let held = self.held.front().map(|&(arrival, _, _)| arrival);
+		let delayed = match self.latency {
+			0 => None,
+			_ => self.users.lock().unwrap().values().filter_map(|user| user.next_delayed()).min()
+		};
+
+		match outgoing.into_iter().chain(incoming).chain(held).chain(delayed).min() {
+			Some(arrival) => Duration::from_millis(POLL_INTERVAL_MS.min(arrival.saturating_sub(now) / 1000000 + 1)),
+			None => Duration::from_millis(POLL_INTERVAL_MS)
+		}
+	}
+
+	/// # Resend reliable datagrams
+	///
+	/// Sends the reliable datagrams of every user again, that have not been acknowledged in time.
+	/// Users whose channel has failed are disconnected.
+	fn resend_udp(&mut self, now: u64) {
 		let mut user_map = self.users.lock().unwrap();
-		for user in &*user_map {
-			drop(user);
+
+		for (addr, user) in user_map.iter_mut() {
+			let failed = {
+				let session = user.session_mut();
+				let (udp_address, datagrams) = match session.resend(now) {
+					Some(resent) => resent,
+					None => continue
+				};
+
+				for datagram in datagrams {
+					if let Ok(size) = self.send_datagram(datagram, udp_address, now) {
+						session.count_sent(size);
+					}
+				}
+
+				session.udp_channel().is_failed()
+			};
+
+			if failed && user.disconnect(DisconnectReason::Unreachable, "Your datagrams stopped arriving.").is_ok() {
+				println!("{} stopped acknowledging datagrams and is disconnected.", addr);
+			}
 		}
+	}
 
-		user_map.clear();
-		println!("All users have been dropped.");
+	/// # Keep the users alive
+	///
+	/// Removes the users that have been idle for too long or have already disconnected, and pings
+	/// all others. Pinging never blocks, and the removed users are only dropped once the maps have
+	/// been let go of.
+	fn keep_alive(&mut self) {
+		let dead = {
+			let mut user_map = self.users.lock().unwrap();
+			let mut token_map = self.tokens.lock().unwrap();
+
+			session::keep_alive(&mut user_map, &mut token_map, &self.keep_alive)
+		};
+
+		for (addr, _) in &dead {
+			self.streams.retain(|_, stream_addr| stream_addr != addr);
+		}
+	}
+
+	/// # Shut the network down
+	///
+	/// Disconnects all users and waits for them to receive it, but no longer than the shutdown
+	/// timeout. Nothing is received anymore in the meantime. Afterwards, all streams are closed.
+	/// The listening sockets are closed once the network is dropped.
+	fn shutdown(&mut self, reason: DisconnectReason, message: &str) {
+		for (_, user) in self.users.lock().unwrap().iter_mut() {
+			let _ = user.disconnect(reason, message);
+		}
+
+		let deadline = time::precise_time_ns() + SHUTDOWN_TIMEOUT_MS * 1000000;
+		let mut events = Events::with_capacity(MAX_EVENTS);
+
+		loop {
+			let now = time::precise_time_ns();
+			if now >= deadline || !self.users.lock().unwrap().values().any(|user| user.is_open()) {
+				break;
+			}
+
+			let timeout = Duration::from_millis(POLL_INTERVAL_MS.min((deadline - now) / 1000000 + 1));
+			if let Err(err) = self.poll.poll(&mut events, Some(timeout)) {
+				if err.kind() == ErrorKind::Interrupted {
+					continue;
+				}
+
+				println!("Unable to poll the sockets. {}", err);
+				break;
+			}
+
+			let mut user_map = self.users.lock().unwrap();
+			for event in events.iter().filter(|event| event.is_writable()) {
+				if let Some(user) = self.streams.get(&event.token()).and_then(|addr| user_map.get_mut(addr)) {
+					user.flush();
+				}
+			}
+
+			// On a simulated network, the disconnects may still be held back for the latency.
+			for (_, user) in user_map.iter_mut() {
+				if user.next_delayed().map_or(false, |due| due <= now) {
+					user.flush();
+				}
+			}
+		}
+
+		self.users.lock().unwrap().clear();
+		self.tokens.lock().unwrap().clear();
+		self.handshakes.clear();
+		self.streams.clear();
+		self.unread.clear();
+	}
+}
+
+impl Handshake {
+	/// Reads what the stream has to offer. Returns None as long as the handshake is not complete,
+	/// and otherwise whether the client is welcome. With a key, the client is challenged after
+	/// the hello, and the session it authenticates is returned along with the hello.
+	fn receive(&mut self, key: Option<&SharedKey>) -> Option<Result<(Hello, Option<SessionAuth>), String>> {
+		let mut buffer = [0; READ_SIZE];
+
+		// The greeting rejects the client before it has sent too much, so the stream is read
+		// until nothing is left.
+		loop {
+			let size = match self.stream.read(&mut buffer) {
+				Ok(0) => return Some(Err(String::from("The client closed the connection."))),
+				Ok(size) => size,
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => return None,
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => return Some(Err(format!("{}", err)))
+			};
+
+			let stream = &mut self.stream;
+			let result = self.greeting.advance(&buffer[..size], key, |bytes| {
+				let _ = stream.write(bytes);
+			});
+
+			if result.is_some() {
+				return result;
+			}
+		}
 	}
 }
@@ -0,0 +1,59 @@
+//! # Area of interest
+//!
+//! A user only hears about the entities close to their character. Everything else is left out of
+//! the snapshots sent to them, so it despawns on their side once it moves out of range, and spawns
+//! again when it comes back.
+//!
+//! Entities that are already visible are kept a little longer than new ones are let in, so that
+//! an entity moving along the border does not spawn and despawn over and over again.
+
+use std::collections::HashSet;
+
+use packets::WorldState;
+use sys::Vector;
+
+/// The part of the radius an entity may move beyond it, before it is no longer visible.
+const HYSTERESIS: f32 = 0.1;
+
+pub struct Interest {
+	radius: f32,
+
+	// The entities that have been visible the last time.
+	visible: HashSet<u32>
+}
+
+impl Interest {
+	/// # Create an area of interest
+	///
+	/// The user is going to hear about all entities no farther from the center than the radius
+	/// provided.
+	pub fn new(radius: f32) -> Interest {
+		Interest {
+			radius: radius,
+			visible: HashSet::new()
+		}
+	}
+
+	/// # Filter the world
+	///
+	/// Returns the part of the state the user is interested in, when the area is around the
+	/// center provided. The entity the user controls is always part of it.
+	pub fn filter(&mut self, center: Vector<f32>, controlled: Option<u32>, state: &WorldState) -> WorldState {
+		let enter = self.radius * self.radius;
+		let leave = (self.radius * (1.0 + HYSTERESIS)) * (self.radius * (1.0 + HYSTERESIS));
+
+		let filtered: WorldState = state.iter()
+			.filter(|&(id, entity)| {
+				let offset = entity.pos() - center;
+				let distance = offset * offset;
+
+				Some(*id) == controlled || distance <= if self.visible.contains(id) { leave } else { enter }
+			})
+			.map(|(id, entity)| (*id, *entity))
+			.collect();
+
+		self.visible = filtered.keys().cloned().collect();
+
+		filtered
+	}
+}
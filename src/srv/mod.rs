@@ -1,7 +1,47 @@
 //! The servers internal files.
 
+#[cfg(feature = "async")]
+pub mod async_connection_hub;
+#[cfg(feature = "async")]
+pub use self::async_connection_hub::AsyncConnectionHub;
+
+#[cfg(feature = "async")]
+pub mod async_user;
+#[cfg(feature = "async")]
+pub use self::async_user::AsyncUser;
+
+pub mod bind;
+pub use self::bind::{BindConfig, BindError};
+
+pub mod chat;
+pub use self::chat::Chat;
+
 pub mod connection_hub;
-pub use self::connection_hub::ConnectionHub;
+pub use self::connection_hub::{ConnectionHub, KeepAlive, SafeRegistry};
+
+pub mod interest;
+pub use self::interest::Interest;
+
+pub mod network_simulator;
+pub use self::network_simulator::{NetworkConditions, NetworkSimulator};
+
+pub mod rate_limit;
+pub use self::rate_limit::{RateLimit, RateLimiter};
+
+pub mod send_error;
+pub use self::send_error::SendError;
+
+pub mod server_entity;
+pub use self::server_entity::ServerEntity;
+
+pub mod session;
+pub use self::session::{Connection, Session};
+
+pub mod snapshot_history;
+pub use self::snapshot_history::SnapshotHistory;
 
 pub mod user;
 pub use self::user::User;
+
+pub mod world;
+pub use self::world::World;
@@ -0,0 +1,223 @@
+//! # Network simulator
+//!
+//! Loopback delivers every datagram at once, in order and exactly once, so it never shows what
+//! happens to the game over a bad connection. The simulator sits between a UDP socket and whoever
+//! uses it, the connection hub on the server and the server connection on the client, and treats
+//! every datagram the way a bad network would: it is lost, delivered twice, delayed by the latency
+//! and some jitter, or held back so the datagrams after it overtake it.
+//!
+//! TCP hides loss and reordering behind retransmits, so the streams are not run through the
+//! simulator. The connection hub only delays them by the latency, and the client leaves them
+//! alone, so a latency set on both ends adds up for the datagrams, but not for the streams.
+//!
+//! Every decision is drawn from a random number generator seeded by the conditions. Given the same
+//! seed, the same datagrams at the same times meet exactly the same fate, so whatever went wrong
+//! can be replayed. The simulator does not read the clock itself, the time is always passed in.
+
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use rand::{Rng, SeedableRng, XorShiftRng};
+
+/// # Network conditions
+///
+/// How bad the simulated network is. The default is a perfect network, which delivers every
+/// datagram right away.
+#[derive(Clone, Copy, Debug)]
+pub struct NetworkConditions {
+	/// The time in milliseconds every datagram takes to arrive.
+	pub latency_ms: u64,
+
+	/// Up to how many milliseconds every datagram is delayed in addition to the latency, chosen at
+	/// random for each of them.
+	pub jitter_ms: u64,
+
+	/// The chance of a datagram to be lost, between 0 and 1.
+	pub loss: f32,
+
+	/// The chance of a datagram to be delivered twice, between 0 and 1. The copy is delayed on its
+	/// own, so it may arrive before the original.
+	pub duplication: f32,
+
+	/// The chance of a datagram to be held back, between 0 and 1, so that the ones sent after it
+	/// arrive first.
+	pub reordering: f32,
+
+	/// How many milliseconds a datagram that is held back arrives later than it would have.
+	pub reorder_delay_ms: u64,
+
+	/// The seed of the random number generator. The same seed makes the same decisions.
+	pub seed: u64
+}
+
+impl Default for NetworkConditions {
+	fn default() -> NetworkConditions {
+		NetworkConditions {
+			latency_ms: 0,
+			jitter_ms: 0,
+			loss: 0.0,
+			duplication: 0.0,
+			reordering: 0.0,
+			reorder_delay_ms: 50,
+			seed: 0
+		}
+	}
+}
+
+/// Reads conditions in the form `latency=100,jitter=20,loss=0.05,duplication=0.01,reordering=0.02,
+/// reorder_delay=50,seed=7`. Every setting is optional, and those left out are the ones of a
+/// perfect network.
+impl FromStr for NetworkConditions {
+	type Err = String;
+
+	fn from_str(conditions: &str) -> Result<NetworkConditions, String> {
+		let mut parsed = NetworkConditions::default();
+
+		for setting in conditions.split(',').map(|setting| setting.trim()).filter(|setting| !setting.is_empty()) {
+			let mut parts = setting.splitn(2, '=');
+			let name = parts.next().unwrap().trim();
+			let value = match parts.next() {
+				Some(value) => value.trim(),
+				None => return Err(format!("Expected a value for '{}'.", name))
+			};
+
+			match name {
+				"latency" => parsed.latency_ms = try!(parse_value(name, value)),
+				"jitter" => parsed.jitter_ms = try!(parse_value(name, value)),
+				"loss" => parsed.loss = try!(parse_chance(name, value)),
+				"duplication" => parsed.duplication = try!(parse_chance(name, value)),
+				"reordering" => parsed.reordering = try!(parse_chance(name, value)),
+				"reorder_delay" => parsed.reorder_delay_ms = try!(parse_value(name, value)),
+				"seed" => parsed.seed = try!(parse_value(name, value)),
+				_ => return Err(format!("Unknown network condition '{}'.", name))
+			}
+		}
+
+		Ok(parsed)
+	}
+}
+
+fn parse_value<T: FromStr>(name: &str, value: &str) -> Result<T, String> where T::Err: Display {
+	match value.parse::<T>() {
+		Ok(value) => Ok(value),
+		Err(err) => Err(format!("Invalid {} '{}'. {}", name, value, err))
+	}
+}
+
+fn parse_chance(name: &str, value: &str) -> Result<f32, String> {
+	match try!(parse_value::<f32>(name, value)) {
+		chance if chance >= 0.0 && chance <= 1.0 => Ok(chance),
+		_ => Err(format!("The {} must be between 0 and 1.", name))
+	}
+}
+
+/// A datagram on its way, and when it arrives.
+struct Delayed {
+	arrival: u64,
+	addr: SocketAddr,
+	datagram: Vec<u8>
+}
+
+/// # The simulated network
+///
+/// Carries datagrams in a single direction. The address that goes along with every datagram is
+/// not looked at, it is only handed back on delivery.
+pub struct NetworkSimulator {
+	conditions: NetworkConditions,
+	rng: XorShiftRng,
+
+	// The datagrams on their way, by the time they arrive.
+	queue: Vec<Delayed>,
+
+	lost: u64,
+	duplicated: u64
+}
+
+impl NetworkSimulator {
+	pub fn new(conditions: NetworkConditions) -> NetworkSimulator {
+		// The generator must not be seeded with zeros only, which the constants rule out.
+		let seed = [conditions.seed as u32, (conditions.seed >> 32) as u32, 0x9e3779b9, 0x7f4a7c15];
+
+		NetworkSimulator {
+			conditions: conditions,
+			rng: XorShiftRng::from_seed(seed),
+			queue: Vec::new(),
+			lost: 0,
+			duplicated: 0
+		}
+	}
+
+	/// # Send a datagram
+	///
+	/// Puts the datagram on the simulated network at the time provided, in nanoseconds. Unless it
+	/// is lost, it can be taken from the network with deliver() once it has arrived.
+	pub fn send(&mut self, datagram: Vec<u8>, addr: SocketAddr, now: u64) {
+		if self.chance(self.conditions.loss) {
+			self.lost += 1;
+			return;
+		}
+
+		if self.chance(self.conditions.duplication) {
+			self.duplicated += 1;
+			self.enqueue(datagram.clone(), addr, now);
+		}
+
+		self.enqueue(datagram, addr, now);
+	}
+
+	/// # Deliver the datagrams
+	///
+	/// Takes every datagram that has arrived by the time provided off the network, in the order
+	/// they have arrived in.
+	pub fn deliver(&mut self, now: u64) -> Vec<(SocketAddr, Vec<u8>)> {
+		let arrived = self.queue.iter().take_while(|delayed| delayed.arrival <= now).count();
+
+		self.queue.drain(..arrived).map(|delayed| (delayed.addr, delayed.datagram)).collect()
+	}
+
+	/// # The next arrival
+	///
+	/// Returns the time the next datagram arrives at, if there is any on its way.
+	pub fn next_arrival(&self) -> Option<u64> {
+		self.queue.first().map(|delayed| delayed.arrival)
+	}
+
+	pub fn conditions(&self) -> NetworkConditions {
+		self.conditions
+	}
+
+	/// The amount of datagrams that have been lost so far.
+	pub fn lost(&self) -> u64 {
+		self.lost
+	}
+
+	/// The amount of datagrams that have been delivered twice so far.
+	pub fn duplicated(&self) -> u64 {
+		self.duplicated
+	}
+
+	/// Delays the datagram and queues it by the time it arrives.
+	fn enqueue(&mut self, datagram: Vec<u8>, addr: SocketAddr, now: u64) {
+		let mut delay_ms = self.conditions.latency_ms + self.rng.gen_range(0, self.conditions.jitter_ms + 1);
+
+		if self.chance(self.conditions.reordering) {
+			delay_ms += self.conditions.reorder_delay_ms;
+		}
+
+		let delayed = Delayed {
+			arrival: now + delay_ms * 1000000,
+			addr: addr,
+			datagram: datagram
+		};
+
+		// Datagrams arriving at the same time stay in the order they have been sent in.
+		let position = self.queue.iter().position(|queued| queued.arrival > delayed.arrival).unwrap_or(self.queue.len());
+		self.queue.insert(position, delayed);
+	}
+
+	/// Draws whether something with the chance provided happens.
+	fn chance(&mut self, chance: f32) -> bool {
+		self.rng.gen::<f32>() < chance
+	}
+}
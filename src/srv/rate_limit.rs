@@ -0,0 +1,179 @@
+//! # Rate limiting
+//!
+//! Every user has a budget of packets and bytes they may send per second, regardless of the
+//! protocol. Both budgets are token buckets, so short bursts are fine as long as the user stays
+//! within the budget on average. Whatever exceeds the budget is dropped without being handled,
+//! and a user that keeps exceeding it is disconnected.
+
+/// The length of the intervals violations are counted in, in milliseconds. A flood drops lots of
+/// packets, but every interval in which anything is dropped only counts as a single violation.
+const VIOLATION_INTERVAL_MS: u64 = 1000;
+
+/// # Rate limit settings
+///
+/// How much every user may send, and how often they may exceed it before being disconnected.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+	/// The packets per second a user may send on average. Every frame received using TCP and every
+	/// datagram received using UDP counts as a packet.
+	pub packets_per_second: u32,
+
+	/// The bytes per second a user may send on average.
+	pub bytes_per_second: u32,
+
+	/// How many milliseconds worth of the budget may be used up at once.
+	pub burst_ms: u64,
+
+	/// The amount of intervals in a row in which the user exceeded the budget, after which they
+	/// are disconnected. An interval within the budget forgives all violations before it.
+	pub max_violations: u32
+}
+
+impl Default for RateLimit {
+	fn default() -> RateLimit {
+		RateLimit {
+			packets_per_second: 200,
+			bytes_per_second: 65536,
+			burst_ms: 2000,
+			max_violations: 5
+		}
+	}
+}
+
+/// # The verdict on received traffic
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Verdict {
+	/// The traffic is within the budget and should be handled.
+	Accept,
+
+	/// The traffic exceeds the budget and must be dropped. It is the first traffic dropped in the
+	/// current interval, so a new violation has been counted.
+	Violation,
+
+	/// The traffic exceeds the budget and must be dropped, but the violation has already been
+	/// counted for the current interval.
+	Drop,
+
+	/// The user has exceeded the budget too often and must be disconnected.
+	Disconnect
+}
+
+struct TokenBucket {
+	// The tokens added per second, and the most tokens the bucket holds.
+	rate: f64,
+	capacity: f64,
+	tokens: f64
+}
+
+impl TokenBucket {
+	/// Creates a full bucket, so a user that just connected may start with a burst.
+	fn new(rate: u32, burst_ms: u64) -> TokenBucket {
+		let rate = rate as f64;
+		let capacity = rate * burst_ms as f64 / 1000.0;
+
+		TokenBucket {
+			rate: rate,
+			capacity: capacity,
+			tokens: capacity
+		}
+	}
+
+	fn refill(&mut self, elapsed: f64) {
+		self.tokens = (self.tokens + self.rate * elapsed).min(self.capacity);
+	}
+
+	fn holds(&self, amount: f64) -> bool {
+		self.tokens >= amount
+	}
+
+	fn take(&mut self, amount: f64) {
+		self.tokens -= amount;
+	}
+}
+
+/// # The rate limiter of a user
+///
+/// Keeps track of the budgets of a single user, and how often they have been exceeded.
+pub struct RateLimiter {
+	limit: RateLimit,
+	packets: TokenBucket,
+	bytes: TokenBucket,
+
+	// The last time the buckets have been refilled, in nanoseconds.
+	last_refill: u64,
+
+	// The violations in a row, and the start of the interval the last one has been counted in.
+	violations: u32,
+	last_violation: Option<u64>,
+
+	dropped: u64
+}
+
+impl RateLimiter {
+	pub fn new(limit: RateLimit, now: u64) -> RateLimiter {
+		RateLimiter {
+			limit: limit,
+			packets: TokenBucket::new(limit.packets_per_second, limit.burst_ms),
+			bytes: TokenBucket::new(limit.bytes_per_second, limit.burst_ms),
+			last_refill: now,
+			violations: 0,
+			last_violation: None,
+			dropped: 0
+		}
+	}
+
+	/// # Check received traffic
+	///
+	/// Takes a single packet of the size provided from the budgets, if both of them allow it.
+	/// Otherwise the packet must be dropped, and the violation is counted. Once the user has
+	/// exceeded the budget in too many intervals in a row, they must be disconnected.
+	pub fn check(&mut self, size: usize, now: u64) -> Verdict {
+		let elapsed = now.saturating_sub(self.last_refill) as f64 / 1000000000.0;
+		self.packets.refill(elapsed);
+		self.bytes.refill(elapsed);
+		self.last_refill = now;
+
+		if self.packets.holds(1.0) && self.bytes.holds(size as f64) {
+			self.packets.take(1.0);
+			self.bytes.take(size as f64);
+
+			return Verdict::Accept;
+		}
+
+		self.dropped += 1;
+		let interval = VIOLATION_INTERVAL_MS * 1000000;
+
+		match self.last_violation {
+			// Still the same interval, which has been counted already.
+			Some(last) if now.saturating_sub(last) < interval => return Verdict::Drop,
+			Some(last) if now.saturating_sub(last) < 2 * interval => self.violations += 1,
+			_ => self.violations = 1
+		}
+		self.last_violation = Some(now);
+
+		if self.violations >= self.limit.max_violations {
+			Verdict::Disconnect
+		}
+		else {
+			Verdict::Violation
+		}
+	}
+
+	pub fn limit(&self) -> RateLimit {
+		self.limit
+	}
+
+	/// # The violations in a row
+	///
+	/// Returns the amount of intervals in a row in which the user has exceeded the budget.
+	pub fn violations(&self) -> u32 {
+		self.violations
+	}
+
+	/// # The dropped packets
+	///
+	/// Returns how many packets of the user have been dropped since they connected.
+	pub fn dropped(&self) -> u64 {
+		self.dropped
+	}
+}
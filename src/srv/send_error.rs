@@ -0,0 +1,58 @@
+//! # Send errors
+//!
+//! Everything that can keep a packet from being sent to a user. None of them affect any other
+//! user, so the hubs leave it to the caller whether they are worth reporting.
+
+use std::error::Error;
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::net::SocketAddr;
+
+#[derive(Debug)]
+pub enum SendError {
+	/// There is no user with the address, or they have been dropped since.
+	NotConnected(SocketAddr),
+
+	/// The user has not sent any datagram yet, so it is unknown where to send datagrams to.
+	NoUdpAddress(SocketAddr),
+
+	/// The stream of the user has been closed, or is about to be closed after a disconnect.
+	Closed(SocketAddr),
+
+	/// More is waiting to be written to the stream of the user than is allowed, so the user has
+	/// been closed.
+	Overflow(SocketAddr),
+
+	/// The socket failed.
+	Io(io::Error)
+}
+
+impl Display for SendError {
+	fn fmt(&self, formatter: &mut Formatter) -> fmt::Result {
+		match self {
+			&SendError::NotConnected(address) => write!(formatter, "A user with address {} is not connected.", address),
+			&SendError::NoUdpAddress(address) => write!(formatter, "The user with address {} has not sent any datagram yet.", address),
+			&SendError::Closed(address) => write!(formatter, "The stream to {} has been closed.", address),
+			&SendError::Overflow(address) => write!(formatter, "Too much data is waiting to be sent to {}.", address),
+			&SendError::Io(ref err) => write!(formatter, "{}", err)
+		}
+	}
+}
+
+impl Error for SendError {
+	fn description(&self) -> &str {
+		match self {
+			&SendError::NotConnected(_) => "user not connected",
+			&SendError::NoUdpAddress(_) => "no udp address",
+			&SendError::Closed(_) => "stream closed",
+			&SendError::Overflow(_) => "outgoing buffer full",
+			&SendError::Io(ref err) => err.description()
+		}
+	}
+}
+
+impl From<io::Error> for SendError {
+	fn from(err: io::Error) -> SendError {
+		SendError::Io(err)
+	}
+}
@@ -0,0 +1,94 @@
+//! # An entity on the server
+//!
+//! The server holds the true state of every entity. Clients only ever see what the server sends
+//! them about it.
+
+use packets::EntityState;
+use sys::{Movable, Vector};
+
+/// The kind of the entities that are controlled by players.
+pub const CHARACTER: u8 = 0;
+
+pub struct ServerEntity {
+	id: u32,
+	kind: u8,
+	health: u16,
+	max_health: u16,
+	pos: Vector<f32>,
+	velocity: Vector<f32>
+}
+
+impl ServerEntity {
+	pub fn new(id: u32, kind: u8, max_health: u16, pos: Vector<f32>) -> ServerEntity {
+		ServerEntity {
+			id: id,
+			kind: kind,
+			health: max_health,
+			max_health: max_health,
+			pos: pos,
+			velocity: Vector::new(0.0, 0.0)
+		}
+	}
+
+	/// # Step the entity
+	///
+	/// Moves the entity along its velocity for the duration of the tick in seconds.
+	pub fn step(&mut self, tick_duration: f32) {
+		let pos = self.pos + self.velocity * tick_duration;
+		self.pos = pos;
+	}
+
+	/// # The state of the entity
+	///
+	/// Returns the state of the entity as it is sent in world snapshots.
+	pub fn state(&self) -> EntityState {
+		EntityState::new(self.kind, self.max_health, self.health, self.pos, self.velocity)
+	}
+
+	pub fn id(&self) -> u32 {
+		self.id
+	}
+
+	pub fn kind(&self) -> u8 {
+		self.kind
+	}
+
+	pub fn health(&self) -> u16 {
+		self.health
+	}
+
+	pub fn max_health(&self) -> u16 {
+		self.max_health
+	}
+
+	pub fn velocity(&self) -> Vector<f32> {
+		self.velocity
+	}
+
+	pub fn set_velocity(&mut self, velocity: Vector<f32>) {
+		self.velocity = velocity;
+	}
+}
+
+impl Movable for ServerEntity {
+	fn translate(&mut self, x: f32, y: f32) {
+		self.pos.x += x;
+		self.pos.y += y;
+	}
+
+	fn set_pos(&mut self, pos: Vector<f32>) {
+		self.pos = pos;
+	}
+
+	fn x(&self) -> f32 {
+		self.pos.x
+	}
+
+	fn y(&self) -> f32 {
+		self.pos.y
+	}
+
+	fn pos(&self) -> Vector<f32> {
+		self.pos
+	}
+}
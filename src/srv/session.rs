@@ -0,0 +1,504 @@
+//! # Sessions
+//!
+//! Everything about a connection that does not depend on how its sockets are driven. Both
+//! connection hubs greet their clients with the same handshake, route datagrams to their users
+//! and keep them alive the same way; only reading from and writing to the sockets differs. The
+//! hubs share this module for all of it, and the users only add their stream to a session.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use packets::{Authenticate, Challenge, Channel, Frame, Hello, Packet, Pong, Reject, RejectReason};
+use packets::{RejectedDatagrams, SessionAuth, SharedKey, Side};
+use packets::auth::{self, Nonce};
+use packets::channel::Received;
+use packets::frame::{self, LENGTH_SIZE, MAX_FRAME_SIZE, TOKEN_SIZE};
+use srv::{RateLimit, RateLimiter, SendError};
+use srv::connection_hub::{self, KeepAlive};
+use srv::rate_limit::Verdict;
+use rand;
+use time;
+
+/// The largest datagram that is read from the UDP socket.
+pub const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// The time a client has to complete the handshake after connecting, before it is dropped.
+pub const HANDSHAKE_TIMEOUT_MS: u64 = 5000;
+
+/// The longest time shutting down waits for the users to receive their disconnect.
+pub const SHUTDOWN_TIMEOUT_MS: u64 = 1000;
+
+/// The amount of bytes read from a stream at once.
+pub const READ_SIZE: usize = 4096;
+
+/// The most reads from the stream of a user every time it is handled. Whatever is left stays in
+/// the buffer of the system, so a client that sends faster than it is read is slowed down by TCP
+/// itself, and cannot keep the hub from handling everyone else.
+pub const READS_PER_TURN: usize = 16;
+
+/// The most bytes a client may send before the handshake is complete. The handshake only consists
+/// of single frames the client has to wait for an answer to, so anything beyond a frame is not
+/// part of it.
+const MAX_HANDSHAKE_SIZE: usize = LENGTH_SIZE + MAX_FRAME_SIZE as usize;
+
+/// The amount of bytes that may wait to be written to a stream. A user that does not read what is
+/// sent to them is closed once it is exceeded, so the buffer cannot grow without limit.
+pub const MAX_OUTGOING_SIZE: usize = 1048576;
+
+/// The weight a new measurement has in the smoothed round trip time.
+const RTT_SMOOTHING: f32 = 0.125;
+
+/// # A connected user
+///
+/// What the connection hubs need from their users, regardless of how the stream is driven.
+pub trait Connection {
+	/// Everything about the user that does not depend on their stream, like their name and their
+	/// UDP channel.
+	fn session(&self) -> &Session;
+	fn session_mut(&mut self) -> &mut Session;
+
+	/// Checks a packet of the size provided against the rate limit, and returns whether it should
+	/// be handled. A user who keeps exceeding it is disconnected.
+	fn admit(&mut self, size: usize) -> bool;
+
+	/// Sends a ping with the current time, or queues it if the stream is not driven right away.
+	fn ping(&mut self) -> Result<usize, SendError>;
+
+	fn is_open(&self) -> bool;
+}
+
+/// # The session of a user
+///
+/// Who the user is, how to reach them using UDP, and how they have behaved so far.
+pub struct Session {
+	remote_address: SocketAddr,
+
+	/// The last time anything has been received from the user, in nanoseconds.
+	last_seen: u64,
+
+	/// The smoothed round trip time in seconds. It is unknown until the first pong arrives.
+	round_trip_time: Option<f32>,
+
+	name: String,
+	session_id: u32,
+	token: u64,
+
+	// The address the user sends datagrams from. It is only known after the first one arrived and
+	// may change at any time, for instance when a NAT decides to use a different port.
+	udp_address: Option<SocketAddr>,
+	udp_channel: Channel,
+
+	// Everything that has been sent to the user, in bytes, regardless of the protocol.
+	bytes_sent: u64,
+
+	// Everything received from the user, regardless of the protocol, is checked against it.
+	rate_limiter: RateLimiter
+}
+
+impl Session {
+	/// # Start a session
+	///
+	/// Starts the session a handshake has handed out. If the user has authenticated, their
+	/// datagrams are checked with the session keys provided.
+	pub fn new(remote_address: SocketAddr, name: String, session_id: u32, token: u64, auth: Option<SessionAuth>, rate_limit: RateLimit) -> Session {
+		println!("Connected to {} ({}, session {}{})", remote_address, name, session_id, if auth.is_some() { ", authenticated" } else { "" });
+
+		Session {
+			remote_address: remote_address,
+			last_seen: time::precise_time_ns(),
+			round_trip_time: None,
+			name: name,
+			session_id: session_id,
+			token: token,
+			udp_address: None,
+			udp_channel: match auth {
+				Some(auth) => Channel::authenticated(auth),
+				None => Channel::new()
+			},
+			bytes_sent: 0,
+			rate_limiter: RateLimiter::new(rate_limit, time::precise_time_ns())
+		}
+	}
+
+	/// # Check received traffic
+	///
+	/// Checks a packet of the size provided against the rate limit. Violations are reported, but
+	/// disconnecting the user is up to whoever owns their stream.
+	pub fn check(&mut self, size: usize) -> Verdict {
+		let verdict = self.rate_limiter.check(size, time::precise_time_ns());
+
+		match verdict {
+			Verdict::Violation => println!("{} exceeds the rate limit. Dropping packets (violation {} of {}).", self.remote_address, self.rate_limiter.violations(), self.rate_limiter.limit().max_violations),
+			Verdict::Disconnect => println!("{} keeps exceeding the rate limit and is disconnected.", self.remote_address),
+			Verdict::Accept | Verdict::Drop => ()
+		}
+
+		verdict
+	}
+
+	/// # Receive a frame
+	///
+	/// Handles a frame received through TCP that has been admitted. Pongs are part of the
+	/// connection itself and are consumed, everything else is returned to be dispatched.
+	pub fn receive_frame(&mut self, frame: Frame) -> Option<Frame> {
+		if frame.id != Pong::id() {
+			return Some(frame);
+		}
+
+		let now = time::precise_time_ns();
+
+		match frame.decode::<Pong>() {
+			Ok(pong) if pong.timestamp <= now => {
+				let sample = (now - pong.timestamp) as f32 / 1000000000.0;
+				self.round_trip_time = match self.round_trip_time {
+					Some(rtt) => Some(rtt + (sample - rtt) * RTT_SMOOTHING),
+					None => Some(sample)
+				};
+			},
+			Ok(_) => println!("Pong from the future received from '{}'.", self.remote_address),
+			Err(err) => println!("Invalid pong received from '{}'. {}", self.remote_address, err)
+		}
+
+		None
+	}
+
+	/// # Resend reliable datagrams
+	///
+	/// Returns the datagrams that have not been acknowledged in time, along with the address they
+	/// must be sent to. Nothing is sent before the user has sent a datagram themselves.
+	pub fn resend(&mut self, now: u64) -> Option<(SocketAddr, Vec<Vec<u8>>)> {
+		match self.udp_address {
+			Some(udp_address) => Some((udp_address, self.udp_channel.resend(now))),
+			None => None
+		}
+	}
+
+	/// # The dropped packets
+	///
+	/// Returns how many packets of the user have been dropped for exceeding the rate limit.
+	pub fn dropped_packets(&self) -> u64 {
+		self.rate_limiter.dropped()
+	}
+
+	/// # Mark the user as seen
+	///
+	/// Resets the idle time. This happens for everything that has been received from the user,
+	/// regardless of the protocol.
+	pub fn mark_seen(&mut self) {
+		self.last_seen = time::precise_time_ns();
+	}
+
+	/// # The idle time
+	///
+	/// Returns the time in seconds since anything has last been received from this user.
+	pub fn idle_time(&self) -> f32 {
+		time::precise_time_ns().saturating_sub(self.last_seen) as f32 / 1000000000.0
+	}
+
+	/// # The round trip time
+	///
+	/// Returns the smoothed time in seconds it takes a packet to reach the user and come back. In
+	/// case the user has not answered any ping yet, None is returned.
+	pub fn round_trip_time(&self) -> Option<f32> {
+		self.round_trip_time
+	}
+
+	pub fn remote_address(&self) -> SocketAddr {
+		self.remote_address
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	pub fn session_id(&self) -> u32 {
+		self.session_id
+	}
+
+	pub fn token(&self) -> u64 {
+		self.token
+	}
+
+	/// # The UDP address
+	///
+	/// The address the user has last sent a datagram from. Datagrams to the user are sent there.
+	pub fn udp_address(&self) -> Option<SocketAddr> {
+		self.udp_address
+	}
+
+	pub fn set_udp_address(&mut self, addr: SocketAddr) {
+		if self.udp_address != Some(addr) {
+			println!("{} sends datagrams from {}", self.remote_address, addr);
+			self.udp_address = Some(addr);
+		}
+	}
+
+	/// # The UDP channel
+	///
+	/// Every datagram sent to or received from this user passes through this channel, so that
+	/// reliable packets are acknowledged and sent again if necessary.
+	pub fn udp_channel(&mut self) -> &mut Channel {
+		&mut self.udp_channel
+	}
+
+	/// # The rejected datagrams
+	///
+	/// Returns how many datagrams claiming to be from this user have been rejected, because they
+	/// were forged or replayed. This only happens in authenticated sessions.
+	pub fn rejected_datagrams(&self) -> RejectedDatagrams {
+		self.udp_channel.rejected()
+	}
+
+	/// # Count sent bytes
+	///
+	/// Adds bytes that have been sent to the user, regardless of the protocol.
+	pub fn count_sent(&mut self, size: usize) {
+		self.bytes_sent += size as u64;
+	}
+
+	/// # The bytes sent
+	///
+	/// Returns how many bytes have been sent to the user since they connected.
+	pub fn bytes_sent(&self) -> u64 {
+		self.bytes_sent
+	}
+}
+
+/// # Route a datagram
+///
+/// Every datagram starts with the session token of the user that sent it. Returns the address of
+/// the user the token has been handed out to, or None if there is none, in which case the
+/// datagram is ignored.
+pub fn route(tokens: &HashMap<u64, SocketAddr>, datagram: &[u8]) -> Option<SocketAddr> {
+	frame::read_token(datagram).and_then(|token| tokens.get(&token)).cloned()
+}
+
+/// # Receive a datagram
+///
+/// Reads a datagram that has been routed to the user with the UDP channel of the user. The address
+/// it has been sent from does not matter, it is only remembered so the user can be answered.
+/// Returns None if the datagram is dropped, otherwise the frames it released and the
+/// acknowledgement that must be sent back to the sender.
+pub fn receive_datagram<C: Connection>(user: &mut C, datagram: &[u8], sender: SocketAddr) -> Option<Received> {
+	// The token is sent in plain text, so anyone who has seen a single datagram of the user can
+	// send more with it. Only those that really are from the user count against their rate limit,
+	// otherwise a flood of forged datagrams would get them disconnected. The others are rejected
+	// and counted by the channel. Without authentication, there is no telling them apart.
+	let payload = &datagram[TOKEN_SIZE..];
+	if user.session_mut().udp_channel().is_authentic(payload) && !user.admit(datagram.len()) {
+		return None;
+	}
+
+	let session = user.session_mut();
+
+	// Only a datagram that has been accepted by the channel tells where to reach the user, so that
+	// nobody else can redirect the datagrams of an authenticated user. A datagram that only
+	// contains the token does exactly that and nothing else.
+	match session.udp_channel().receive(payload) {
+		Ok(received) => {
+			session.set_udp_address(sender);
+			session.mark_seen();
+
+			Some(received)
+		},
+		// In case the datagram has been cut off, forged or replayed, it is ignored.
+		Err(err) => {
+			println!("Invalid datagram received from {}. {}", sender, err);
+			None
+		}
+	}
+}
+
+/// # Keep the users alive
+///
+/// Removes the users that have been idle for too long or have already disconnected, and pings all
+/// others. The removed users are returned, so they can be dropped once the maps are not locked
+/// any longer.
+pub fn keep_alive<C: Connection>(users: &mut HashMap<SocketAddr, C>, tokens: &mut HashMap<u64, SocketAddr>, keep_alive: &KeepAlive) -> Vec<(SocketAddr, C)> {
+	let idle_timeout = keep_alive.idle_timeout_ms as f32 / 1000.0;
+
+	let dead: Vec<SocketAddr> = users.iter()
+		.filter(|&(_, user)| !user.is_open() || user.session().idle_time() > idle_timeout)
+		.map(|(addr, _)| *addr)
+		.collect();
+
+	let mut removed = Vec::new();
+	for addr in dead {
+		if let Some(user) = users.remove(&addr) {
+			tokens.remove(&user.session().token());
+
+			if user.is_open() {
+				println!("{} timed out after {} seconds.", addr, user.session().idle_time());
+			}
+
+			removed.push((addr, user));
+		}
+	}
+
+	// A failing ping is not handled here, since the user will time out soon enough.
+	for (_, user) in users.iter_mut() {
+		let _ = user.ping();
+	}
+
+	removed
+}
+
+/// # Find a user by name
+///
+/// Returns the address of the user with the name provided, or None if nobody is called that.
+/// Names are compared regardless of case, so that nobody can pass for someone else by changing it.
+pub fn find_named<C: Connection>(users: &HashMap<SocketAddr, C>, name: &str) -> Option<SocketAddr> {
+	let name = name.to_lowercase();
+
+	users.iter()
+		.find(|&(_, user)| user.session().name().to_lowercase() == name)
+		.map(|(addr, _)| *addr)
+}
+
+/// # Claim a name
+///
+/// Checks that nobody is called like the client yet, so that nobody can pass for another user or
+/// receive their whispers. The users must not change until the client has been added, otherwise
+/// two clients could claim the same name. Returns the reject for the client if the name is taken.
+pub fn claim_name<C: Connection>(users: &HashMap<SocketAddr, C>, name: &str) -> Result<(), Reject> {
+	match find_named(users, name) {
+		Some(_) => Err(Reject {
+			reason: RejectReason::NameTaken,
+			message: format!("Somebody called '{}' is already connected.", name)
+		}),
+		None => Ok(())
+	}
+}
+
+/// # A handshake in progress
+///
+/// The state of a client that has connected, but not completed the handshake yet. The client says
+/// hello first. If the server demands a key, the client is challenged and has to prove it knows
+/// the key before it is welcomed. The stream is read and written by the hub; the greeting only
+/// decides what to answer.
+pub struct Greeting {
+	pub remote_address: SocketAddr,
+	pub started: u64,
+	pub session_id: u32,
+
+	// The token is the only thing that identifies the datagrams of a user, so it must not be
+	// guessable.
+	pub token: u64,
+
+	// Bytes that have been read, but do not make up a complete frame yet. Whatever follows the
+	// handshake is handed to the user.
+	pub incoming: Vec<u8>,
+
+	// Once the client has been challenged, its hello and the nonce it has been challenged with.
+	challenge: Option<(Hello, Nonce)>
+}
+
+impl Greeting {
+	pub fn new(remote_address: SocketAddr, session_id: u32) -> Greeting {
+		Greeting {
+			remote_address: remote_address,
+			started: time::precise_time_ns(),
+			session_id: session_id,
+			token: rand::random::<u64>(),
+			incoming: Vec::new(),
+			challenge: None
+		}
+	}
+
+	/// Returns whether the client has taken too long to complete the handshake.
+	pub fn is_expired(&self, now: u64) -> bool {
+		now.saturating_sub(self.started) >= HANDSHAKE_TIMEOUT_MS * 1000000
+	}
+
+	/// The reject a client is sent once the handshake has expired.
+	pub fn timeout(&self) -> Reject {
+		let message = match self.challenge {
+			Some(_) => "Expected authentication, but the client did not answer the challenge.",
+			None => "Expected hello, but the client did not say anything."
+		};
+
+		Reject {
+			reason: RejectReason::InvalidHandshake,
+			message: String::from(message)
+		}
+	}
+
+	/// # Continue the handshake
+	///
+	/// Goes through the frames that have been read so far, including the data provided. Returns
+	/// None as long as the handshake is not complete, and otherwise whether the client is welcome.
+	/// With a key, the client is challenged after the hello, and the session it authenticates is
+	/// returned along with the hello. Challenges and rejects are handed to the writer, and are
+	/// small enough to fit into the empty send buffer of the stream, so they are never waited for.
+	///
+	/// A client that sends more than a single frame can hold is rejected, so the stream has to
+	/// be read in small pieces, each of which is passed along right away.
+	pub fn advance<W: FnMut(&[u8])>(&mut self, data: &[u8], key: Option<&SharedKey>, mut write: W) -> Option<Result<(Hello, Option<SessionAuth>), String>> {
+		self.incoming.extend(data.iter().cloned());
+
+		if self.incoming.len() > MAX_HANDSHAKE_SIZE {
+			return Some(Err(reject(&mut write, Reject {
+				reason: RejectReason::InvalidHandshake,
+				message: format!("The client sent more than {} bytes without waiting for an answer.", MAX_HANDSHAKE_SIZE)
+			})));
+		}
+
+		loop {
+			let frame = match Frame::take_from(&mut self.incoming) {
+				Ok(Some(frame)) => frame,
+				Ok(None) => return None,
+				Err(err) => {
+					let expected = if self.challenge.is_some() { "authentication" } else { "hello" };
+
+					return Some(Err(reject(&mut write, Reject {
+						reason: RejectReason::InvalidHandshake,
+						message: format!("Expected {}. {}", expected, err)
+					})));
+				}
+			};
+
+			match (self.challenge.take(), key) {
+				(Some((hello, server_nonce)), Some(key)) => return Some(authenticate(&mut write, &frame, hello, server_nonce, key)),
+				(_, key) => {
+					let hello = match connection_hub::greet(&frame) {
+						Ok(hello) => hello,
+						Err(err) => return Some(Err(reject(&mut write, err)))
+					};
+
+					if key.is_none() {
+						return Some(Ok((hello, None)));
+					}
+
+					let nonce = auth::nonce();
+					write(&Frame::new(&Challenge { nonce: nonce }).to_bytes());
+					self.challenge = Some((hello, nonce));
+				}
+			}
+		}
+	}
+}
+
+/// Checks the answer to the challenge, and starts the session if the client knows the key.
+fn authenticate<W: FnMut(&[u8])>(write: &mut W, frame: &Frame, hello: Hello, server_nonce: Nonce, key: &SharedKey) -> Result<(Hello, Option<SessionAuth>), String> {
+	let authenticate = match frame.decode::<Authenticate>() {
+		Ok(authenticate) => authenticate,
+		Err(err) => return Err(reject(write, Reject {
+			reason: RejectReason::InvalidHandshake,
+			message: format!("Expected authentication. {}", err)
+		}))
+	};
+
+	if !key.verify(&server_nonce, &authenticate.nonce, &authenticate.proof) {
+		return Err(reject(write, Reject {
+			reason: RejectReason::AuthenticationFailed,
+			message: String::from("The client does not know the key of the server.")
+		}));
+	}
+
+	Ok((hello, Some(key.session(&server_nonce, &authenticate.nonce, Side::Server))))
+}
+
+/// Hands the reject to the writer and returns its message.
+fn reject<W: FnMut(&[u8])>(write: &mut W, reject: Reject) -> String {
+	write(&Frame::new(&reject).to_bytes());
+	reject.message
+}
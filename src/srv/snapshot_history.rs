@@ -0,0 +1,85 @@
+//! # Snapshot history of a user
+//!
+//! Remembers the world snapshots that have been sent to a user, until the user acknowledges one
+//! of them. From then on, every snapshot is sent relative to the acknowledged one, since the user
+//! is known to have it.
+//!
+//! Acknowledgements are sent unreliably, so they may all be lost for a while. Clients only keep
+//! the newest snapshots they have received, so a baseline that has become too old is dropped, and
+//! the snapshots are sent in full until the user acknowledges one of them again.
+
+use std::collections::VecDeque;
+
+use packets::{WorldSnapshot, WorldState};
+
+/// The amount of snapshots that are remembered while waiting for an acknowledgement. If the user
+/// acknowledges a snapshot that has already been forgotten, the next snapshot is sent in full.
+const MAX_SENT_SNAPSHOTS: usize = 32;
+
+/// The amount of ticks after which the baseline is dropped. Clients keep the 64 newest snapshots
+/// they have received, so an older baseline may already have been forgotten by them.
+const MAX_BASELINE_AGE: u32 = 64;
+
+pub struct SnapshotHistory {
+	// The snapshots that have been sent, but not acknowledged, oldest first.
+	sent: VecDeque<(u32, WorldState)>,
+
+	// The newest snapshot that has been acknowledged.
+	baseline: Option<(u32, WorldState)>
+}
+
+impl SnapshotHistory {
+	pub fn new() -> SnapshotHistory {
+		SnapshotHistory {
+			sent: VecDeque::new(),
+			baseline: None
+		}
+	}
+
+	/// # Acknowledge a snapshot
+	///
+	/// The snapshot of the tick becomes the baseline, unless a newer one already is. Snapshots
+	/// sent before it are forgotten. If the snapshot has already been forgotten itself, there is
+	/// no baseline until the user acknowledges another one.
+	pub fn acknowledge(&mut self, tick: u32) {
+		if let Some((baseline_tick, _)) = self.baseline {
+			if tick <= baseline_tick {
+				return;
+			}
+		}
+
+		while self.sent.front().map_or(false, |&(sent_tick, _)| sent_tick < tick) {
+			self.sent.pop_front();
+		}
+
+		self.baseline = match self.sent.front() {
+			Some(&(sent_tick, _)) if sent_tick == tick => self.sent.pop_front(),
+			_ => None
+		};
+	}
+
+	/// # Take a snapshot
+	///
+	/// Creates the snapshot of the state for the tick, relative to the baseline, and remembers it
+	/// until it is acknowledged. If the baseline is too old for the user to still have it, the
+	/// snapshot is sent in full.
+	pub fn snapshot(&mut self, tick: u32, state: &WorldState) -> WorldSnapshot {
+		if let Some((baseline_tick, _)) = self.baseline {
+			if tick.saturating_sub(baseline_tick) >= MAX_BASELINE_AGE {
+				self.baseline = None;
+			}
+		}
+
+		let snapshot = match self.baseline {
+			Some((baseline_tick, ref baseline)) => WorldSnapshot::delta(tick, Some((baseline_tick, baseline)), state),
+			None => WorldSnapshot::delta(tick, None, state)
+		};
+
+		if self.sent.len() >= MAX_SENT_SNAPSHOTS {
+			self.sent.pop_front();
+		}
+		self.sent.push_back((tick, state.clone()));
+
+		snapshot
+	}
+}
@@ -1,110 +1,318 @@
 /*
  * The user represents a structure that can receive data from  or send data to a certain user that
  * the program is currently connected to.
+ *
+ * The stream of the user never blocks. It is read by the connection hub whenever it becomes
+ * readable, and everything sent to the user is buffered until the stream accepts it. On a simulated
+ * network, it is held back for the latency before that.
  */
 
-use std::io::prelude::*;
-use std::net::{TcpStream, SocketAddr, Shutdown};
-use std::thread;
-use std::sync::atomic::{AtomicBool, Ordering};
-use std::sync::Arc;
-use packets::Packet;
+use std::collections::{HashSet, VecDeque};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, Shutdown};
+use mio::net::TcpStream;
+use packets::{Disconnect, DisconnectReason, Frame, Packet, Ping, SessionAuth};
+use packets::frame::LENGTH_SIZE;
+use srv::{Connection, RateLimit, SendError, Session};
+use srv::rate_limit::Verdict;
+use srv::session::{MAX_OUTGOING_SIZE, READ_SIZE, READS_PER_TURN};
+use time;
 
 pub struct User {
 	stream: TcpStream,
-	open: Arc<AtomicBool>
+	open: bool,
+
+	// Set once the user has been disconnected. The stream is closed as soon as everything that
+	// has been sent before, including the disconnect, has been written.
+	disconnecting: bool,
+
+	// Bytes that have been read, but do not make up a complete frame yet.
+	incoming: Vec<u8>,
+
+	// Set when reading stopped before the stream ran dry. The stream does not become readable
+	// again for what is left, so it has to be read again without waiting for it.
+	unread: bool,
+
+	// Bytes that have been sent, but not yet accepted by the stream.
+	outgoing: Vec<u8>,
+
+	// The simulated latency in nanoseconds, and the frames that are held back for it by the time
+	// they are due to be written, oldest first. Without a simulated network, nothing is held back.
+	latency: u64,
+	delayed: VecDeque<(u64, Vec<u8>)>,
+
+	session: Session,
+
+	// The names of the groups the user is a member of.
+	groups: HashSet<String>
 }
 
 impl User {
 	/// # New User
 	///
-	/// Creates a new user from the stream provided. If the streams read channel has been shutdown,
-	/// The user is assumed to be dead.
-	pub fn new(stream: TcpStream) -> User {
-		let receive_stream = stream.try_clone().unwrap();
-		let open = Arc::new(AtomicBool::new(true));
-		let open_clone = open.clone();
-		thread::spawn(move || {
-			User::receive_tcp(receive_stream, open_clone);
-		});
-
-		println!("Connected to {}", stream.peer_addr().unwrap());
-
+	/// Creates a new user from the non-blocking stream provided. The handshake must already have
+	/// been completed on the stream, handing out the session id and token provided. Anything that
+	/// has been read from the stream after the handshake is passed along, so it is not lost. If
+	/// the user has authenticated, their datagrams are checked with the session provided. What the
+	/// user sends is limited by the rate limit provided. Everything sent to the user is held back
+	/// for the latency in nanoseconds, which is zero unless the network is simulated.
+	pub fn new(stream: TcpStream, remote_address: SocketAddr, name: String, session_id: u32, token: u64, incoming: Vec<u8>, auth: Option<SessionAuth>, rate_limit: RateLimit, latency: u64) -> User {
 		User {
 			stream: stream,
-			open: open
+			open: true,
+			disconnecting: false,
+			incoming: incoming,
+			unread: false,
+			outgoing: Vec::new(),
+			latency: latency,
+			delayed: VecDeque::new(),
+			session: Session::new(remote_address, name, session_id, token, auth, rate_limit),
+			groups: HashSet::new()
 		}
 	}
 
 	/// # Receive from TCP
 	///
-	/// While packets sent via UDP are received by the connection hub, the user handles all of the
-	/// packets sent using TCP themselves, since the stream is owned.
-	fn receive_tcp(mut stream: TcpStream, open: Arc<AtomicBool>) {
-		while open.load(Ordering::Relaxed) {
-			let mut data = vec![0; 10];
-
-			let size = match stream.read(&mut data) {
-				Ok(size) => size,
+	/// Reads what the stream has to offer without blocking, and returns the frames that are
+	/// complete. Pongs are part of the connection itself and never leave the user. If the stream
+	/// has been closed or cannot be trusted any longer, the user is closed. Frames that exceed the
+	/// rate limit are dropped.
+	///
+	/// No more than a few reads are done at once, so a user cannot hold up the others by sending
+	/// a lot. If something may be left, has_unread() tells so.
+	pub fn receive_tcp(&mut self) -> Vec<Frame> {
+		let mut buffer = [0; READ_SIZE];
+		let remote_address = self.session.remote_address();
+		let mut reads = 0;
+
+		self.unread = false;
+		while self.open {
+			if reads == READS_PER_TURN {
+				self.unread = true;
+				break;
+			}
+			reads += 1;
+
+			match self.stream.read(&mut buffer) {
+				Ok(0) => {
+					println!("{} disconnected.", remote_address);
+					self.close();
+				},
+				Ok(size) => self.incoming.extend(buffer[..size].iter().cloned()),
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => {
+					println!("{} has been closed due to: {}", remote_address, err);
+					self.close();
+				}
+			}
+		}
+
+		let mut frames = Vec::new();
+		loop {
+			let frame = match Frame::take_from(&mut self.incoming) {
+				Ok(Some(frame)) => frame,
+				Ok(None) => break,
 				Err(err) => {
-					println!("{:?} has been closed due to: {}", stream, err);
-					open.store(false, Ordering::Relaxed);
-					break
+					// Once a frame could not be read, the stream cannot be trusted any longer.
+					println!("{} has been closed due to: {}", remote_address, err);
+					self.close();
+					break;
 				}
 			};
 
-			if size == 0 {
-				println!("{} disconnected.", stream.peer_addr().unwrap());
-				open.store(false, Ordering::Relaxed);
+			self.session.mark_seen();
+
+			if !self.admit(LENGTH_SIZE + 1 + frame.data.len()) {
+				continue;
 			}
-			else {
-				let message = String::from_utf8(data).unwrap();
-				println!("Message received from '{}':", stream.peer_addr().unwrap());
-				println!("{}", message);
+
+			if let Some(frame) = self.session.receive_frame(frame) {
+				frames.push(frame);
+			}
+		}
+
+		frames
+	}
+
+	/// # Check for unread data
+	///
+	/// Returns whether the last receive stopped before the stream ran dry, in which case it has to
+	/// be received from again.
+	pub fn has_unread(&self) -> bool {
+		self.open && self.unread
+	}
+
+	/// # Admit received traffic
+	///
+	/// Checks a packet of the size provided against the rate limit of the user, and returns
+	/// whether it should be handled. A user who keeps exceeding the rate limit is disconnected,
+	/// and nothing they send is handled afterwards.
+	pub fn admit(&mut self, size: usize) -> bool {
+		if self.disconnecting {
+			return false;
+		}
+
+		match self.session.check(size) {
+			Verdict::Accept => true,
+			Verdict::Violation | Verdict::Drop => false,
+			Verdict::Disconnect => {
+				let _ = self.disconnect(DisconnectReason::Flooding, "You sent more than the server allows.");
+				false
 			}
 		}
+	}
+
+	/// # Send a packet using TCP
+	///
+	/// The frame is written as far as the stream accepts it right away. The rest is written by
+	/// flush(), once the stream becomes writable again. With a latency, the frame is only written
+	/// once it is due, by flush() as well.
+	pub fn send_tcp<P: Packet>(&mut self, data: &P) -> Result<usize, SendError> {
+		let remote_address = self.session.remote_address();
+		if !self.open || self.disconnecting {
+			return Err(SendError::Closed(remote_address));
+		}
+
+		let bytes = Frame::new(data).to_bytes();
+		let waiting = self.outgoing.len() + self.delayed.iter().map(|&(_, ref delayed)| delayed.len()).sum::<usize>();
+		if waiting + bytes.len() > MAX_OUTGOING_SIZE {
+			println!("{} does not receive fast enough and has been closed.", remote_address);
+			self.close();
+
+			return Err(SendError::Overflow(remote_address));
+		}
+
+		let size = bytes.len();
+		if self.latency > 0 {
+			self.delayed.push_back((time::precise_time_ns() + self.latency, bytes));
+		}
+		else {
+			self.outgoing.extend(bytes);
+		}
+		self.session.count_sent(size);
+		self.flush();
+
+		Ok(size)
+	}
+
+	/// # Disconnect the user
+	///
+	/// Tells the user why they are being disconnected, and closes the stream once that has been
+	/// written. Nothing can be sent to the user afterwards.
+	pub fn disconnect(&mut self, reason: DisconnectReason, message: &str) -> Result<usize, SendError> {
+		let sent = self.send_tcp(&Disconnect {
+			reason: reason,
+			message: String::from(message)
+		});
+
+		self.disconnecting = true;
+		self.flush();
 
-		println!("Closing stream: {:?}", stream);
-		stream.shutdown(Shutdown::Both).unwrap();
-		drop(stream);
+		sent
 	}
 
-	pub fn send_tcp<P: Packet>(&mut self, data: &P) -> Result<usize, String> {
-		let mut vec = vec![P::id()];
-		vec.append(&mut data.to_bytes());
+	/// # Flush the stream
+	///
+	/// Writes as much of the data waiting to be sent as the stream accepts without blocking. Frames
+	/// that are held back for the latency are only written once they are due.
+	pub fn flush(&mut self) {
+		let now = time::precise_time_ns();
+		while self.delayed.front().map_or(false, |&(due, _)| due <= now) {
+			let (_, bytes) = self.delayed.pop_front().unwrap();
+			self.outgoing.extend(bytes);
+		}
+
+		while self.open && !self.outgoing.is_empty() {
+			match self.stream.write(&self.outgoing) {
+				Ok(0) => self.close(),
+				Ok(size) => {
+					self.outgoing.drain(..size);
+				},
+				Err(ref err) if err.kind() == ErrorKind::WouldBlock => break,
+				Err(ref err) if err.kind() == ErrorKind::Interrupted => continue,
+				Err(err) => {
+					println!("{} has been closed due to: {}", self.session.remote_address(), err);
+					self.close();
+				}
+			}
+		}
 
-		// TODO: This is a stupid workaround, because the io::error::Error type is private.
-		match self.stream.write(&vec) {
-			Ok(size) => Ok(size),
-			Err(err) => Err(format!("{}", err))
+		if self.open && self.disconnecting && self.outgoing.is_empty() && self.delayed.is_empty() {
+			self.close();
 		}
 	}
 
-	pub fn remote_address(&self) -> SocketAddr {
-		self.stream.peer_addr().unwrap()
+	/// # The next delayed frame
+	///
+	/// Returns the time the next frame that is held back for the latency is due at, if there is
+	/// any. The stream has to be flushed then, even if it does not become writable.
+	pub fn next_delayed(&self) -> Option<u64> {
+		if self.open { self.delayed.front().map(|&(due, _)| due) } else { None }
+	}
+
+	/// # Ping the user
+	///
+	/// Sends a ping with the current time. Once the user answers, the round trip time is updated.
+	pub fn ping(&mut self) -> Result<usize, SendError> {
+		self.send_tcp(&Ping { timestamp: time::precise_time_ns() })
+	}
+
+	/// # Join a group
+	///
+	/// Makes the user a member of the group. Returns false if the user already was a member.
+	pub fn join_group(&mut self, group: &str) -> bool {
+		self.groups.insert(String::from(group))
+	}
+
+	/// # Leave a group
+	///
+	/// Returns whether the user has been a member of the group.
+	pub fn leave_group(&mut self, group: &str) -> bool {
+		self.groups.remove(group)
+	}
+
+	pub fn is_in_group(&self, group: &str) -> bool {
+		self.groups.contains(group)
 	}
 
 	pub fn is_open(&self) -> bool {
-		self.open.load(Ordering::Relaxed)
+		self.open
 	}
 
 	pub fn close(&mut self) {
-		self.stream.shutdown(Shutdown::Both).unwrap();
+		self.open = false;
+		let _ = self.stream.shutdown(Shutdown::Both);
+	}
+}
+
+impl Connection for User {
+	fn session(&self) -> &Session {
+		&self.session
+	}
+
+	fn session_mut(&mut self) -> &mut Session {
+		&mut self.session
+	}
+
+	fn admit(&mut self, size: usize) -> bool {
+		User::admit(self, size)
+	}
+
+	fn ping(&mut self) -> Result<usize, SendError> {
+		User::ping(self)
+	}
+
+	fn is_open(&self) -> bool {
+		self.open
 	}
 }
 
 impl Drop for User {
 	fn drop(&mut self) {
-		// Closing the stream will make the receiving thread realize it has been cancelled.
-		println!("Dropping user: {}", self.remote_address());
-		self.stream.shutdown(Shutdown::Both).unwrap();
-
-		// TODO: This is a workaround because I cannot use a JoinHandle here. This is because it
-		// be owned in order for the thread to be joinable.
-		while self.is_open() {
-			thread::sleep_ms(50);
-		}
-
-		println!("User has been dropped properly.");
+		// Nobody else reads from the stream, so there is nothing to wait for. If the user has
+		// already disconnected, the stream may have been shut down before.
+		println!("Dropping user: {}", self.session.remote_address());
+		let _ = self.stream.shutdown(Shutdown::Both);
 	}
 }
@@ -0,0 +1,170 @@
+//! # The world of the server
+//!
+//! The world owns every entity in the game and knows which of them are controlled by which user.
+//! It is only ever changed by the server loop, which steps it once every tick.
+
+use std::collections::HashMap;
+use std::collections::hash_map::Values;
+use std::net::SocketAddr;
+
+use packets::{InputAck, PlayerInput, WorldState};
+use packets::player_input::PLAYER_SPEED;
+use srv::server_entity::{self, ServerEntity};
+use sys::{Movable, Vector};
+
+/// The health of the characters of players.
+const CHARACTER_HEALTH: u16 = 10;
+
+pub struct World {
+	entities: HashMap<u32, ServerEntity>,
+
+	// The entity every user controls, by the TCP address of the user.
+	players: HashMap<SocketAddr, u32>,
+
+	// The sequence number of the last input that has been applied for every user.
+	last_inputs: HashMap<SocketAddr, u32>,
+	next_id: u32
+}
+
+impl World {
+	pub fn new() -> World {
+		World {
+			entities: HashMap::new(),
+			players: HashMap::new(),
+			last_inputs: HashMap::new(),
+			next_id: 0
+		}
+	}
+
+	/// # Add a player
+	///
+	/// Spawns a character for the user with the address provided and returns it. If the user
+	/// already controls a character, that one is returned instead.
+	pub fn add_player(&mut self, addr: SocketAddr) -> &ServerEntity {
+		let id = match self.players.get(&addr) {
+			Some(id) => *id,
+			None => {
+				let id = self.next_id;
+				self.next_id = self.next_id.wrapping_add(1);
+
+				self.entities.insert(id, ServerEntity::new(id, server_entity::CHARACTER, CHARACTER_HEALTH, Vector::new(0.0, 0.0)));
+				self.players.insert(addr, id);
+
+				id
+			}
+		};
+
+		&self.entities[&id]
+	}
+
+	/// # Remove a player
+	///
+	/// Removes the character of the user together with the user. Returns the id of the character,
+	/// or None if the user did not control any.
+	pub fn remove_player(&mut self, addr: &SocketAddr) -> Option<u32> {
+		self.last_inputs.remove(addr);
+
+		match self.players.remove(addr) {
+			Some(id) => {
+				self.entities.remove(&id);
+				Some(id)
+			},
+			None => None
+		}
+	}
+
+	/// # The players
+	///
+	/// Returns the addresses of all users that control a character.
+	pub fn players(&self) -> Vec<SocketAddr> {
+		self.players.keys().cloned().collect()
+	}
+
+	/// # The character of a player
+	///
+	/// Returns the entity the user controls, or None if they do not control any.
+	pub fn character(&self, addr: &SocketAddr) -> Option<&ServerEntity> {
+		self.players.get(addr).and_then(|id| self.entities.get(id))
+	}
+
+	pub fn is_player(&self, addr: &SocketAddr) -> bool {
+		self.players.contains_key(addr)
+	}
+
+	/// # Apply the input of a player
+	///
+	/// Moves the character of the user for one input step. Inputs must be applied in the order of
+	/// their sequence numbers, older ones than the last applied input are ignored, as is the input
+	/// of users that do not control a character.
+	pub fn apply_input(&mut self, addr: &SocketAddr, input: &PlayerInput) {
+		let id = match self.players.get(addr) {
+			Some(id) => *id,
+			None => return
+		};
+
+		if let Some(&last) = self.last_inputs.get(addr) {
+			if input.sequence <= last {
+				return;
+			}
+		}
+
+		if let Some(entity) = self.entities.get_mut(&id) {
+			let movement = input.movement();
+			entity.translate(movement.x, movement.y);
+
+			// The velocity is only used by the clients to continue the movement until the next
+			// update arrives.
+			entity.set_velocity(input.direction() * PLAYER_SPEED);
+		}
+
+		self.last_inputs.insert(*addr, input.sequence);
+	}
+
+	/// # Acknowledge the inputs of a player
+	///
+	/// Creates the packet that tells the user up to which input their character has been moved,
+	/// and where it is now. Returns None if no input of the user has been applied yet.
+	pub fn input_ack(&self, addr: &SocketAddr) -> Option<InputAck> {
+		let sequence = match self.last_inputs.get(addr) {
+			Some(sequence) => *sequence,
+			None => return None
+		};
+
+		match self.character(addr) {
+			Some(entity) => Some(InputAck {
+				sequence: sequence,
+				pos: entity.pos()
+			}),
+			None => None
+		}
+	}
+
+	/// # Step the world
+	///
+	/// Advances the simulation by the duration of one tick in seconds. Characters are only moved
+	/// by the inputs of their players.
+	pub fn step(&mut self, tick_duration: f32) {
+		let players = &self.players;
+
+		for (id, entity) in self.entities.iter_mut() {
+			if !players.values().any(|controlled| controlled == id) {
+				entity.step(tick_duration);
+			}
+		}
+	}
+
+	/// # The state of the world
+	///
+	/// Returns the state of every entity, as it is sent to the users.
+	pub fn state(&self) -> WorldState {
+		self.entities.iter().map(|(id, entity)| (*id, entity.state())).collect()
+	}
+
+	pub fn entities(&self) -> Values<u32, ServerEntity> {
+		self.entities.values()
+	}
+
+	pub fn get(&self, id: u32) -> Option<&ServerEntity> {
+		self.entities.get(&id)
+	}
+}
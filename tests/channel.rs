@@ -0,0 +1,331 @@
+//! # Channels over UDP
+//!
+//! Two channels talk to each other directly, with the datagrams between them lost, duplicated and
+//! reordered by hand. Reliable messages have to be sent again until they are acknowledged, handled
+//! exactly once and, if they are ordered, handled in the order they have been sent in. Finally,
+//! they talk through real sockets, with a simulated network losing datagrams in between.
+
+extern crate stupid;
+
+use stupid::packets::{Channel, ControlEntity, Delivery, Frame, Ping};
+use stupid::packets::channel::{MAX_RESEND_TIMEOUT_MS, MAX_RESENDS, RESEND_TIMEOUT_MS, SEQUENCE_WINDOW};
+use stupid::srv::{NetworkConditions, NetworkSimulator};
+
+use std::cmp;
+use std::net::UdpSocket;
+use std::time::Duration;
+
+/// Receives the datagram, and returns the ids of the entities it released and whether it has to
+/// be acknowledged.
+fn receive(channel: &mut Channel, datagram: &[u8]) -> (Vec<u32>, bool) {
+	let received = channel.receive(datagram).unwrap();
+	let ids = received.frames.iter().map(|frame| frame.decode::<ControlEntity>().unwrap().id).collect();
+
+	(ids, received.ack.is_some())
+}
+
+fn send_unordered(channel: &mut Channel, id: u32) -> Option<Vec<u8>> {
+	channel.send_frame(&Frame::new(&ControlEntity { id: id }), Delivery::ReliableUnordered, 0)
+}
+
+/// Has the message acknowledged by a channel other than the one the test is about, so that the
+/// sender moves on without that one ever seeing it.
+fn acknowledge_elsewhere(client: &mut Channel, datagram: &[u8]) {
+	let ack = Channel::new().receive(datagram).unwrap().ack.unwrap();
+	client.receive(&ack).unwrap();
+}
+
+#[test]
+fn reliable_message_is_sent_again_until_acknowledged() {
+	let mut client = Channel::new();
+	let mut server = Channel::new();
+	let timeout = RESEND_TIMEOUT_MS * 1000000;
+
+	// The first datagram is lost.
+	client.send(&ControlEntity { id: 7 }, 0).unwrap();
+	assert_eq!(client.unacknowledged(), 1);
+	assert!(client.resend(timeout - 1).is_empty());
+
+	let resent = client.resend(timeout);
+	assert_eq!(resent.len(), 1);
+	assert!(client.resend(timeout + 1).is_empty());
+
+	// So is the acknowledgement of the second one, which is only sent again after twice as long.
+	let received = server.receive(&resent[0]).unwrap();
+	assert_eq!(received.frames.len(), 1);
+	assert!(received.ack.is_some());
+	assert_eq!(client.unacknowledged(), 1);
+
+	assert!(client.resend(3 * timeout - 1).is_empty());
+	let resent = client.resend(3 * timeout);
+	assert_eq!(resent.len(), 1);
+
+	let received = server.receive(&resent[0]).unwrap();
+	assert!(received.frames.is_empty());
+	client.receive(&received.ack.unwrap()).unwrap();
+
+	assert_eq!(client.unacknowledged(), 0);
+	assert!(client.resend(100 * timeout).is_empty());
+	assert!(!client.is_failed());
+}
+
+#[test]
+fn resends_back_off_until_the_channel_fails() {
+	let mut client = Channel::new();
+	let timeout = RESEND_TIMEOUT_MS * 1000000;
+	let max_timeout = MAX_RESEND_TIMEOUT_MS * 1000000;
+
+	client.send(&ControlEntity { id: 7 }, 0).unwrap();
+
+	let mut now = 0;
+	for resends in 0..MAX_RESENDS {
+		now += cmp::min(timeout << resends, max_timeout);
+
+		assert!(client.resend(now - 1).is_empty());
+		assert_eq!(client.resend(now).len(), 1);
+		assert!(!client.is_failed());
+	}
+
+	// Once the last attempt has gone unacknowledged as well, nothing is sent any longer.
+	assert!(client.resend(now + max_timeout - 1).is_empty());
+	assert!(client.resend(now + max_timeout).is_empty());
+	assert!(client.is_failed());
+	assert!(client.resend(now + 100 * max_timeout).is_empty());
+}
+
+#[test]
+fn unreliable_message_is_never_acknowledged() {
+	let mut client = Channel::new();
+	let mut server = Channel::new();
+
+	let datagram = client.send(&Ping { timestamp: 1 }, 0).unwrap();
+	assert_eq!(client.unacknowledged(), 0);
+
+	let received = server.receive(&datagram).unwrap();
+	assert_eq!(received.frames.len(), 1);
+	assert!(received.ack.is_none());
+}
+
+#[test]
+fn duplicates_are_handled_once() {
+	let mut client = Channel::new();
+	let mut server = Channel::new();
+
+	let ordered = client.send(&ControlEntity { id: 1 }, 0).unwrap();
+	let unordered = send_unordered(&mut client, 2).unwrap();
+
+	assert_eq!(receive(&mut server, &ordered), (vec![1], true));
+	assert_eq!(receive(&mut server, &unordered), (vec![2], true));
+
+	// The acknowledgements may have been lost, so duplicates are acknowledged again.
+	assert_eq!(receive(&mut server, &ordered), (vec![], true));
+	assert_eq!(receive(&mut server, &unordered), (vec![], true));
+}
+
+#[test]
+fn reliable_messages_beyond_the_window_are_queued() {
+	let mut client = Channel::new();
+	let window = SEQUENCE_WINDOW as u32;
+
+	let datagrams: Vec<Vec<u8>> = (0..window).map(|id| client.send(&ControlEntity { id: id }, 0).unwrap()).collect();
+	for id in window..window + 10 {
+		assert!(client.send(&ControlEntity { id: id }, 0).is_none());
+	}
+
+	// The other deliveries are not held up by it.
+	assert!(send_unordered(&mut client, 0).is_some());
+	assert!(client.send(&Ping { timestamp: 1 }, 0).is_some());
+
+	assert_eq!(client.unacknowledged(), window as usize + 1);
+	assert_eq!(client.queued(), 10);
+	assert!(client.resend(0).is_empty());
+
+	// Every acknowledgement makes room for one more, which is sent in the order it was queued in.
+	for datagram in &datagrams[..4] {
+		acknowledge_elsewhere(&mut client, datagram);
+	}
+
+	let mut server = Channel::new();
+	for datagram in &datagrams[..4] {
+		receive(&mut server, datagram);
+	}
+
+	let released = client.resend(0);
+	assert_eq!(released.len(), 4);
+	assert_eq!(client.queued(), 6);
+
+	// They are the next ones in sequence, so the server only needs the ones in between.
+	for datagram in released.iter().rev() {
+		assert_eq!(receive(&mut server, datagram), (vec![], true));
+	}
+
+	let mut ids = Vec::new();
+	for datagram in &datagrams[4..] {
+		ids.extend(receive(&mut server, datagram).0);
+	}
+	assert_eq!(ids, (4..window + 4).collect::<Vec<u32>>());
+}
+
+#[test]
+fn ordered_messages_are_reassembled_across_the_wrap() {
+	let mut client = Channel::new();
+	let mut server = Channel::new();
+
+	// Everything up to shortly before the sequence numbers wrap arrives in order.
+	for id in 0..65530 {
+		let datagram = client.send(&ControlEntity { id: id }, 0).unwrap();
+		let received = server.receive(&datagram).unwrap();
+		assert_eq!(received.frames[0].decode::<ControlEntity>().unwrap().id, id);
+		client.receive(&received.ack.unwrap()).unwrap();
+	}
+
+	// The next messages wrap around and arrive backwards, so they have to wait for the first.
+	let datagrams: Vec<Vec<u8>> = (65530..65542).map(|id| client.send(&ControlEntity { id: id }, 0).unwrap()).collect();
+
+	for datagram in datagrams[1..].iter().rev() {
+		assert_eq!(receive(&mut server, datagram), (vec![], true));
+	}
+
+	assert_eq!(receive(&mut server, &datagrams[0]), ((65530..65542).collect(), true));
+}
+
+#[test]
+fn ordered_messages_are_only_buffered_within_the_window() {
+	let mut client = Channel::new();
+	let mut server = Channel::new();
+
+	let window = SEQUENCE_WINDOW as u32;
+	let mut datagrams: Vec<Vec<u8>> = (0..window).map(|id| client.send(&ControlEntity { id: id }, 0).unwrap()).collect();
+
+	// The sender never gets that far ahead of the receiver on its own.
+	assert!(client.send(&ControlEntity { id: window }, 0).is_none());
+	acknowledge_elsewhere(&mut client, &datagrams[0]);
+	datagrams.extend(client.resend(0));
+
+	// Too far ahead to be buffered, so it is not acknowledged and will be sent again.
+	assert_eq!(receive(&mut server, &datagrams[window as usize]), (vec![], false));
+
+	// The last one within the window is buffered.
+	assert_eq!(receive(&mut server, &datagrams[window as usize - 1]), (vec![], true));
+
+	let mut ids = Vec::new();
+	for datagram in &datagrams[..window as usize - 1] {
+		ids.extend(receive(&mut server, datagram).0);
+	}
+	assert_eq!(ids, (0..window).collect::<Vec<u32>>());
+
+	assert_eq!(receive(&mut server, &datagrams[window as usize]), (vec![window], true));
+}
+
+#[test]
+fn unordered_messages_older_than_the_window_are_ignored() {
+	let mut client = Channel::new();
+	let mut server = Channel::new();
+
+	let window = SEQUENCE_WINDOW as u32;
+	let mut datagrams: Vec<Vec<u8>> = (0..window).map(|id| send_unordered(&mut client, id).unwrap()).collect();
+
+	assert!(send_unordered(&mut client, window).is_none());
+	acknowledge_elsewhere(&mut client, &datagrams[0]);
+	datagrams.extend(client.resend(0));
+
+	assert_eq!(receive(&mut server, &datagrams[window as usize]), (vec![window], true));
+
+	// Too old to tell whether it has been received before. The sender only sent something this
+	// new after it had been acknowledged, so it is a stray copy that is neither handled nor
+	// acknowledged again.
+	assert_eq!(receive(&mut server, &datagrams[0]), (vec![], false));
+
+	// The oldest one within the window is still handled, but only once.
+	assert_eq!(receive(&mut server, &datagrams[1]), (vec![1], true));
+	assert_eq!(receive(&mut server, &datagrams[1]), (vec![], true));
+}
+
+#[test]
+fn reliable_messages_survive_loss_between_sockets() {
+	const MESSAGES: u32 = 1500;
+	const STEP_NS: u64 = 1000000;
+
+	let client_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	let server_socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+	client_socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+	server_socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+	let client_address = client_socket.local_addr().unwrap();
+	let server_address = server_socket.local_addr().unwrap();
+
+	let lossy = |seed| NetworkConditions {
+		loss: 0.1,
+		duplication: 0.05,
+		seed: seed,
+		..NetworkConditions::default()
+	};
+	let mut to_server = NetworkSimulator::new(lossy(7));
+	let mut to_client = NetworkSimulator::new(lossy(8));
+
+	let mut client = Channel::new();
+	let mut server = Channel::new();
+	let mut ordered = Vec::new();
+	let mut unordered = Vec::new();
+	let mut buffer = vec![0; 65536];
+
+	// More than fit into the window at once, so some have to wait.
+	for id in 0..MESSAGES {
+		if let Some(datagram) = client.send(&ControlEntity { id: id }, 0) {
+			to_server.send(datagram, server_address, 0);
+		}
+		if let Some(datagram) = send_unordered(&mut client, MESSAGES + id) {
+			to_server.send(datagram, server_address, 0);
+		}
+	}
+	assert!(client.queued() > 0);
+
+	for step in 0..30000 {
+		let now = step * STEP_NS;
+
+		for datagram in client.resend(now) {
+			to_server.send(datagram, server_address, now);
+		}
+
+		// Whatever the simulator lets through crosses the loopback. Every datagram is read right
+		// away, so the loopback does not lose any itself by running out of buffer space.
+		for (addr, datagram) in to_server.deliver(now) {
+			client_socket.send_to(&datagram, addr).unwrap();
+			let (size, sender) = server_socket.recv_from(&mut buffer).unwrap();
+			assert_eq!(sender, client_address);
+
+			let received = server.receive(&buffer[..size]).unwrap();
+			if let Some(ack) = received.ack {
+				to_client.send(ack, client_address, now);
+			}
+
+			for frame in received.frames {
+				let id = frame.decode::<ControlEntity>().unwrap().id;
+				if id < MESSAGES {
+					ordered.push(id);
+				}
+				else {
+					unordered.push(id - MESSAGES);
+				}
+			}
+		}
+
+		for (addr, datagram) in to_client.deliver(now) {
+			server_socket.send_to(&datagram, addr).unwrap();
+			let (size, _) = client_socket.recv_from(&mut buffer).unwrap();
+			client.receive(&buffer[..size]).unwrap();
+		}
+
+		if client.unacknowledged() == 0 && client.queued() == 0 {
+			break;
+		}
+	}
+
+	assert!(to_server.lost() > 0);
+	assert!(!client.is_failed());
+	assert_eq!(client.unacknowledged(), 0);
+	assert_eq!(ordered, (0..MESSAGES).collect::<Vec<u32>>());
+
+	unordered.sort();
+	assert_eq!(unordered, (0..MESSAGES).collect::<Vec<u32>>());
+}
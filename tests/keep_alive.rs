@@ -0,0 +1,127 @@
+//! # Keeping users alive
+//!
+//! Both hubs keep their users alive the same way, while the users are locked. Pinging must not
+//! wait for anyone, and users that are removed must not be dropped before the maps are let go of,
+//! so a single slow user cannot hold up everyone else. The users are modelled by connections that
+//! count their pings and tell when they have been dropped.
+
+extern crate stupid;
+
+use stupid::srv::{Connection, KeepAlive, RateLimit, SendError, Session};
+use stupid::srv::session;
+
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+
+struct TestConnection {
+	session: Session,
+	open: bool,
+	pings: Rc<Cell<u32>>,
+	dropped: Rc<Cell<bool>>
+}
+
+impl Connection for TestConnection {
+	fn session(&self) -> &Session {
+		&self.session
+	}
+
+	fn session_mut(&mut self) -> &mut Session {
+		&mut self.session
+	}
+
+	fn admit(&mut self, _: usize) -> bool {
+		true
+	}
+
+	fn ping(&mut self) -> Result<usize, SendError> {
+		self.pings.set(self.pings.get() + 1);
+		Ok(0)
+	}
+
+	fn is_open(&self) -> bool {
+		self.open
+	}
+}
+
+impl Drop for TestConnection {
+	fn drop(&mut self) {
+		self.dropped.set(true);
+	}
+}
+
+/// The pings and the drop of a connection, which can still be looked at once it is gone.
+struct Probe {
+	pings: Rc<Cell<u32>>,
+	dropped: Rc<Cell<bool>>
+}
+
+/// Adds an open or closed connection to the maps, the way the hubs do once the handshake is done.
+fn connect(users: &mut HashMap<SocketAddr, TestConnection>, tokens: &mut HashMap<u64, SocketAddr>, port: u16, open: bool) -> (SocketAddr, Probe) {
+	let addr: SocketAddr = format!("127.0.0.1:{}", port).parse().unwrap();
+	let token = port as u64;
+	let probe = Probe {
+		pings: Rc::new(Cell::new(0)),
+		dropped: Rc::new(Cell::new(false))
+	};
+
+	users.insert(addr, TestConnection {
+		session: Session::new(addr, format!("user{}", port), port as u32, token, None, RateLimit::default()),
+		open: open,
+		pings: probe.pings.clone(),
+		dropped: probe.dropped.clone()
+	});
+	tokens.insert(token, addr);
+
+	(addr, probe)
+}
+
+#[test]
+fn open_users_are_pinged_and_closed_ones_are_handed_back() {
+	let mut users = HashMap::new();
+	let mut tokens = HashMap::new();
+	let (open_addr, open) = connect(&mut users, &mut tokens, 1000, true);
+	let (closed_addr, closed) = connect(&mut users, &mut tokens, 1001, false);
+
+	let removed = session::keep_alive(&mut users, &mut tokens, &KeepAlive::default());
+
+	assert_eq!(open.pings.get(), 1);
+	assert_eq!(closed.pings.get(), 0);
+	assert!(users.contains_key(&open_addr));
+	assert!(!users.contains_key(&closed_addr));
+	assert!(!tokens.contains_key(&1001));
+
+	// The caller decides when the removed users are dropped, after letting go of the maps.
+	assert_eq!(removed.len(), 1);
+	assert_eq!(removed[0].0, closed_addr);
+	assert!(!closed.dropped.get());
+
+	drop(removed);
+	assert!(closed.dropped.get());
+	assert!(!open.dropped.get());
+}
+
+#[test]
+fn idle_users_are_handed_back_without_being_pinged() {
+	let mut users = HashMap::new();
+	let mut tokens = HashMap::new();
+	let (addr, idle) = connect(&mut users, &mut tokens, 1000, true);
+
+	let keep_alive = KeepAlive {
+		ping_interval_ms: 1000,
+		idle_timeout_ms: 0
+	};
+	thread::sleep(Duration::from_millis(5));
+
+	let removed = session::keep_alive(&mut users, &mut tokens, &keep_alive);
+
+	assert_eq!(idle.pings.get(), 0);
+	assert!(users.is_empty());
+	assert!(tokens.is_empty());
+	assert_eq!(removed.len(), 1);
+	assert_eq!(removed[0].0, addr);
+	assert!(!idle.dropped.get());
+}
@@ -0,0 +1,132 @@
+//! # Simulated networks
+//!
+//! The simulator has to make the same decisions for the same seed, so that a failure on a bad
+//! network can be replayed. On top of it, the UDP channels have to deliver reliable packets in
+//! order, no matter how bad the network is.
+
+extern crate proptest;
+extern crate stupid;
+
+use stupid::packets::{Channel, SpawnEntity};
+use stupid::srv::{NetworkConditions, NetworkSimulator};
+use stupid::sys::Vector;
+
+use proptest::prelude::*;
+
+use std::net::SocketAddr;
+
+/// The time between two steps of the simulation, in nanoseconds.
+const STEP_NS: u64 = 1000000;
+
+fn address() -> SocketAddr {
+	"127.0.0.1:4242".parse().unwrap()
+}
+
+fn bad_network(seed: u64) -> NetworkConditions {
+	NetworkConditions {
+		latency_ms: 50,
+		jitter_ms: 30,
+		loss: 0.2,
+		duplication: 0.1,
+		reordering: 0.1,
+		reorder_delay_ms: 40,
+		seed: seed
+	}
+}
+
+/// Sends a datagram every step, and returns every datagram that arrived along with the step it
+/// arrived in.
+fn arrivals(conditions: NetworkConditions, datagrams: u8) -> Vec<(u64, Vec<u8>)> {
+	let mut simulator = NetworkSimulator::new(conditions);
+	let mut arrived = Vec::new();
+
+	for step in 0..datagrams as u64 + 1000 {
+		if step < datagrams as u64 {
+			simulator.send(vec![step as u8], address(), step * STEP_NS);
+		}
+
+		for (_, datagram) in simulator.deliver(step * STEP_NS) {
+			arrived.push((step, datagram));
+		}
+	}
+
+	arrived
+}
+
+#[test]
+fn perfect_network_delivers_everything_at_once() {
+	let arrived = arrivals(NetworkConditions::default(), 200);
+
+	assert_eq!(arrived, (0..200).map(|step| (step as u64, vec![step as u8])).collect::<Vec<_>>());
+}
+
+#[test]
+fn same_seed_makes_the_same_decisions() {
+	assert_eq!(arrivals(bad_network(7), 200), arrivals(bad_network(7), 200));
+	assert!(arrivals(bad_network(7), 200) != arrivals(bad_network(8), 200));
+}
+
+#[test]
+fn bad_network_loses_duplicates_and_reorders() {
+	let arrived = arrivals(bad_network(7), 200);
+	let mut datagrams: Vec<Vec<u8>> = arrived.iter().map(|&(_, ref datagram)| datagram.clone()).collect();
+
+	assert!(arrived.iter().all(|&(step, ref datagram)| step >= datagram[0] as u64 + 50));
+	assert!(datagrams.windows(2).any(|pair| pair[0] > pair[1]));
+
+	datagrams.sort();
+	let sent = datagrams.len();
+	datagrams.dedup();
+
+	assert!(datagrams.len() < 200);
+	assert!(sent > datagrams.len());
+}
+
+proptest! {
+	#![proptest_config(ProptestConfig::with_cases(32))]
+
+	// Whatever happens to the datagrams, every reliable packet arrives exactly once and in order.
+	#[test]
+	fn reliable_ordered_survives_a_bad_network(seed in any::<u64>()) {
+		let mut to_server = NetworkSimulator::new(bad_network(seed));
+		let mut to_client = NetworkSimulator::new(bad_network(!seed));
+
+		let mut client = Channel::new();
+		let mut server = Channel::new();
+		let mut received = Vec::new();
+
+		for step in 0..30000 {
+			let now = step * STEP_NS;
+
+			if step < 100 {
+				let spawn_entity = SpawnEntity { id: step as u32, kind: 0, max_health: 100, health: 100, pos: Vector::new(0.0, 0.0) };
+				if let Some(datagram) = client.send(&spawn_entity, now) {
+					to_server.send(datagram, address(), now);
+				}
+			}
+
+			for datagram in client.resend(now) {
+				to_server.send(datagram, address(), now);
+			}
+
+			for (_, datagram) in to_server.deliver(now) {
+				let delivered = server.receive(&datagram).unwrap();
+
+				if let Some(ack) = delivered.ack {
+					to_client.send(ack, address(), now);
+				}
+
+				for frame in delivered.frames {
+					received.push(frame.decode::<SpawnEntity>().unwrap().id);
+				}
+			}
+
+			for (_, datagram) in to_client.deliver(now) {
+				client.receive(&datagram).unwrap();
+			}
+		}
+
+		prop_assert_eq!(received, (0..100).collect::<Vec<u32>>());
+		prop_assert_eq!(client.unacknowledged(), 0);
+	}
+}
@@ -0,0 +1,407 @@
+//! # Packet round trips
+//!
+//! Every packet is encoded and decoded again with arbitrary contents, and has to come back exactly
+//! the way it has been sent. Fields are compared one by one, since a decoder that reads two fields
+//! of the same type in the wrong order would still produce the same bytes when encoded again.
+
+extern crate proptest;
+extern crate stupid;
+
+use stupid::packets::*;
+use stupid::packets::auth::{NONCE_SIZE, PROOF_SIZE};
+use stupid::sys::Vector;
+
+use proptest::prelude::*;
+use proptest::test_runner::TestCaseError;
+
+use std::collections::HashMap;
+
+/// Encodes the packet, both on its own and inside of a frame, and decodes it again. The decoded
+/// packet is returned so its fields can be compared.
+fn round_trip<P: Packet>(packet: &P) -> Result<P, TestCaseError> {
+	let bytes = packet.to_bytes();
+
+	let decoded = match P::from_bytes(&bytes) {
+		Ok(decoded) => decoded,
+		Err(err) => return Err(TestCaseError::fail(format!("Packet {} could not be decoded. {}", P::id(), err)))
+	};
+	prop_assert_eq!(decoded.to_bytes(), bytes.clone());
+
+	let framed = Frame::new(packet).to_bytes();
+	match Frame::from_bytes(&framed).and_then(|frame| frame.decode::<P>()) {
+		Ok(framed) => prop_assert_eq!(framed.to_bytes(), bytes),
+		Err(err) => return Err(TestCaseError::fail(format!("Framed packet {} could not be decoded. {}", P::id(), err)))
+	}
+
+	Ok(decoded)
+}
+
+/// Floats are compared by their bits, so that NaN has to survive the trip aswell.
+fn same_vector(vector: &Vector<f32>, x: f32, y: f32) -> bool {
+	vector.x.to_bits() == x.to_bits() && vector.y.to_bits() == y.to_bits()
+}
+
+fn chat_channel() -> BoxedStrategy<ChatChannel> {
+	prop_oneof![
+		Just(ChatChannel::Global),
+		any::<String>().prop_map(ChatChannel::Group),
+		any::<String>().prop_map(ChatChannel::Whisper),
+		Just(ChatChannel::System)
+	].boxed()
+}
+
+/// The kind, health and position of an entity, turned into an EntityState by entity_state().
+type EntityFields = (u8, u16, u16, (f32, f32), (f32, f32));
+
+fn entity_state(fields: &EntityFields) -> EntityState {
+	let &(kind, max_health, health, (x, y), (velocity_x, velocity_y)) = fields;
+	EntityState::new(kind, max_health, health, Vector::new(x, y), Vector::new(velocity_x, velocity_y))
+}
+
+fn world_state(entities: &HashMap<u32, EntityFields>) -> WorldState {
+	entities.iter().map(|(id, fields)| (*id, entity_state(fields))).collect()
+}
+
+/// Decodes the data as the packet with the id provided, the way a registry would.
+fn decode(id: u8, data: &[u8]) -> Result<(), PacketError> {
+	match id {
+		0 => SpawnEntity::from_bytes(data).map(|_| ()),
+		1 => Hello::from_bytes(data).map(|_| ()),
+		2 => Welcome::from_bytes(data).map(|_| ()),
+		3 => Reject::from_bytes(data).map(|_| ()),
+		4 => Ping::from_bytes(data).map(|_| ()),
+		5 => Pong::from_bytes(data).map(|_| ()),
+		6 => MoveEntity::from_bytes(data).map(|_| ()),
+		7 => ChangeHealth::from_bytes(data).map(|_| ()),
+		8 => DespawnEntity::from_bytes(data).map(|_| ()),
+		9 => PlayerInput::from_bytes(data).map(|_| ()),
+		10 => ControlEntity::from_bytes(data).map(|_| ()),
+		11 => InputAck::from_bytes(data).map(|_| ()),
+		12 => WorldSnapshot::from_bytes(data).map(|_| ()),
+		13 => SnapshotAck::from_bytes(data).map(|_| ()),
+		14 => Disconnect::from_bytes(data).map(|_| ()),
+		15 => SendChat::from_bytes(data).map(|_| ()),
+		16 => ChatMessage::from_bytes(data).map(|_| ()),
+		17 => JoinGroup::from_bytes(data).map(|_| ()),
+		18 => LeaveGroup::from_bytes(data).map(|_| ()),
+		19 => Challenge::from_bytes(data).map(|_| ()),
+		20 => Authenticate::from_bytes(data).map(|_| ()),
+		21 => TickRate::from_bytes(data).map(|_| ()),
+		id => Err(PacketError::UnknownId(id))
+	}
+}
+
+#[test]
+fn spawn_entity_is_encoded_in_declaration_order() {
+	let packet = SpawnEntity {
+		id: 0x01020304,
+		kind: 5,
+		max_health: 0x0607,
+		health: 0x0809,
+		pos: Vector::new(1.0, -2.0)
+	};
+
+	assert_eq!(packet.to_bytes(), vec![
+		0x01, 0x02, 0x03, 0x04,
+		0x05,
+		0x06, 0x07,
+		0x08, 0x09,
+		0x3f, 0x80, 0x00, 0x00,
+		0xc0, 0x00, 0x00, 0x00
+	]);
+}
+
+/// The wire format of every protocol version since it has been pinned, as the fingerprint of
+/// wire_samples(). The current format has to be listed for PROTOCOL_VERSION, so a change to any
+/// packet, the frames or the UDP channel fails here until the version is increased and the new
+/// format has been listed for it.
+const WIRE_FORMATS: &'static [(u16, u64)] = &[
+	(13, 0x2ce3256264c3b0c3)
+];
+
+/// Sends the packet through a fresh UDP channel, and adds both the datagram and its frame on a
+/// stream to the samples.
+fn add_sample<P: Packet>(samples: &mut Vec<Vec<u8>>, packet: &P) {
+	samples.push(Frame::new(packet).to_bytes());
+	samples.push(Channel::new().send(packet, 0).unwrap());
+}
+
+/// Every packet with fixed contents, the way it goes over the wire, along with the token datagrams
+/// start with and an authenticated datagram.
+fn wire_samples() -> Vec<Vec<u8>> {
+	let mut samples = Vec::new();
+	let pos = Vector::new(1.0, -2.0);
+	let velocity = Vector::new(0.5, 0.25);
+
+	// The entities of a snapshot come in no particular order, so each of them is taken from a
+	// snapshot of its own: one that has changed, one that is sent in full and one that is gone.
+	let state = |entity: Option<EntityState>| -> WorldState { entity.into_iter().map(|entity| (1, entity)).collect() };
+	let old = EntityState::new(1, 100, 90, pos, velocity);
+	let new = EntityState::new(2, 50, 40, velocity, pos);
+	let mut snapshot = WorldSnapshot::delta(2, Some((1, &state(Some(old)))), &state(Some(new)));
+	snapshot.entities.extend(WorldSnapshot::delta(2, None, &state(Some(new))).entities);
+	snapshot.entities.extend(WorldSnapshot::delta(2, Some((1, &state(Some(old)))), &state(None)).entities);
+
+	add_sample(&mut samples, &SpawnEntity { id: 1, kind: 2, max_health: 3, health: 4, pos: pos });
+	add_sample(&mut samples, &Hello { version: 1, name: String::from("name") });
+	add_sample(&mut samples, &Welcome { session_id: 1, token: 2, udp_port: 3 });
+	add_sample(&mut samples, &Reject { reason: RejectReason::NameTaken, message: String::from("message") });
+	add_sample(&mut samples, &Ping { timestamp: 1 });
+	add_sample(&mut samples, &Pong { timestamp: 1 });
+	add_sample(&mut samples, &MoveEntity { tick: 1, id: 2, pos: pos, velocity: velocity });
+	add_sample(&mut samples, &ChangeHealth { id: 1, change: HealthChange::Heal, amount: 2, health: 3 });
+	add_sample(&mut samples, &DespawnEntity { id: 1 });
+	add_sample(&mut samples, &PlayerInput { sequence: 1, left: true, right: false, up: false, down: true });
+	add_sample(&mut samples, &ControlEntity { id: 1 });
+	add_sample(&mut samples, &InputAck { sequence: 1, pos: pos });
+	add_sample(&mut samples, &snapshot);
+	add_sample(&mut samples, &SnapshotAck { tick: 1 });
+	add_sample(&mut samples, &Disconnect { reason: DisconnectReason::Unreachable, message: String::from("message") });
+	add_sample(&mut samples, &SendChat { channel: ChatChannel::Whisper(String::from("name")), text: String::from("text") });
+	add_sample(&mut samples, &ChatMessage { sender: String::from("name"), channel: ChatChannel::Group(String::from("group")), text: String::from("text") });
+	add_sample(&mut samples, &JoinGroup { group: String::from("group") });
+	add_sample(&mut samples, &LeaveGroup { group: String::from("group") });
+	add_sample(&mut samples, &Challenge { nonce: [1; NONCE_SIZE] });
+	add_sample(&mut samples, &Authenticate { nonce: [1; NONCE_SIZE], proof: [2; PROOF_SIZE] });
+	add_sample(&mut samples, &TickRate { tick_rate: 1 });
+
+	let key = SharedKey::new(b"key").unwrap();
+	samples.push(key.prove(&[1; NONCE_SIZE], &[2; NONCE_SIZE]).to_vec());
+	samples.push(Channel::authenticated(key.session(&[1; NONCE_SIZE], &[2; NONCE_SIZE], Side::Client)).send(&SnapshotAck { tick: 1 }, 0).unwrap());
+	samples.push(frame::token_to_bytes(1));
+
+	samples
+}
+
+/// Hashes the samples with FNV-1a, which unlike the hasher of the standard library is the same on
+/// every build. Every sample is prefixed with its length, so that bytes cannot move between them.
+fn fingerprint(samples: &[Vec<u8>]) -> u64 {
+	let mut hash: u64 = 0xcbf29ce484222325;
+
+	for sample in samples {
+		let length = sample.len() as u32;
+		let bytes = [(length >> 24) as u8, (length >> 16) as u8, (length >> 8) as u8, length as u8];
+
+		for byte in bytes.iter().chain(sample.iter()) {
+			hash ^= *byte as u64;
+			hash = hash.wrapping_mul(0x100000001b3);
+		}
+	}
+
+	hash
+}
+
+#[test]
+fn wire_format_changes_with_the_protocol_version() {
+	let fingerprint = fingerprint(&wire_samples());
+
+	for &(version, _) in WIRE_FORMATS {
+		assert_eq!(WIRE_FORMATS.iter().filter(|&&(listed, _)| listed == version).count(), 1, "Protocol version {} is listed more than once.", version);
+	}
+
+	match WIRE_FORMATS.iter().find(|&&(version, _)| version == PROTOCOL_VERSION) {
+		Some(&(_, pinned)) => assert!(pinned == fingerprint, "The wire format has changed, but the protocol version has not. Increase PROTOCOL_VERSION and list {:#018x} for it.", fingerprint),
+		None => panic!("The wire format of protocol version {} is not listed. List {:#018x} for it.", PROTOCOL_VERSION, fingerprint)
+	}
+}
+
+proptest! {
+	#[test]
+	fn spawn_entity(id in any::<u32>(), kind in any::<u8>(), max_health in any::<u16>(), health in any::<u16>(), (x, y) in any::<(f32, f32)>()) {
+		let packet = try!(round_trip(&SpawnEntity { id: id, kind: kind, max_health: max_health, health: health, pos: Vector::new(x, y) }));
+
+		prop_assert_eq!(packet.id, id);
+		prop_assert_eq!(packet.kind, kind);
+		prop_assert_eq!(packet.max_health, max_health);
+		prop_assert_eq!(packet.health, health);
+		prop_assert!(same_vector(&packet.pos, x, y));
+	}
+
+	#[test]
+	fn hello(version in any::<u16>(), name in any::<String>()) {
+		let packet = try!(round_trip(&Hello { version: version, name: name.clone() }));
+
+		prop_assert_eq!(packet.version, version);
+		prop_assert_eq!(packet.name, name);
+	}
+
+	#[test]
+	fn welcome(session_id in any::<u32>(), token in any::<u64>(), udp_port in any::<u16>()) {
+		let packet = try!(round_trip(&Welcome { session_id: session_id, token: token, udp_port: udp_port }));
+
+		prop_assert_eq!(packet.session_id, session_id);
+		prop_assert_eq!(packet.token, token);
+		prop_assert_eq!(packet.udp_port, udp_port);
+	}
+
+	#[test]
+	fn reject(reason in prop_oneof![Just(RejectReason::VersionMismatch), Just(RejectReason::InvalidHandshake), Just(RejectReason::AuthenticationFailed), Just(RejectReason::InvalidName), Just(RejectReason::NameTaken)], message in any::<String>()) {
+		let packet = try!(round_trip(&Reject { reason: reason, message: message.clone() }));
+
+		prop_assert_eq!(packet.reason, reason);
+		prop_assert_eq!(packet.message, message);
+	}
+
+	#[test]
+	fn ping(timestamp in any::<u64>()) {
+		let packet = try!(round_trip(&Ping { timestamp: timestamp }));
+
+		prop_assert_eq!(packet.timestamp, timestamp);
+	}
+
+	#[test]
+	fn pong(timestamp in any::<u64>()) {
+		let packet = try!(round_trip(&Pong { timestamp: timestamp }));
+
+		prop_assert_eq!(packet.timestamp, timestamp);
+	}
+
+	#[test]
+	fn move_entity(tick in any::<u32>(), id in any::<u32>(), (x, y) in any::<(f32, f32)>(), (velocity_x, velocity_y) in any::<(f32, f32)>()) {
+		let packet = try!(round_trip(&MoveEntity { tick: tick, id: id, pos: Vector::new(x, y), velocity: Vector::new(velocity_x, velocity_y) }));
+
+		prop_assert_eq!(packet.tick, tick);
+		prop_assert_eq!(packet.id, id);
+		prop_assert!(same_vector(&packet.pos, x, y));
+		prop_assert!(same_vector(&packet.velocity, velocity_x, velocity_y));
+	}
+
+	#[test]
+	fn change_health(id in any::<u32>(), change in prop_oneof![Just(HealthChange::Damage), Just(HealthChange::Heal)], amount in any::<u16>(), health in any::<u16>()) {
+		let packet = try!(round_trip(&ChangeHealth { id: id, change: change, amount: amount, health: health }));
+
+		prop_assert_eq!(packet.id, id);
+		prop_assert_eq!(packet.change, change);
+		prop_assert_eq!(packet.amount, amount);
+		prop_assert_eq!(packet.health, health);
+	}
+
+	#[test]
+	fn despawn_entity(id in any::<u32>()) {
+		let packet = try!(round_trip(&DespawnEntity { id: id }));
+
+		prop_assert_eq!(packet.id, id);
+	}
+
+	#[test]
+	fn player_input(sequence in any::<u32>(), left in any::<bool>(), right in any::<bool>(), up in any::<bool>(), down in any::<bool>()) {
+		let input = PlayerInput { sequence: sequence, left: left, right: right, up: up, down: down };
+		let packet = try!(round_trip(&input));
+
+		prop_assert!(packet == input);
+	}
+
+	#[test]
+	fn control_entity(id in any::<u32>()) {
+		let packet = try!(round_trip(&ControlEntity { id: id }));
+
+		prop_assert_eq!(packet.id, id);
+	}
+
+	#[test]
+	fn input_ack(sequence in any::<u32>(), (x, y) in any::<(f32, f32)>()) {
+		let packet = try!(round_trip(&InputAck { sequence: sequence, pos: Vector::new(x, y) }));
+
+		prop_assert_eq!(packet.sequence, sequence);
+		prop_assert!(same_vector(&packet.pos, x, y));
+	}
+
+	#[test]
+	fn world_snapshot(tick in any::<u32>(), baseline in proptest::option::of((any::<u32>(), proptest::collection::hash_map(any::<u32>(), any::<EntityFields>(), 0..16))), current in proptest::collection::hash_map(any::<u32>(), any::<EntityFields>(), 0..16)) {
+		// Some entities of the baseline are left as they are, so they are not part of the delta.
+		let current = world_state(&current);
+		let baseline = baseline.map(|(baseline_tick, entities)| {
+			let mut state = world_state(&entities);
+			for (id, entity) in current.iter().take(current.len() / 2) {
+				state.insert(*id, *entity);
+			}
+
+			(baseline_tick, state)
+		});
+
+		let snapshot = WorldSnapshot::delta(tick, baseline.as_ref().map(|&(baseline_tick, ref state)| (baseline_tick, state)), &current);
+		let packet = try!(round_trip(&snapshot));
+
+		prop_assert_eq!(packet.tick, tick);
+		prop_assert_eq!(packet.baseline, baseline.as_ref().map(|&(baseline_tick, _)| baseline_tick));
+
+		match packet.apply(baseline.as_ref().map(|&(_, ref state)| state)) {
+			Ok(state) => prop_assert!(state == current),
+			Err(err) => return Err(TestCaseError::fail(format!("The snapshot could not be applied. {}", err)))
+		}
+	}
+
+	#[test]
+	fn snapshot_ack(tick in any::<u32>()) {
+		let packet = try!(round_trip(&SnapshotAck { tick: tick }));
+
+		prop_assert_eq!(packet.tick, tick);
+	}
+
+	#[test]
+	fn tick_rate(tick_rate in any::<u16>()) {
+		let packet = try!(round_trip(&TickRate { tick_rate: tick_rate }));
+
+		prop_assert_eq!(packet.tick_rate, tick_rate);
+	}
+
+	#[test]
+	fn disconnect(reason in prop_oneof![Just(DisconnectReason::ServerShutdown), Just(DisconnectReason::Kicked), Just(DisconnectReason::Flooding), Just(DisconnectReason::Unreachable)], message in any::<String>()) {
+		let packet = try!(round_trip(&Disconnect { reason: reason, message: message.clone() }));
+
+		prop_assert_eq!(packet.reason, reason);
+		prop_assert_eq!(packet.message, message);
+	}
+
+	#[test]
+	fn send_chat(channel in chat_channel(), text in any::<String>()) {
+		let packet = try!(round_trip(&SendChat { channel: channel.clone(), text: text.clone() }));
+
+		prop_assert_eq!(packet.channel, channel);
+		prop_assert_eq!(packet.text, text);
+	}
+
+	#[test]
+	fn chat_message(sender in any::<String>(), channel in chat_channel(), text in any::<String>()) {
+		let packet = try!(round_trip(&ChatMessage { sender: sender.clone(), channel: channel.clone(), text: text.clone() }));
+
+		prop_assert_eq!(packet.sender, sender);
+		prop_assert_eq!(packet.channel, channel);
+		prop_assert_eq!(packet.text, text);
+	}
+
+	#[test]
+	fn join_group(group in any::<String>()) {
+		let packet = try!(round_trip(&JoinGroup { group: group.clone() }));
+
+		prop_assert_eq!(packet.group, group);
+	}
+
+	#[test]
+	fn leave_group(group in any::<String>()) {
+		let packet = try!(round_trip(&LeaveGroup { group: group.clone() }));
+
+		prop_assert_eq!(packet.group, group);
+	}
+
+	#[test]
+	fn challenge(nonce in any::<[u8; NONCE_SIZE]>()) {
+		let packet = try!(round_trip(&Challenge { nonce: nonce }));
+
+		prop_assert_eq!(packet.nonce, nonce);
+	}
+
+	#[test]
+	fn authenticate(nonce in any::<[u8; NONCE_SIZE]>(), proof in any::<[u8; PROOF_SIZE]>()) {
+		let packet = try!(round_trip(&Authenticate { nonce: nonce, proof: proof }));
+
+		prop_assert_eq!(packet.nonce, nonce);
+		prop_assert_eq!(packet.proof, proof);
+	}
+
+	// Whatever arrives, decoding it may fail, but it must never panic.
+	#[test]
+	fn arbitrary_bytes(id in 0..22u8, data in proptest::collection::vec(any::<u8>(), 0..256)) {
+		let _ = decode(id, &data);
+	}
+}
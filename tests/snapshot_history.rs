@@ -0,0 +1,118 @@
+//! # Snapshot history
+//!
+//! Acknowledgements of world snapshots are sent unreliably, so the history has to cope with any of
+//! them being lost or arriving late. The client is modelled the way it treats snapshots: it keeps
+//! the 64 newest states, ignores snapshots relative to one it does not have and acknowledges every
+//! snapshot it applies.
+
+extern crate stupid;
+
+use stupid::packets::{EntityState, WorldSnapshot, WorldState};
+use stupid::srv::SnapshotHistory;
+use stupid::sys::Vector;
+
+use std::collections::VecDeque;
+
+/// The amount of states the client keeps.
+const CLIENT_SNAPSHOTS: usize = 64;
+
+/// A single entity moving along, so every tick has a state of its own.
+fn state(tick: u32) -> WorldState {
+	let mut state = WorldState::new();
+	state.insert(1, EntityState::new(0, 100, 100, Vector::new(tick as f32, 0.0), Vector::new(1.0, 0.0)));
+
+	state
+}
+
+struct Client {
+	states: VecDeque<(u32, WorldState)>
+}
+
+impl Client {
+	fn new() -> Client {
+		Client {
+			states: VecDeque::new()
+		}
+	}
+
+	/// Applies the snapshot, and returns the acknowledgement if it could be applied.
+	fn receive(&mut self, snapshot: &WorldSnapshot) -> Option<u32> {
+		let state = {
+			let baseline = match snapshot.baseline {
+				Some(baseline_tick) => match self.states.iter().find(|&&(tick, _)| tick == baseline_tick) {
+					Some(&(_, ref baseline)) => Some(baseline),
+					None => return None
+				},
+				None => None
+			};
+
+			snapshot.apply(baseline).unwrap()
+		};
+
+		if self.states.len() >= CLIENT_SNAPSHOTS {
+			self.states.pop_front();
+		}
+		self.states.push_back((snapshot.tick, state));
+
+		Some(snapshot.tick)
+	}
+}
+
+#[test]
+fn acknowledged_snapshot_becomes_the_baseline() {
+	let mut history = SnapshotHistory::new();
+
+	assert_eq!(history.snapshot(1, &state(1)).baseline, None);
+	assert_eq!(history.snapshot(2, &state(2)).baseline, None);
+
+	history.acknowledge(1);
+	assert_eq!(history.snapshot(3, &state(3)).baseline, Some(1));
+
+	// An older acknowledgement arriving late does not replace a newer baseline.
+	history.acknowledge(3);
+	history.acknowledge(2);
+	assert_eq!(history.snapshot(4, &state(4)).baseline, Some(3));
+}
+
+#[test]
+fn acknowledging_a_forgotten_snapshot_sends_a_full_one() {
+	let mut history = SnapshotHistory::new();
+
+	history.snapshot(1, &state(1));
+	history.acknowledge(1);
+
+	for tick in 2..100 {
+		history.snapshot(tick, &state(tick));
+	}
+
+	history.acknowledge(2);
+	assert_eq!(history.snapshot(100, &state(100)).baseline, None);
+
+	history.acknowledge(100);
+	assert_eq!(history.snapshot(101, &state(101)).baseline, Some(100));
+}
+
+#[test]
+fn lost_acknowledgements_do_not_stall_the_client() {
+	let mut history = SnapshotHistory::new();
+	let mut client = Client::new();
+	let mut applied = Vec::new();
+
+	for tick in 1..300 {
+		let snapshot = history.snapshot(tick, &state(tick));
+
+		if let Some(ack) = client.receive(&snapshot) {
+			applied.push(ack);
+
+			// Every acknowledgement after the first few is lost for longer than the client keeps
+			// the baseline around.
+			if ack <= 10 || ack > 150 {
+				history.acknowledge(ack);
+			}
+		}
+	}
+
+	assert_eq!(applied, (1..300).collect::<Vec<u32>>());
+	assert!(client.states.back().unwrap().1 == state(299));
+	assert_eq!(history.snapshot(300, &state(300)).baseline, Some(299));
+}